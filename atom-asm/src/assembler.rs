@@ -0,0 +1,2077 @@
+use crate::{
+    diagnostic::{Diagnostic, Diagnostics},
+    encoder::{branch_target, encode, encode_branch, has_short_form},
+    eval::{Def, Evaluator},
+    listing::Listing,
+    literal::{is_literal_section, Merge},
+    num::NumExt as _,
+    object::{is_assembler_local, Object, Reloc, Section, SectionId, Symbol},
+    parser::{
+        BinaryOp, Data, DataItem, Expr, Incbin, Instruction, Line, SectionDecl, SymbolDirective,
+        ZerofillSymbol,
+    },
+    preprocessor::SourceLine,
+};
+use atom_macho::{
+    load_command::segment64::{SectionAttr, SectionType},
+    nlist::{NDesc, NDescFlag},
+    reloc::X86_64RelocType,
+};
+use atom_x86_64::{
+    addr::{Rel, Size},
+    instructions::encode_nops,
+    Code,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryFrom as _,
+    rc::Rc,
+};
+
+/// 配置が決まるまで繰り返す回数の上限
+const MAX_PASSES: usize = 100;
+
+/// `mov rdi, [rel var@TLVP]` のようにスレッドローカル変数の TLV ディスクリプタを読む
+const TLVP_SUFFIX: &str = "@TLVP";
+
+/// `.tbss` で確保したスレッドローカル変数の初期値を置くシンボルの接尾辞
+const TLV_INIT_SUFFIX: &str = "$tlv$init";
+
+/// TLV ディスクリプタを初めて読んだ時に dyld が呼ぶ関数
+const TLV_BOOTSTRAP: &str = "__tlv_bootstrap";
+
+/// セクション内の要素
+enum Stmt {
+    /// ラベルの定義
+    Label(String),
+    /// `equ` による定義. 式は `equs` に別に持つ.
+    Equ(String),
+    /// 命令. `long` は同じセクション内への分岐を `rel32` 形式にするかどうか.
+    Inst { inst: Instruction, long: bool },
+    Data(Data),
+    Reserve(Size, Expr),
+    Times(Expr, Box<Stmt>),
+    /// `incbin` で読み込んだファイルの中身
+    Incbin {
+        bytes: Vec<u8>,
+        offset: Option<Expr>,
+        len: Option<Expr>,
+    },
+    /// `align N`. `nop` は詰め物をNOPにするかどうか.
+    Align { align: Expr, nop: bool },
+}
+
+/// 要素と、その要素を書いた行
+struct Entry {
+    stmt: Stmt,
+    line: Rc<SourceLine>,
+}
+
+/// 要素を配置した結果
+enum Output {
+    /// `Reloc.addr` は `bytes` の先頭からのオフセット
+    Bytes { bytes: Vec<u8>, relocs: Vec<Reloc> },
+    /// `resb` などで確保した領域
+    Zero(u64),
+}
+
+impl Output {
+    fn size(&self) -> u64 {
+        match self {
+            Output::Bytes { bytes, .. } => bytes.len() as u64,
+            Output::Zero(size) => *size,
+        }
+    }
+
+    /// `other` を後ろに続ける
+    fn append(self, other: Output) -> Output {
+        match (self, other) {
+            (Output::Zero(a), Output::Zero(b)) => Output::Zero(a + b),
+            (Output::Zero(0), other) => other,
+            (output, other) => {
+                let (mut bytes, mut relocs) = output.into_bytes();
+                let (tail, tail_relocs) = other.into_bytes();
+                let offset = bytes.len() as i32;
+                relocs.extend(tail_relocs.into_iter().map(|reloc| Reloc {
+                    addr: reloc.addr + offset,
+                    ..reloc
+                }));
+                bytes.extend(tail);
+                Output::Bytes { bytes, relocs }
+            }
+        }
+    }
+
+    /// 確保した領域は0で埋める
+    fn into_bytes(self) -> (Vec<u8>, Vec<Reloc>) {
+        match self {
+            Output::Bytes { bytes, relocs } => (bytes, relocs),
+            Output::Zero(size) => (vec![0; size as usize], Vec::new()),
+        }
+    }
+
+    /// 命令の機械語から作る.
+    /// `branch` は `call`, `jmp` などで他のセクションや外部のシンボルへ分岐するかどうか.
+    fn from_code(code: Code, branch: bool) -> Result<Output, String> {
+        let Code { mut bytes, fixups } = code;
+        // RIP相対アドレスはリンク時に決定する
+        let relocs = fixups
+            .into_iter()
+            .map(|fixup| {
+                // 変位の後に即値が続くと、変位の基準の命令の末尾がずれる.
+                // リンカはずれの大きさを種類で区別する.
+                let trailing = bytes.len() - (fixup.offset + 4);
+                let tlv = fixup.symbol.ends_with(TLVP_SUFFIX);
+                let kind = match trailing {
+                    _ if tlv => X86_64RelocType::Tlv,
+                    _ if branch => X86_64RelocType::Branch,
+                    0 => X86_64RelocType::Signed,
+                    1 => X86_64RelocType::Signed1,
+                    2 => X86_64RelocType::Signed2,
+                    4 => X86_64RelocType::Signed4,
+                    _ => {
+                        return Err(format!(
+                            "{} bytes follow the displacement of {}, which can not be relocated",
+                            trailing, fixup.symbol
+                        ))
+                    }
+                };
+                // 変位に書かれた値はシンボルからのオフセット.
+                // 書き込む値は生成するファイルの形式で決まるので0にしておく.
+                let field = &mut bytes[fixup.offset..fixup.offset + 4];
+                let addend = i32::from_le_bytes([field[0], field[1], field[2], field[3]]);
+                field.copy_from_slice(&[0; 4]);
+
+                let symbol = match fixup.symbol.strip_suffix(TLVP_SUFFIX) {
+                    Some(symbol) => symbol.to_string(),
+                    None => fixup.symbol,
+                };
+                Ok(Reloc {
+                    addr: fixup.offset as i32,
+                    symbol,
+                    addend: addend as i64,
+                    pcrel: true,
+                    len: 2,
+                    kind,
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Output::Bytes { bytes, relocs })
+    }
+}
+
+/// 行の列から `Object` を組み立てる.
+/// エラーは `diagnostics` に報告し、その要素を除いて組み立てを続ける.
+/// `listing` があれば各行を配置したアドレスとバイト列を書き加える.
+pub fn assemble<I>(lines: I, diagnostics: &Diagnostics, listing: Option<&Listing>) -> Object
+where
+    I: IntoIterator<Item = (SourceLine, Vec<Line>)>,
+{
+    // シンボル名、宣言した行、エラーメッセージでの呼び方
+    let mut globals = Vec::new();
+    let mut externs = Vec::new();
+    let mut directives = Vec::new();
+    let mut commons = Vec::new();
+    let mut build_version = None;
+    let mut subsections_via_symbols = false;
+    let mut defined = HashMap::new();
+    let mut equs = HashMap::new();
+    // 最初に使われた順のセクションと、その要素
+    let mut sections: Vec<(Section, Vec<Entry>)> = Vec::new();
+
+    // section宣言がなければ .text として扱う
+    let mut section = None;
+
+    for (source, lines) in lines {
+        let source = Rc::new(source);
+        for line in lines {
+            let id = match &line {
+                Line::SectionDeclare(decl) => {
+                    section = Some(declare_section(&mut sections, decl));
+                    continue;
+                }
+                Line::GlobalSymbol(name) => {
+                    globals.push((name.clone(), Rc::clone(&source), "global symbol"));
+                    continue;
+                }
+                Line::ExternSymbol(name) => {
+                    externs.push(name.clone());
+                    continue;
+                }
+                Line::SymbolDirective(directive, name) => {
+                    match directive {
+                        SymbolDirective::PrivateExtern => {
+                            let kind = "private extern symbol";
+                            globals.push((name.clone(), Rc::clone(&source), kind))
+                        }
+                        SymbolDirective::WeakReference | SymbolDirective::Reference => {
+                            externs.push(name.clone())
+                        }
+                        _ => {}
+                    }
+                    directives.push((*directive, name.clone(), Rc::clone(&source)));
+                    continue;
+                }
+                Line::Common(symbol) => {
+                    if define(&mut defined, &symbol.name, &source, diagnostics) {
+                        commons.push((symbol.clone(), Rc::clone(&source)));
+                    }
+                    continue;
+                }
+                Line::LocalCommon(symbol) => {
+                    let decl = SectionDecl::new("__DATA", "__bss");
+                    let id = declare_section(&mut sections, &decl);
+                    if define(&mut defined, &symbol.name, &source, diagnostics) {
+                        push_zerofill_symbol(&mut sections[id.0].1, symbol, &source);
+                    }
+                    continue;
+                }
+                Line::ThreadLocalZerofill(symbol) => {
+                    let init = ZerofillSymbol {
+                        name: format!("{}{}", symbol.name, TLV_INIT_SUFFIX),
+                        ..symbol.clone()
+                    };
+                    if define(&mut defined, &symbol.name, &source, diagnostics)
+                        && define(&mut defined, &init.name, &source, diagnostics)
+                    {
+                        let decl = SectionDecl::new("__DATA", "__thread_bss");
+                        let id = declare_section(&mut sections, &decl);
+                        push_zerofill_symbol(&mut sections[id.0].1, &init, &source);
+                        let decl = SectionDecl::new("__DATA", "__thread_vars");
+                        let id = declare_section(&mut sections, &decl);
+                        push_tlv_descriptor(
+                            &mut sections[id.0].1,
+                            &symbol.name,
+                            &init.name,
+                            &source,
+                        );
+                    }
+                    continue;
+                }
+                Line::Zerofill(decl, symbol) => {
+                    let id = declare_section(&mut sections, decl);
+                    let (section, entries) = &mut sections[id.0];
+                    // 中身を置く前であれば zerofill のセクションにできる
+                    if !section.is_zerofill() && entries.is_empty() {
+                        section.sect_type = SectionType::Zerofill;
+                    }
+                    if !section.is_zerofill() {
+                        let message = format!("{} is not a zerofill section", section.name());
+                        diagnostics.push(Diagnostic::error_at(&source, message));
+                        continue;
+                    }
+                    if let Some(symbol) = symbol {
+                        if define(&mut defined, &symbol.name, &source, diagnostics) {
+                            push_zerofill_symbol(entries, symbol, &source);
+                        }
+                    }
+                    continue;
+                }
+                Line::SubsectionsViaSymbols => {
+                    subsections_via_symbols = true;
+                    continue;
+                }
+                Line::BuildVersion(version) => {
+                    if build_version.replace(*version).is_some() {
+                        let message = "build version is specified more than once";
+                        diagnostics.push(Diagnostic::error_at(&source, message));
+                    }
+                    continue;
+                }
+                _ => *section.get_or_insert_with(|| {
+                    declare_section(&mut sections, &SectionDecl::new("__TEXT", "__text"))
+                }),
+            };
+            let (section, entries) = &mut sections[id.0];
+            let stmt = match line {
+                Line::SymbolDef(name) => {
+                    if !define(&mut defined, &name, &source, diagnostics) {
+                        continue;
+                    }
+                    Stmt::Label(name)
+                }
+                Line::Equ(name, expr) => {
+                    if !define(&mut defined, &name, &source, diagnostics) {
+                        continue;
+                    }
+                    equs.insert(name.clone(), expr);
+                    Stmt::Equ(name)
+                }
+                line => match to_stmt(line, section) {
+                    Ok(stmt) => stmt,
+                    Err(message) => {
+                        diagnostics.push(Diagnostic::error_at(&source, message));
+                        continue;
+                    }
+                },
+            };
+            entries.push(Entry {
+                stmt,
+                line: Rc::clone(&source),
+            });
+        }
+    }
+
+    // atom を始めるラベル. アセンブラ内のラベルと `.alt_entry` は直前の atom に含まれる.
+    let alt_entries = directives
+        .iter()
+        .filter(|(directive, _, _)| *directive == SymbolDirective::AltEntry)
+        .map(|(_, name, _)| name.as_str())
+        .collect::<HashSet<_>>();
+    let atom_labels = sections
+        .iter()
+        .flat_map(|(_, entries)| entries.iter())
+        .filter_map(|entry| match &entry.stmt {
+            Stmt::Label(name) if subsections_via_symbols => Some(name),
+            _ => None,
+        })
+        .filter(|name| !is_assembler_local(name) && !alt_entries.contains(name.as_str()))
+        .cloned()
+        .collect::<HashSet<_>>();
+
+    // 各要素の大きさ. 変化しなくなるまで配置し直す.
+    // ここでのエラーは最後に配置した時に報告する.
+    let mut sizes = sections
+        .iter()
+        .map(|(_, entries)| vec![0; entries.len()])
+        .collect::<Vec<_>>();
+    for pass in 0.. {
+        let symbols = define_symbols(&sections, &sizes, &equs, &atom_labels);
+        let atoms = atom_starts(&sections, &sizes, &atom_labels);
+        let mut changed = None;
+        for (id, ((_, entries), sizes)) in sections.iter_mut().zip(sizes.iter_mut()).enumerate() {
+            let mut here = 0;
+            for (entry, size) in entries.iter_mut().zip(sizes.iter_mut()) {
+                let ev = Evaluator::new(&symbols, SectionId(id), here);
+                entry.stmt.relax(&ev, &atoms[id]);
+                let new_size = entry
+                    .stmt
+                    .output(&ev, &atoms[id])
+                    .map_or(0, |output| output.size());
+                if new_size != *size {
+                    changed = Some(Rc::clone(&entry.line));
+                }
+                *size = new_size;
+                here += new_size;
+            }
+        }
+
+        match changed {
+            None => break,
+            Some(line) if pass + 1 == MAX_PASSES => {
+                let message = format!("could not determine the layout in {} passes", MAX_PASSES);
+                diagnostics.push(Diagnostic::error_at(&line, message));
+                break;
+            }
+            Some(_) => {}
+        }
+    }
+
+    let mut symbols = define_symbols(&sections, &sizes, &equs, &atom_labels);
+    let atoms = atom_starts(&sections, &sizes, &atom_labels);
+
+    // 同じリテラルをまとめた後のアドレスで最後の配置をする
+    let merges = sections
+        .iter()
+        .enumerate()
+        .map(|(id, (section, entries))| {
+            match plan_merge(section, entries, &symbols, SectionId(id)) {
+                Ok(merge) => merge,
+                Err(message) => {
+                    if let Some(entry) = entries.last() {
+                        diagnostics.push(Diagnostic::error_at(&entry.line, message));
+                    }
+                    None
+                }
+            }
+        })
+        .collect::<Vec<_>>();
+    for def in symbols.values_mut() {
+        let (section, addr) = match def {
+            Def::Label { section, addr, .. } | Def::Equ { section, addr, .. } => (section, addr),
+        };
+        if let Some(merge) = &merges[section.0] {
+            *addr = merge.remap(*addr);
+        }
+    }
+
+    let mut obj = Object::new();
+    obj.build_version = build_version;
+    obj.subsections_via_symbols = subsections_via_symbols;
+    let (headers, sections): (Vec<_>, Vec<_>) = sections.into_iter().unzip();
+    obj.sections = headers;
+    for (id, entries) in sections.iter().enumerate() {
+        let section = SectionId(id);
+        let merge = merges[id].as_ref();
+        // `here` はまとめる前のアドレス
+        let mut here = 0;
+        let addr = |here| merge.map_or(here, |merge| merge.remap(here));
+        // 最初のシンボルより前の中身はどのシンボルにも属さない
+        let mut labeled = !subsections_via_symbols;
+        // ラベルの後にいるかどうかと、直前の命令から次の要素へ実行が続くかどうか
+        let mut in_atom = false;
+        let mut falls_through = false;
+        for Entry { stmt, line } in entries.iter() {
+            let ev = Evaluator::new(&symbols, section, addr(here));
+            // セクションの先頭がそれ以上に揃っていなければ意味がない
+            if let Stmt::Align { align, .. } = stmt {
+                if let Ok(align) = expect_align(align, &ev) {
+                    let section = &mut obj.sections[id];
+                    section.align = section.align.max(align);
+                }
+            }
+            let result = match stmt {
+                Stmt::Label(name) => {
+                    // アセンブラの中だけで使うラベルはシンボルテーブルに書き込まない
+                    labeled |= !is_assembler_local(name);
+                    // リンカは atom を並べ替えたり取り除いたりできる
+                    if atom_labels.contains(name) && in_atom && falls_through {
+                        let message = format!(
+                            "execution falls through into {}, which starts an atom",
+                            name
+                        );
+                        diagnostics.push(Diagnostic::warning_at(line, message));
+                    }
+                    in_atom = subsections_via_symbols;
+                    obj.sections[id].symbols.push(Symbol::Ref {
+                        name: name.clone(),
+                        addr: addr(here),
+                        ext: false,
+                        pext: false,
+                        desc: NDesc::new(),
+                    });
+                    if let Some(listing) = listing {
+                        listing.place_bytes(line.index, addr(here), &[]);
+                    }
+                    Ok(())
+                }
+                Stmt::Equ(name) => push_equ(&mut obj, name, &ev),
+                stmt => stmt
+                    .output(&ev, &atoms[id])
+                    .and_then(|output| anchor_local_labels(output, &symbols))
+                    .map(|output| {
+                        if output.size() > 0 {
+                            falls_through = match stmt {
+                                Stmt::Inst { inst, .. } => !matches!(
+                                    inst.mnemonic.as_str(),
+                                    "jmp" | "ret" | "ud2" | "int3"
+                                ),
+                                // 詰め物は直前の命令の続きとして扱う
+                                Stmt::Align { .. } => falls_through,
+                                _ => false,
+                            };
+                        }
+                        if !labeled && output.size() > 0 {
+                            labeled = true;
+                            let message = format!(
+                                "content before the first symbol in {} can not be dead-stripped",
+                                obj.sections[id].name()
+                            );
+                            diagnostics.push(Diagnostic::warning_at(line, message));
+                        }
+                        let start = here;
+                        here += output.size();
+                        let output = match merge {
+                            Some(merge) => retain_merged(output, start, merge),
+                            None => output,
+                        };
+                        if let Some(listing) = listing {
+                            match &output {
+                                Output::Bytes { bytes, .. } => {
+                                    listing.place_bytes(line.index, addr(start), bytes)
+                                }
+                                Output::Zero(size) => {
+                                    listing.place_reserve(line.index, addr(start), *size)
+                                }
+                            }
+                        }
+                        push_output(&mut obj.sections[id], output);
+                    }),
+            };
+            if let Err(message) = result {
+                diagnostics.push(Diagnostic::error_at(line, message));
+            }
+        }
+
+        // まとめた後のアドレスで中身が変わると、まとめ方が正しくなくなる
+        if let (Some(merge), Some(entry)) = (merge, entries.last()) {
+            if obj.sections[id].bytes != merge.bytes {
+                let message = format!(
+                    "contents of {} depend on the addresses of merged literals",
+                    obj.sections[id].name()
+                );
+                diagnostics.push(Diagnostic::error_at(&entry.line, message));
+            }
+        }
+    }
+
+    // zerofill のセクションはファイル上に中身を持つセクションの後に置く
+    obj.sections.sort_by_key(|section| section.is_zerofill());
+
+    for (symbol, line) in commons {
+        let ev = Evaluator::new(&symbols, SectionId(0), 0);
+        match common_symbol(&symbol, &ev) {
+            Ok(symbol) => obj.symbols.push(symbol),
+            Err(message) => diagnostics.push(Diagnostic::error_at(&line, message)),
+        }
+    }
+
+    // global宣言されたシンボルをexternalにする
+    let mut externals = HashSet::new();
+    let mut symbols = obj.symbols.iter_mut().collect::<Vec<_>>();
+    for section in obj.sections.iter_mut() {
+        symbols.extend(section.symbols.iter_mut());
+    }
+    for sym in symbols {
+        match sym {
+            Symbol::Ref { name, ext, .. } | Symbol::Abs { name, ext, .. } => {
+                *ext = globals.iter().any(|(global, _, _)| global == name);
+                if *ext {
+                    externals.insert(name.clone());
+                }
+            }
+            // 共通シンボルは常に外部シンボル
+            Symbol::Common { name, .. } => {
+                externals.insert(name.clone());
+            }
+            Symbol::Undef { .. } => {}
+        }
+    }
+    for (name, line, kind) in globals.iter() {
+        if externals.insert(name.clone()) {
+            let message = format!("{} {} is not defined", kind, name);
+            diagnostics.push(Diagnostic::error_at(line, message));
+        }
+    }
+
+    // extern宣言されたシンボルと、参照されているが定義されていないシンボル
+    let referenced = obj
+        .sections
+        .iter()
+        .flat_map(|section| section.relocs.iter())
+        .map(|reloc| reloc.symbol.clone());
+    let mut undefs = Vec::new();
+    for name in externs.into_iter().chain(referenced) {
+        if !defined.contains_key(&name) && !undefs.contains(&name) {
+            undefs.push(name);
+        }
+    }
+    obj.symbols.extend(undefs.into_iter().map(|name| Symbol::Undef {
+        name,
+        desc: NDesc::new(),
+    }));
+
+    // `.private_extern` などの属性を付ける
+    let mut symbols = obj.symbols.iter_mut().collect::<Vec<_>>();
+    for section in obj.sections.iter_mut() {
+        symbols.extend(section.symbols.iter_mut());
+    }
+    for (directive, name, line) in directives {
+        let sym = symbols.iter_mut().find(|sym| sym.name() == name);
+        if let Err(message) = apply_symbol_directive(directive, &name, sym) {
+            diagnostics.push(Diagnostic::error_at(&line, message));
+        }
+    }
+
+    obj
+}
+
+fn apply_symbol_directive(
+    directive: SymbolDirective,
+    name: &str,
+    sym: Option<&mut &mut Symbol>,
+) -> Result<(), String> {
+    let sym = match sym {
+        Some(sym) => sym,
+        // `.private_extern` は global と同じくエラーを報告済み
+        None if directive == SymbolDirective::PrivateExtern => return Ok(()),
+        None => return Err(format!("symbol {} is not defined", name)),
+    };
+    let flag = match directive {
+        SymbolDirective::PrivateExtern => {
+            if let Symbol::Ref { pext, .. } | Symbol::Abs { pext, .. } = sym {
+                *pext = true;
+            }
+            return Ok(());
+        }
+        SymbolDirective::Reference => return Ok(()),
+        SymbolDirective::WeakReference => NDescFlag::WeakRef,
+        SymbolDirective::WeakDefinition => NDescFlag::WeakDef,
+        SymbolDirective::NoDeadStrip => NDescFlag::NoDeadStrip,
+        SymbolDirective::AltEntry => NDescFlag::AltEntry,
+    };
+    match (directive, &**sym) {
+        (SymbolDirective::WeakReference, _) => {}
+        (_, Symbol::Undef { .. }) => return Err(format!("symbol {} is not defined", name)),
+        // 弱い定義は他のファイルの定義と置き換わるので、外から見えなければ意味がない
+        (SymbolDirective::WeakDefinition, Symbol::Ref { ext: false, .. })
+        | (SymbolDirective::WeakDefinition, Symbol::Abs { ext: false, .. }) => {
+            return Err(format!("weak definition {} must be global", name))
+        }
+        _ => {}
+    }
+    sym.desc_mut().push(flag);
+    Ok(())
+}
+
+/// シンボルを定義済みにする.
+/// 同じ名前のシンボルが定義されていればエラーを報告して `false` を返す.
+fn define(
+    defined: &mut HashMap<String, Rc<SourceLine>>,
+    name: &str,
+    line: &Rc<SourceLine>,
+    diagnostics: &Diagnostics,
+) -> bool {
+    match defined.get(name) {
+        Some(prev) => {
+            let message = format!("symbol {} is defined more than once", name);
+            let note = format!("previous definition at {}", prev.loc.position());
+            diagnostics.push(Diagnostic::error_at(line, message).with_note(note));
+            false
+        }
+        None => {
+            defined.insert(name.to_string(), Rc::clone(line));
+            true
+        }
+    }
+}
+
+/// `decl` のセクションを探し、なければ追加する
+fn declare_section(sections: &mut Vec<(Section, Vec<Entry>)>, decl: &SectionDecl) -> SectionId {
+    let idx = match sections
+        .iter()
+        .position(|(s, _)| s.segname == decl.segname && s.sectname == decl.sectname)
+    {
+        Some(idx) => idx,
+        None => {
+            sections.push((Section::new(&decl.segname, &decl.sectname), Vec::new()));
+            sections.len() - 1
+        }
+    };
+    // 何度も宣言されたら最も大きいアラインメントにする
+    if let Some(align) = decl.align {
+        let section = &mut sections[idx].0;
+        section.align = section.align.max(align);
+    }
+    SectionId(idx)
+}
+
+fn to_stmt(line: Line, section: &mut Section) -> Result<Stmt, String> {
+    let stmt = match line {
+        Line::Content(inst) => {
+            if section.is_zerofill() {
+                return Err(format!(
+                    "instruction can not be placed in {}: {}",
+                    section.name(),
+                    inst
+                ));
+            }
+            if !section.attrs.contains(SectionAttr::SomeInstructions) {
+                section.attrs.push(SectionAttr::SomeInstructions);
+            }
+            Stmt::Inst {
+                long: !has_short_form(&inst),
+                inst,
+            }
+        }
+        Line::Data(_) | Line::Incbin(_) if section.is_zerofill() => {
+            return Err(format!("data can not be placed in {}", section.name()));
+        }
+        Line::Data(data) => Stmt::Data(data),
+        // 命令を含むセクションはNOPで埋める
+        Line::Align(align) => Stmt::Align {
+            align,
+            nop: section.attrs.contains(SectionAttr::SomeInstructions),
+        },
+        Line::Reserve(size, count) => Stmt::Reserve(size, count),
+        Line::Times(count, line) => Stmt::Times(count, Box::new(to_stmt(*line, section)?)),
+        Line::Incbin(Incbin { path, offset, len }) => {
+            let bytes = std::fs::read(&path)
+                .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+            Stmt::Incbin { bytes, offset, len }
+        }
+        _ => unreachable!(),
+    };
+    Ok(stmt)
+}
+
+/// 現在の配置でのシンボルの定義.
+/// `atom_labels` のラベルからセクション内の atom の番号を数える.
+fn define_symbols<'a>(
+    sections: &[(Section, Vec<Entry>)],
+    sizes: &[Vec<u64>],
+    equs: &'a HashMap<String, Expr>,
+    atom_labels: &HashSet<String>,
+) -> HashMap<String, Def<'a>> {
+    let mut symbols = HashMap::new();
+    for (id, ((_, entries), sizes)) in sections.iter().zip(sizes.iter()).enumerate() {
+        let section = SectionId(id);
+        let mut addr = 0;
+        let mut atom = 0;
+        for (Entry { stmt, .. }, size) in entries.iter().zip(sizes.iter()) {
+            match stmt {
+                Stmt::Label(name) => {
+                    if atom_labels.contains(name) {
+                        atom += 1;
+                    }
+                    symbols.insert(
+                        name.clone(),
+                        Def::Label {
+                            section,
+                            addr,
+                            atom,
+                        },
+                    );
+                }
+                Stmt::Equ(name) => {
+                    let expr = &equs[name];
+                    symbols.insert(
+                        name.clone(),
+                        Def::Equ {
+                            section,
+                            addr,
+                            expr,
+                        },
+                    );
+                }
+                _ => {}
+            }
+            addr += size;
+        }
+    }
+    symbols
+}
+
+impl Stmt {
+    /// 同じセクション内への分岐命令が `rel8` 形式で届かなければ `rel32` 形式に広げる.
+    /// 広げる方向にしか変化しないので、配置はいずれ変化しなくなる.
+    fn relax(&mut self, ev: &Evaluator, atoms: &[u64]) {
+        match self {
+            Stmt::Inst { inst, long } if !*long => {
+                if let Some(target) = local_branch_target(inst, ev, atoms) {
+                    // `rel8` 形式がなければ `rel32` 形式にする
+                    *long = match encode_branch(inst, Rel::Rel8(0)) {
+                        Ok(code) => {
+                            let disp = target as i64 - (ev.here() + code.len() as u64) as i64;
+                            i8::try_from(disp).is_err()
+                        }
+                        Err(_) => true,
+                    };
+                }
+            }
+            // 繰り返しのどれかが届かなければ全て広げる
+            Stmt::Times(count, stmt) => {
+                let count = expect_count(count, ev).unwrap_or(0);
+                let mut here = ev.here();
+                for _ in 0..count {
+                    let ev = ev.at(here);
+                    stmt.relax(&ev, atoms);
+                    match stmt.output(&ev, atoms) {
+                        Ok(output) => here += output.size(),
+                        Err(_) => break,
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// `atoms` はこのセクションで atom を始めるラベルのアドレス
+    fn output(&self, ev: &Evaluator, atoms: &[u64]) -> Result<Output, String> {
+        let output = match self {
+            Stmt::Label(_) | Stmt::Equ(_) => Output::Zero(0),
+            Stmt::Inst { inst, long } => match local_branch_target(inst, ev, atoms) {
+                Some(target) => {
+                    let rel = if *long { Rel::Rel32(0) } else { Rel::Rel8(0) };
+                    let size = encode_branch(inst, rel)?.len() as u64;
+                    // 相対アドレスは次の命令の先頭から数える
+                    let disp = target as i64 - (ev.here() + size) as i64;
+                    let rel = if *long {
+                        Rel::Rel32(disp as i32)
+                    } else {
+                        Rel::Rel8(disp as i8)
+                    };
+                    Output::from_code(encode_branch(inst, rel)?, false)?
+                }
+                None => Output::from_code(encode(inst, ev)?, branch_target(inst).is_some())?,
+            },
+            Stmt::Data(data) => data_output(data, ev)?,
+            Stmt::Reserve(size, count) => {
+                Output::Zero(size.bytes() as u64 * expect_count(count, ev)?)
+            }
+            // `$` はそれぞれの繰り返しの先頭のアドレスになる
+            Stmt::Times(count, stmt) => {
+                let count = expect_count(count, ev)?;
+                let mut output = Output::Zero(0);
+                for _ in 0..count {
+                    let ev = ev.at(ev.here() + output.size());
+                    output = output.append(stmt.output(&ev, atoms)?);
+                }
+                output
+            }
+            Stmt::Incbin { bytes, offset, len } => {
+                let offset = match offset {
+                    Some(offset) => expect_count(offset, ev)? as usize,
+                    None => 0,
+                };
+                let len = match len {
+                    Some(len) => expect_count(len, ev)? as usize,
+                    None => bytes.len().saturating_sub(offset),
+                };
+                if offset + len > bytes.len() {
+                    return Err(format!(
+                        "incbin range {}..{} is out of the file ({} bytes)",
+                        offset,
+                        offset + len,
+                        bytes.len()
+                    ));
+                }
+                Output::Bytes {
+                    bytes: bytes[offset..offset + len].to_vec(),
+                    relocs: Vec::new(),
+                }
+            }
+            Stmt::Align { align, nop } => {
+                let len = ev.here().padding(expect_align(align, ev)?);
+                if *nop {
+                    let mut code = Code::new();
+                    encode_nops(&mut code, len as usize);
+                    Output::from_code(code, false)?
+                } else {
+                    Output::Zero(len)
+                }
+            }
+        };
+        Ok(output)
+    }
+}
+
+/// 同じセクションの同じ atom の中への分岐命令であれば飛び先のアドレスを返す.
+/// 他の atom への分岐はリンカが atom を動かしても届くようにリロケーションを残す.
+fn local_branch_target(inst: &Instruction, ev: &Evaluator, atoms: &[u64]) -> Option<u64> {
+    let target = branch_target(inst)?;
+    let atom = |addr: u64| atoms.partition_point(|&start| start <= addr);
+    match ev.eval(target).ok()?.as_section_addr() {
+        Some((section, addr)) if section == ev.section() && atom(addr) == atom(ev.here()) => {
+            Some(addr)
+        }
+        _ => None,
+    }
+}
+
+/// SUBTRACTOR の組と TLV のリロケーションはリンカがシンボルを必要とする.
+/// アセンブラの中だけで使うラベルはシンボルテーブルにないので、
+/// 同じ atom でその位置より前にある他のラベルからのオフセットにする.
+fn anchor_local_labels(output: Output, symbols: &HashMap<String, Def>) -> Result<Output, String> {
+    let (bytes, mut relocs) = match output {
+        Output::Bytes { bytes, relocs } => (bytes, relocs),
+        output => return Ok(output),
+    };
+    for i in 0..relocs.len() {
+        let kind = relocs[i].kind;
+        let subtracted = kind == X86_64RelocType::Subtractor;
+        let added = i > 0 && relocs[i - 1].kind == X86_64RelocType::Subtractor;
+        let name = &relocs[i].symbol;
+        if !is_assembler_local(name) || !(subtracted || added || kind == X86_64RelocType::Tlv) {
+            continue;
+        }
+        let (anchor, offset) = match anchor_symbol(name, symbols) {
+            Some((anchor, offset)) if kind != X86_64RelocType::Tlv => (anchor, offset as i64),
+            Some(_) => {
+                let message = format!("thread-local variable {} must not be a local label", name);
+                return Err(message);
+            }
+            None => return Err(format!("no symbol precedes {} in its atom", name)),
+        };
+        relocs[i].symbol = anchor.to_string();
+        // 差の値は続く UNSIGNED の addend に持つ
+        if subtracted {
+            relocs[i + 1].addend -= offset;
+        } else {
+            relocs[i].addend += offset;
+        }
+    }
+    Ok(Output::Bytes { bytes, relocs })
+}
+
+/// ラベル `name` と同じ atom でその位置以前にある、シンボルテーブルに書き込むラベルと、
+/// そこからのオフセット. 同じ位置に複数あれば名前の順で最後のもの.
+fn anchor_symbol<'a>(name: &str, symbols: &'a HashMap<String, Def>) -> Option<(&'a str, u64)> {
+    let (section, addr, atom) = match symbols.get(name)? {
+        Def::Label {
+            section,
+            addr,
+            atom,
+        } => (*section, *addr, *atom),
+        Def::Equ { .. } => return None,
+    };
+    symbols
+        .iter()
+        .filter_map(|(n, def)| match def {
+            Def::Label {
+                section: s,
+                addr: a,
+                atom: t,
+            } if *s == section && *t == atom && *a <= addr && !is_assembler_local(n) => {
+                Some((*a, n.as_str()))
+            }
+            _ => None,
+        })
+        .max()
+        .map(|(a, n)| (n, addr - a))
+}
+
+/// 現在の配置で `atom_labels` のラベルを置いたアドレス. セクションごとに昇順に並べる.
+fn atom_starts(
+    sections: &[(Section, Vec<Entry>)],
+    sizes: &[Vec<u64>],
+    atom_labels: &HashSet<String>,
+) -> Vec<Vec<u64>> {
+    sections
+        .iter()
+        .zip(sizes.iter())
+        .map(|((_, entries), sizes)| {
+            let mut addr = 0;
+            let mut starts = Vec::new();
+            for (Entry { stmt, .. }, size) in entries.iter().zip(sizes.iter()) {
+                if let Stmt::Label(name) = stmt {
+                    if atom_labels.contains(name) {
+                        starts.push(addr);
+                    }
+                }
+                addr += size;
+            }
+            starts
+        })
+        .collect()
+}
+
+fn expect_count(count: &Expr, ev: &Evaluator) -> Result<u64, String> {
+    let n = ev.eval_const(count)?;
+    u64::try_from(n).map_err(|_| format!("invalid count {}", n))
+}
+
+fn expect_align(align: &Expr, ev: &Evaluator) -> Result<u64, String> {
+    let n = ev.eval_const(align)?;
+    match u64::try_from(n) {
+        Ok(align) if align.is_power_of_two() => Ok(align),
+        _ => Err(format!("alignment {} is not a power of 2", n)),
+    }
+}
+
+fn data_output(data: &Data, ev: &Evaluator) -> Result<Output, String> {
+    let size = data.size.bytes() as usize;
+
+    let mut bytes = Vec::new();
+    let mut relocs = Vec::new();
+    for item in data.items.iter() {
+        match item {
+            DataItem::Expr(expr) => {
+                let value = ev.eval(expr)?;
+                let n = match (value.as_const(), value.as_symbol()) {
+                    (Some(n), _) => {
+                        check_range(n, data.size)?;
+                        n
+                    }
+                    (None, Some((symbol, _))) if symbol.ends_with(TLVP_SUFFIX) => {
+                        return Err(format!(
+                            "{} can only be used in a RIP-relative operand: {}",
+                            TLVP_SUFFIX, expr
+                        ))
+                    }
+                    // シンボルのアドレスはリンク時に書き込まれる
+                    (None, Some((symbol, addend))) if data.size == Size::Qword => {
+                        relocs.push(Reloc {
+                            addr: bytes.len() as i32,
+                            symbol: symbol.to_string(),
+                            addend,
+                            pcrel: false,
+                            len: 3,
+                            kind: X86_64RelocType::Unsigned,
+                        });
+                        0
+                    }
+                    (None, Some(_)) => {
+                        return Err(format!(
+                            "address can not be stored in {}: {}",
+                            data.size, expr
+                        ))
+                    }
+                    (None, None) => match value.as_difference() {
+                        // `a - b` は b を引く SUBTRACTOR と a を足す UNSIGNED の組にする
+                        Some((plus, minus, addend))
+                            if data.size == Size::Dword || data.size == Size::Qword =>
+                        {
+                            let len = if data.size == Size::Qword { 3 } else { 2 };
+                            let reloc = |symbol: &str, addend, kind| Reloc {
+                                addr: bytes.len() as i32,
+                                symbol: symbol.to_string(),
+                                addend,
+                                pcrel: false,
+                                len,
+                                kind,
+                            };
+                            relocs.push(reloc(minus, 0, X86_64RelocType::Subtractor));
+                            relocs.push(reloc(plus, addend, X86_64RelocType::Unsigned));
+                            0
+                        }
+                        Some(_) => {
+                            return Err(format!(
+                                "difference of addresses can not be stored in {}: {}",
+                                data.size, expr
+                            ))
+                        }
+                        None => {
+                            return Err(format!("expression can not be resolved: {}", expr))
+                        }
+                    },
+                };
+                bytes.extend(&n.to_le_bytes()[..size]);
+            }
+            DataItem::Str(s) => {
+                bytes.extend(s);
+                let padding = (size - s.len() % size) % size;
+                bytes.resize(bytes.len() + padding, 0);
+            }
+        }
+    }
+
+    Ok(Output::Bytes { bytes, relocs })
+}
+
+/// `.lcomm` や `.zerofill` で確保するシンボルを `entries` に追加する
+fn push_zerofill_symbol(entries: &mut Vec<Entry>, symbol: &ZerofillSymbol, line: &Rc<SourceLine>) {
+    let mut push = |stmt| {
+        entries.push(Entry {
+            stmt,
+            line: Rc::clone(line),
+        })
+    };
+    if let Some(align) = &symbol.align {
+        // 指数をバイト数にする
+        let align = Expr::binary(BinaryOp::Shl, Expr::Int(1), align.clone());
+        push(Stmt::Align { align, nop: false });
+    }
+    push(Stmt::Label(symbol.name.clone()));
+    push(Stmt::Reserve(Size::Byte, symbol.size.clone()));
+}
+
+/// `.tbss` で確保した変数 `name` の TLV ディスクリプタを `entries` に追加する.
+/// dyld が `__tlv_bootstrap` を呼んで、スレッドごとに `init` から作った領域を割り当てる.
+fn push_tlv_descriptor(entries: &mut Vec<Entry>, name: &str, init: &str, line: &Rc<SourceLine>) {
+    let items = vec![
+        Expr::Symbol(TLV_BOOTSTRAP.to_string()),
+        Expr::Int(0),
+        Expr::Symbol(init.to_string()),
+    ];
+    let data = Data {
+        size: Size::Qword,
+        items: items.into_iter().map(DataItem::Expr).collect(),
+    };
+    let align = Stmt::Align {
+        align: Expr::Int(8),
+        nop: false,
+    };
+    for stmt in [align, Stmt::Label(name.to_string()), Stmt::Data(data)] {
+        entries.push(Entry {
+            stmt,
+            line: Rc::clone(line),
+        });
+    }
+}
+
+/// `.comm` で宣言したシンボル
+fn common_symbol(symbol: &ZerofillSymbol, ev: &Evaluator) -> Result<Symbol, String> {
+    let size = expect_count(&symbol.size, ev)?;
+    let align = match &symbol.align {
+        Some(align) => ev.eval_const(align)?,
+        // 省略するとリンカが大きさから決める
+        None => 0,
+    };
+    // n_desc には4ビットで書き込む
+    if !(0..16).contains(&align) {
+        return Err(format!("alignment 2^{} of common symbol is out of range", align));
+    }
+    Ok(Symbol::Common {
+        name: symbol.name.clone(),
+        size,
+        align: align as u8,
+        desc: NDesc::new(),
+    })
+}
+
+/// `n` が符号付き、符号なしのどちらかとして `size` に収まるか確認する
+fn check_range(n: i64, size: Size) -> Result<(), String> {
+    let bits = size.bytes() as u32 * 8;
+    if bits < 64 && (n < -(1 << (bits - 1)) || n >= 1 << bits) {
+        return Err(format!("value {} does not fit in {}", n, size));
+    }
+    Ok(())
+}
+
+/// `equ` で定義したシンボルを `obj` に追加する
+fn push_equ(obj: &mut Object, name: &str, ev: &Evaluator) -> Result<(), String> {
+    let value = ev.eval(&Expr::Symbol(name.to_string()))?;
+    if let Some(val) = value.as_const() {
+        obj.symbols.push(Symbol::Abs {
+            name: name.to_string(),
+            val: val as u64,
+            ext: false,
+            pext: false,
+            desc: NDesc::new(),
+        });
+    } else if let Some((section, addr)) = value.as_section_addr() {
+        obj.sections[section.0].symbols.push(Symbol::Ref {
+            name: name.to_string(),
+            addr,
+            ext: false,
+            pext: false,
+            desc: NDesc::new(),
+        });
+    } else {
+        return Err(format!("{} is neither a constant nor an address", name));
+    }
+    Ok(())
+}
+
+/// リテラルのセクションであれば、まとめる前の中身を配置して同じ値のまとめ方を決める.
+/// ここでのエラーは最後に配置した時に報告する.
+fn plan_merge(
+    section: &Section,
+    entries: &[Entry],
+    symbols: &HashMap<String, Def>,
+    id: SectionId,
+) -> Result<Option<Merge>, String> {
+    if !is_literal_section(section) {
+        return Ok(None);
+    }
+    let mut literals = Section::new(&section.segname, &section.sectname);
+    literals.sect_type = section.sect_type;
+    let mut here = 0;
+    for Entry { stmt, .. } in entries {
+        let ev = Evaluator::new(symbols, id, here);
+        if let Ok(output) = stmt.output(&ev, &[]) {
+            here += output.size();
+            push_output(&mut literals, output);
+        }
+    }
+    Merge::new(&literals)
+}
+
+/// まとめた後も残るバイトとリロケーションだけにする. `start` は要素のまとめる前のアドレス.
+fn retain_merged(output: Output, start: u64, merge: &Merge) -> Output {
+    let kept = |offset: u64| merge.is_kept(start + offset);
+    match output {
+        Output::Bytes { bytes, relocs } => {
+            let relocs = relocs
+                .into_iter()
+                .filter(|reloc| kept(reloc.addr as u64))
+                .map(|reloc| Reloc {
+                    addr: (0..reloc.addr as u64).filter(|&i| kept(i)).count() as i32,
+                    ..reloc
+                })
+                .collect();
+            let bytes = bytes
+                .into_iter()
+                .zip(0..)
+                .filter(|&(_, i)| kept(i))
+                .map(|(b, _)| b)
+                .collect();
+            Output::Bytes { bytes, relocs }
+        }
+        Output::Zero(size) => Output::Zero((0..size).filter(|&i| kept(i)).count() as u64),
+    }
+}
+
+fn push_output(section: &mut Section, output: Output) {
+    match output {
+        Output::Bytes { bytes, relocs } => {
+            let offset = section.bytes.len() as i32;
+            section.relocs.extend(relocs.into_iter().map(|reloc| Reloc {
+                addr: reloc.addr + offset,
+                ..reloc
+            }));
+            section.bytes.extend(bytes);
+        }
+        // zerofill 以外では0で埋める
+        Output::Zero(size) if section.is_zerofill() => section.zerofill_size += size,
+        Output::Zero(size) => section.bytes.resize(section.bytes.len() + size as usize, 0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{diagnostic::Severity, parser::LineStream, preprocessor::Preprocessor};
+    use atom_x86_64::encode::Fixup;
+    use std::path::Path;
+
+    fn try_assemble(s: &str) -> (Object, Vec<Diagnostic>) {
+        let diagnostics = Diagnostics::new();
+        let preprocessor =
+            Preprocessor::new(Path::new("test.s"), s.as_bytes(), diagnostics.clone());
+        let lines = LineStream::new(preprocessor, diagnostics.clone());
+        let obj = assemble(lines, &diagnostics, None);
+        (obj, diagnostics.take())
+    }
+
+    fn assemble_str(s: &str) -> Object {
+        let (obj, diagnostics) = try_assemble(s);
+        let errors = diagnostics
+            .into_iter()
+            .filter(|d| d.severity == Severity::Error)
+            .collect::<Vec<_>>();
+        assert_eq!(errors, vec![]);
+        obj
+    }
+
+    /// 報告された `severity` の診断のメッセージと行番号
+    fn diagnostics(s: &str, severity: Severity) -> Vec<(String, usize)> {
+        try_assemble(s)
+            .1
+            .into_iter()
+            .filter(|d| d.severity == severity)
+            .map(|d| (d.message, d.loc.line))
+            .collect()
+    }
+
+    fn errors(s: &str) -> Vec<(String, usize)> {
+        diagnostics(s, Severity::Error)
+    }
+
+    fn warnings(s: &str) -> Vec<(String, usize)> {
+        diagnostics(s, Severity::Warning)
+    }
+
+    /// `segname,sectname` のセクション
+    fn section<'a>(obj: &'a Object, name: &str) -> &'a Section {
+        obj.sections.iter().find(|s| s.name() == name).unwrap()
+    }
+
+    fn text_symbols(obj: &Object) -> Vec<(&str, u64)> {
+        section(obj, "__TEXT,__text")
+            .symbols
+            .iter()
+            .map(|sym| match sym {
+                Symbol::Ref { name, addr, .. } => (name.as_str(), *addr),
+                _ => unreachable!(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn short_jump() {
+        let obj = assemble_str("start:\n  jmp .end\n  ret\n.end:\n  jne start\n");
+        assert_eq!(section(&obj, "__TEXT,__text").bytes, vec![0xEB, 0x01, 0xC3, 0x75, 0xFB]);
+        assert_eq!(text_symbols(&obj), vec![("start", 0), (".end", 3)]);
+        assert!(section(&obj, "__TEXT,__text").relocs.is_empty());
+    }
+
+    #[test]
+    fn long_jump() {
+        let mut s = "start:\n  je .end\n".to_string();
+        for _ in 0..64 {
+            s.push_str("  push 1\n");
+        }
+        s.push_str(".end:\n  jmp start\n");
+
+        let obj = assemble_str(&s);
+        let bytes = &section(&obj, "__TEXT,__text").bytes;
+        // 0F 84 rel32
+        assert_eq!(&bytes[..6], &[0x0F, 0x84, 0x80, 0x00, 0x00, 0x00]);
+        assert_eq!(text_symbols(&obj), vec![("start", 0), (".end", 134)]);
+        // .end から start へは -139 なので rel32
+        assert_eq!(&bytes[134..], &[0xE9, 0x75, 0xFF, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn call_is_always_rel32() {
+        let obj = assemble_str("f:\n  ret\n.main:\n  call f\n");
+        assert_eq!(section(&obj, "__TEXT,__text").bytes, vec![0xC3, 0xE8, 0xFA, 0xFF, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn branches_between_atoms() {
+        let s = ".subsections_via_symbols
+f:
+  jne .done
+  call g
+.done:
+  ret
+g:
+  jmp f
+Loop:
+  jmp .done
+%macro spin 0
+%%again:
+  jmp %%again
+%endmacro
+h:
+  spin
+";
+        let obj = assemble_str(s);
+        let text = section(&obj, "__TEXT,__text");
+        // 同じ atom の中は解決し、他の atom への分岐はリロケーションを残す
+        assert_eq!(
+            text.bytes,
+            vec![
+                0x75, 0x05, 0xE8, 0, 0, 0, 0, 0xC3, 0xE9, 0, 0, 0, 0, 0xE9, 0, 0, 0, 0, 0xEB, 0xFE
+            ]
+        );
+        let relocs = text
+            .relocs
+            .iter()
+            .map(|reloc| (reloc.addr, reloc.symbol.as_str(), reloc.kind))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            relocs,
+            vec![
+                (3, "g", X86_64RelocType::Branch),
+                (9, "f", X86_64RelocType::Branch),
+                (14, ".done", X86_64RelocType::Branch)
+            ]
+        );
+        // アセンブラの中だけのラベルは atom を始めず、シンボルテーブルにも書き込まない
+        let locals = text
+            .symbols
+            .iter()
+            .filter(|sym| sym.is_assembler_local())
+            .map(|sym| sym.name())
+            .collect::<Vec<_>>();
+        assert_eq!(locals, vec![".done", "..@1.again"]);
+
+        assert_eq!(
+            warnings(".subsections_via_symbols\nf:\n  nop\n.next:\n  nop\ng:\n  ret\n"),
+            vec![(
+                "execution falls through into g, which starts an atom".to_string(),
+                6
+            )]
+        );
+        let s = ".subsections_via_symbols
+f:
+  ret
+  align 16
+g:
+  ud2
+h:
+  int3
+i:
+  jmp qword [rel f]
+j:
+  ret
+";
+        assert_eq!(warnings(s), vec![]);
+        // `.subsections_via_symbols` がなければ atom に分けない
+        assert_eq!(warnings("f:\n  nop\ng:\n  ret\n"), vec![]);
+    }
+
+    #[test]
+    fn data_and_reserve() {
+        let obj = assemble_str(
+            "section .data\nmsg db `hi\\n`, 0\nlen dw 3\nsection .bss\nbuf resq 2\nhoge: resb 42\n",
+        );
+        assert_eq!(section(&obj, "__DATA,__data").bytes, vec![b'h', b'i', b'\n', 0, 3, 0]);
+        assert_eq!(section(&obj, "__DATA,__bss").zerofill_size, 58);
+        assert_eq!(
+            section(&obj, "__DATA,__bss").symbols,
+            vec![
+                Symbol::Ref {
+                    name: "buf".to_string(),
+                    addr: 0,
+                    ext: false,
+                    pext: false,
+                    desc: NDesc::new(),
+                },
+                Symbol::Ref {
+                    name: "hoge".to_string(),
+                    addr: 16,
+                    ext: false,
+                    pext: false,
+                    desc: NDesc::new(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn incbin() {
+        let path = std::env::temp_dir().join(format!("atom-asm-incbin-{}.bin", std::process::id()));
+        std::fs::write(&path, b"abcdef").unwrap();
+        let obj = assemble_str(&format!(
+            "section .data\nincbin \"{0}\"\nincbin \"{0}\", 4\nblob incbin \"{0}\", 1, 2\n",
+            path.display()
+        ));
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(section(&obj, "__DATA,__data").bytes, b"abcdefefbc".to_vec());
+        assert_eq!(
+            section(&obj, "__DATA,__data").symbols,
+            vec![Symbol::Ref {
+                name: "blob".to_string(),
+                addr: 8,
+                ext: false,
+                pext: false,
+                desc: NDesc::new(),
+            }]
+        );
+    }
+
+    #[test]
+    fn branch_to_other_section_is_relocated() {
+        let obj = assemble_str("  jmp foo\nsection .data\nfoo:\n");
+        assert_eq!(section(&obj, "__TEXT,__text").bytes, vec![0xE9, 0, 0, 0, 0]);
+        assert_eq!(section(&obj, "__TEXT,__text").relocs[0].addr, 1);
+        assert_eq!(section(&obj, "__TEXT,__text").relocs[0].symbol, "foo");
+    }
+
+    #[test]
+    fn times_evaluates_each_copy() {
+        let obj = assemble_str(
+            "section .text
+start:
+  times 3 jmp start
+section .data
+  dd 0
+  times 3 dd $ - $$
+",
+        );
+        assert_eq!(
+            section(&obj, "__TEXT,__text").bytes,
+            vec![0xEB, 0xFE, 0xEB, 0xFC, 0xEB, 0xFA]
+        );
+        assert_eq!(
+            section(&obj, "__DATA,__data").bytes,
+            vec![0, 0, 0, 0, 4, 0, 0, 0, 8, 0, 0, 0, 12, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn equ_and_expressions() {
+        let obj = assemble_str(
+            "section .text
+  mov rax, SYSCALL_WRITE
+  mov rdx, len
+  lea rsi, [rel msg + len - 1]
+section .data
+msg db 'hello, world', 0x0A
+len equ $ - msg
+SYSCALL_WRITE equ 0x2000000 + 4
+ptr dq msg + 2
+  times 8 - ($ - $$) % 8 db 0
+",
+        );
+        assert_eq!(
+            section(&obj, "__TEXT,__text").bytes,
+            vec![
+                0xB8, 0x04, 0x00, 0x00, 0x02, // mov eax, 0x2000004
+                0xBA, 0x0D, 0x00, 0x00, 0x00, // mov edx, 13
+                0x48, 0x8D, 0x35, 0x00, 0x00, 0x00, 0x00, // lea rsi, [rel msg+12]
+            ]
+        );
+        assert_eq!(section(&obj, "__TEXT,__text").relocs[0].addend, 12);
+        assert_eq!(section(&obj, "__DATA,__data").bytes.len(), 24);
+        assert_eq!(&section(&obj, "__DATA,__data").bytes[13..21], &[0; 8]);
+        assert_eq!(section(&obj, "__DATA,__data").relocs[0].addr, 13);
+        assert_eq!(section(&obj, "__DATA,__data").relocs[0].addend, 2);
+        assert_eq!(section(&obj, "__DATA,__data").relocs[0].len, 3);
+        assert!(!section(&obj, "__DATA,__data").relocs[0].pcrel);
+        assert_eq!(
+            obj.symbols,
+            vec![
+                Symbol::Abs {
+                    name: "len".to_string(),
+                    val: 13,
+                    ext: false,
+                    pext: false,
+                    desc: NDesc::new(),
+                },
+                Symbol::Abs {
+                    name: "SYSCALL_WRITE".to_string(),
+                    val: 0x2000004,
+                    ext: false,
+                    pext: false,
+                    desc: NDesc::new(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn forward_reference_changes_size() {
+        // `.later` が決まるまで命令の大きさは分からない
+        let obj = assemble_str("  add rax, .later - .here
+.here:
+  times 200 db 0
+.later:
+");
+        assert_eq!(
+            &section(&obj, "__TEXT,__text").bytes[..7],
+            &[0x48, 0x81, 0xC0, 0xC8, 0x00, 0x00, 0x00]
+        );
+        assert_eq!(section(&obj, "__TEXT,__text").bytes.len(), 207);
+    }
+
+    #[test]
+    fn jump_to_here() {
+        let obj = assemble_str("  jmp $
+  jmp $$
+");
+        assert_eq!(section(&obj, "__TEXT,__text").bytes, vec![0xEB, 0xFE, 0xEB, 0xFC]);
+    }
+
+    #[test]
+    fn named_sections() {
+        let obj = assemble_str(
+            "section __TEXT,__const align=16
+k dq 1
+section .text
+  ret
+section __DATA,__mystuff align=4
+  jmp k
+section __TEXT,__const align=8
+  dd 2
+",
+        );
+        let names = obj.sections.iter().map(|s| s.name()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["__TEXT,__const", "__TEXT,__text", "__DATA,__mystuff"]);
+
+        let konst = section(&obj, "__TEXT,__const");
+        assert_eq!(konst.align, 16);
+        assert_eq!(konst.bytes, vec![1, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0]);
+        // 命令を含むセクションには属性が付く
+        let text = section(&obj, "__TEXT,__text");
+        assert!(text.attrs.contains(SectionAttr::PureInstructions));
+        let mystuff = section(&obj, "__DATA,__mystuff");
+        assert!(mystuff.attrs.contains(SectionAttr::SomeInstructions));
+        assert!(!konst.attrs.contains(SectionAttr::SomeInstructions));
+        assert_eq!(mystuff.relocs[0].symbol, "k");
+    }
+
+    #[test]
+    fn zerofill_sections_last() {
+        let obj = assemble_str(
+            "section .bss
+buf: resb 16
+section .data
+x: dq buf
+section .text
+f: ret
+",
+        );
+        let names = obj
+            .sections
+            .iter()
+            .map(|section| section.name())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["__DATA,__data", "__TEXT,__text", "__DATA,__bss"]);
+        assert_eq!(section(&obj, "__DATA,__bss").symbols[0].name(), "buf");
+    }
+
+    #[test]
+    fn align() {
+        let obj = assemble_str(
+            "  ret
+  align 8
+f:
+  ret
+section .data
+  db 1
+  align 4
+x dd 2
+section .bss
+  resb 3
+  align 16
+buf resb 16
+",
+        );
+        let text = section(&obj, "__TEXT,__text");
+        assert_eq!(
+            text.bytes,
+            vec![0xC3, 0x0F, 0x1F, 0x80, 0x00, 0x00, 0x00, 0x00, 0xC3]
+        );
+        assert_eq!(text.align, 8);
+        assert_eq!(text_symbols(&obj), vec![("f", 8)]);
+
+        let data = section(&obj, "__DATA,__data");
+        assert_eq!(data.bytes, vec![1, 0, 0, 0, 2, 0, 0, 0]);
+        assert_eq!(data.align, 4);
+
+        let bss = section(&obj, "__DATA,__bss");
+        assert_eq!(bss.zerofill_size, 32);
+        assert_eq!(bss.align, 16);
+
+        assert_eq!(
+            errors("  align 3\n"),
+            vec![("alignment 3 is not a power of 2".to_string(), 1)]
+        );
+    }
+
+    #[test]
+    fn subsections_via_symbols() {
+        let s = ".subsections_via_symbols
+  nop
+f:
+  ret
+section .data
+  align 8
+x: dq 1
+section .bss
+  resb 4
+y: resb 4
+";
+        assert!(assemble_str(s).subsections_via_symbols);
+        assert_eq!(
+            warnings(s),
+            vec![
+                (
+                    "content before the first symbol in __TEXT,__text can not be dead-stripped"
+                        .to_string(),
+                    2
+                ),
+                (
+                    "content before the first symbol in __DATA,__bss can not be dead-stripped"
+                        .to_string(),
+                    9
+                ),
+            ]
+        );
+        assert!(!assemble_str("f:\n  ret\n").subsections_via_symbols);
+    }
+
+    #[test]
+    fn symbol_directives() {
+        let obj = assemble_str(
+            ".private_extern _helper
+global _main
+.weak_definition _main
+.no_dead_strip _helper
+.weak_reference _optional
+.reference _kept
+.alt_entry inner
+_helper:
+  ret
+_main:
+  call _optional
+inner:
+  ret
+",
+        );
+        let symbols = section(&obj, "__TEXT,__text")
+            .symbols
+            .iter()
+            .map(|sym| match sym {
+                Symbol::Ref {
+                    name,
+                    ext,
+                    pext,
+                    desc,
+                    ..
+                } => (name.as_str(), *ext, *pext, desc.to_u16()),
+                _ => unreachable!(),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            symbols,
+            vec![
+                ("_helper", true, true, NDescFlag::NoDeadStrip.to_u16()),
+                ("_main", true, false, NDescFlag::WeakDef.to_u16()),
+                ("inner", false, false, NDescFlag::AltEntry.to_u16()),
+            ]
+        );
+        let mut weak = NDesc::new();
+        weak.push(NDescFlag::WeakRef);
+        assert_eq!(
+            obj.symbols,
+            vec![
+                Symbol::Undef {
+                    name: "_optional".to_string(),
+                    desc: weak,
+                },
+                Symbol::Undef {
+                    name: "_kept".to_string(),
+                    desc: NDesc::new(),
+                },
+            ]
+        );
+
+        assert_eq!(
+            errors(".weak_definition f\nf: ret\n.no_dead_strip g\n.private_extern h\n"),
+            vec![
+                ("private extern symbol h is not defined".to_string(), 4),
+                ("weak definition f must be global".to_string(), 1),
+                ("symbol g is not defined".to_string(), 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn common_and_zerofill() {
+        let obj = assemble_str(
+            "global _shared
+.comm _shared, 4096, 4
+.lcomm counter, 4
+.lcomm total, 8, 3
+.zerofill __DATA,__huge,table,1 << 20,12
+  lea rax, [rel _shared]
+  ret
+",
+        );
+        assert_eq!(
+            obj.symbols,
+            vec![Symbol::Common {
+                name: "_shared".to_string(),
+                size: 4096,
+                align: 4,
+                desc: NDesc::new(),
+            }]
+        );
+        assert_eq!(section(&obj, "__TEXT,__text").relocs[0].symbol, "_shared");
+
+        let bss = section(&obj, "__DATA,__bss");
+        assert!(bss.is_zerofill());
+        assert_eq!((bss.zerofill_size, bss.align), (16, 8));
+        let symbols = bss.symbols.iter().map(|sym| sym.name()).collect::<Vec<_>>();
+        assert_eq!(symbols, vec!["counter", "total"]);
+
+        let huge = section(&obj, "__DATA,__huge");
+        assert!(huge.is_zerofill());
+        assert_eq!((huge.zerofill_size, huge.align), (1 << 20, 1 << 12));
+
+        assert_eq!(
+            errors(".comm a, 8, 16\nsection __DATA,__x\ndb 1\n.zerofill __DATA,__x\n"),
+            vec![
+                ("__DATA,__x is not a zerofill section".to_string(), 4),
+                ("alignment 2^16 of common symbol is out of range".to_string(), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn thread_local_variables() {
+        let obj = assemble_str(
+            "global _var
+section .tdata
+_var$tlv$init: dq 42
+section .tlv
+_var:
+  dq __tlv_bootstrap, 0, _var$tlv$init
+section .text
+  mov rdi, [rel _var@TLVP]
+  call [rdi]
+  ret
+",
+        );
+        let tdata = section(&obj, "__DATA,__thread_data");
+        assert_eq!(tdata.sect_type, SectionType::ThreadLocalRegular);
+        let tlv = section(&obj, "__DATA,__thread_vars");
+        assert_eq!((tlv.sect_type, tlv.align), (SectionType::ThreadLocalVariables, 8));
+
+        let text = section(&obj, "__TEXT,__text");
+        assert_eq!(
+            text.relocs,
+            vec![Reloc {
+                addr: 3,
+                symbol: "_var".to_string(),
+                addend: 0,
+                pcrel: true,
+                len: 2,
+                kind: X86_64RelocType::Tlv,
+            }]
+        );
+
+        // `.tbss` は初期値の領域と TLV ディスクリプタを作る
+        let obj = assemble_str(
+            "global _counter
+.tbss _counter, 4, 2
+section .text
+  mov rax, [rel _counter@TLVP]
+",
+        );
+        let tbss = section(&obj, "__DATA,__thread_bss");
+        assert_eq!((tbss.zerofill_size, tbss.align), (4, 4));
+        assert_eq!(tbss.symbols[0].name(), "_counter$tlv$init");
+        let tlv = section(&obj, "__DATA,__thread_vars");
+        assert_eq!(tlv.bytes, vec![0; 24]);
+        assert_eq!(tlv.symbols[0].name(), "_counter");
+        let relocs = tlv
+            .relocs
+            .iter()
+            .map(|r| (r.addr, r.symbol.as_str(), r.kind))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            relocs,
+            vec![
+                (0, "__tlv_bootstrap", X86_64RelocType::Unsigned),
+                (16, "_counter$tlv$init", X86_64RelocType::Unsigned)
+            ]
+        );
+        let undefs = obj.symbols.iter().map(|sym| sym.name()).collect::<Vec<_>>();
+        assert_eq!(undefs, vec!["__tlv_bootstrap"]);
+        assert_eq!(
+            errors("_counter:\n.tbss _counter, 4\n")[0].0,
+            "symbol _counter is defined more than once"
+        );
+
+        assert_eq!(
+            errors("section .tlv\ndq _var@TLVP\n"),
+            vec![(
+                "@TLVP can only be used in a RIP-relative operand: _var@TLVP".to_string(),
+                2
+            )]
+        );
+    }
+
+    #[test]
+    fn merge_literals() {
+        let obj = assemble_str(
+            "section .text
+  lea rdi, [rel hello2]
+  lea rsi, [rel world + 2]
+  mov rax, [rel one_again]
+section .cstring
+hello1: .asciz \"hello\"
+world: .asciz \"world\"
+hello2: .asciz \"hello\"
+bye: .asciz \"bye\"
+bye_len equ $ - bye
+.literal8
+one: dq 0x3FF0000000000000
+two: dq 0x4000000000000000
+one_again: dq 0x3FF0000000000000
+",
+        );
+        let cstring = section(&obj, "__TEXT,__cstring");
+        assert_eq!(cstring.bytes, b"hello\0world\0bye\0");
+        let addrs = cstring
+            .symbols
+            .iter()
+            .map(|sym| match sym {
+                Symbol::Ref { name, addr, .. } => (name.as_str(), *addr),
+                _ => unreachable!(),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            addrs,
+            vec![("hello1", 0), ("world", 6), ("hello2", 0), ("bye", 12)]
+        );
+        // 定数はまとめた後のアドレスで計算する
+        let consts = obj
+            .symbols
+            .iter()
+            .filter_map(|sym| match sym {
+                Symbol::Abs { name, val, .. } => Some((name.as_str(), *val)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(consts, vec![("bye_len", 4)]);
+        let relocs = section(&obj, "__TEXT,__text")
+            .relocs
+            .iter()
+            .map(|reloc| (reloc.symbol.as_str(), reloc.addend))
+            .collect::<Vec<_>>();
+        assert_eq!(relocs, vec![("hello2", 0), ("world", 2), ("one_again", 0)]);
+
+        let literal8 = section(&obj, "__TEXT,__literal8");
+        assert_eq!((literal8.bytes.len(), literal8.align), (16, 8));
+
+        assert_eq!(
+            errors("section .cstring\ndb 'abc', 0\ndb 'def'\n"),
+            vec![("string is not null-terminated in __TEXT,__cstring".to_string(), 3)]
+        );
+        assert_eq!(
+            errors("section .cstring\na: db 'x', 0\nb: db 'x', 0\nc: db $ - $$, 0\n"),
+            vec![(
+                "contents of __TEXT,__cstring depend on the addresses of merged literals"
+                    .to_string(),
+                4
+            )]
+        );
+    }
+
+    #[test]
+    fn undefined_symbols() {
+        let obj = assemble_str(
+            "extern _exit
+extern _unused
+extern f
+  call _printf
+  call _exit
+  jmp f
+f:
+  lea rax, [rel _printf]
+section .data
+  dq _environ + 8
+",
+        );
+        assert_eq!(
+            obj.symbols,
+            vec![
+                Symbol::Undef {
+                    name: "_exit".to_string(),
+                    desc: NDesc::new(),
+                },
+                Symbol::Undef {
+                    name: "_unused".to_string(),
+                    desc: NDesc::new(),
+                },
+                Symbol::Undef {
+                    name: "_printf".to_string(),
+                    desc: NDesc::new(),
+                },
+                Symbol::Undef {
+                    name: "_environ".to_string(),
+                    desc: NDesc::new(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn relocation_kinds() {
+        let obj = assemble_str(
+            "  call _printf
+  lea rax, [rel msg]
+  add qword [rel counter + 8], 1
+  mov qword [rel counter], 0x100
+  jmp [rel _table]
+section .data
+msg dq _printf
+",
+        );
+        let text = section(&obj, "__TEXT,__text");
+        let kinds = text
+            .relocs
+            .iter()
+            .map(|reloc| (reloc.addr, reloc.kind))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            vec![
+                (1, X86_64RelocType::Branch),
+                (8, X86_64RelocType::Signed),
+                (15, X86_64RelocType::Signed1),
+                (23, X86_64RelocType::Signed4),
+                (33, X86_64RelocType::Signed),
+            ]
+        );
+        assert_eq!(&text.bytes[12..20], &[0x48, 0x83, 0x05, 0, 0, 0, 0, 1]);
+        assert_eq!(text.relocs[2].addend, 8);
+
+        let data = section(&obj, "__DATA,__data");
+        assert_eq!(data.relocs[0].kind, X86_64RelocType::Unsigned);
+
+        // SIGNED_1/2/4 で表せないずれはエラーにする
+        let mut code = Code::new();
+        code.extend(&[0x8B, 0x05, 0, 0, 0, 0, 0, 0, 0]);
+        code.fixups.push(Fixup {
+            offset: 2,
+            symbol: "msg".to_string(),
+        });
+        assert_eq!(
+            Output::from_code(code, false).err(),
+            Some("3 bytes follow the displacement of msg, which can not be relocated".to_string())
+        );
+    }
+
+    #[test]
+    fn label_difference() {
+        let obj = assemble_str(
+            ".subsections_via_symbols
+f:
+  ret
+.inner:
+  ret
+g:
+  ret
+section .data
+table:
+  dd f - table
+  dq g - table + 4, g - f
+  dd _ext - g, .inner - f
+  dq g - .inner
+",
+        );
+        let data = section(&obj, "__DATA,__data");
+        // 同じ atom の中の差だけが定数になる
+        let mut bytes = vec![0; 36];
+        bytes[24] = 1;
+        assert_eq!(data.bytes, bytes);
+        let relocs = data
+            .relocs
+            .iter()
+            .map(|r| (r.addr, r.symbol.as_str(), r.addend, r.len, r.kind))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            relocs,
+            vec![
+                (0, "table", 0, 2, X86_64RelocType::Subtractor),
+                (0, "f", 0, 2, X86_64RelocType::Unsigned),
+                (4, "table", 0, 3, X86_64RelocType::Subtractor),
+                (4, "g", 4, 3, X86_64RelocType::Unsigned),
+                (12, "f", 0, 3, X86_64RelocType::Subtractor),
+                (12, "g", 0, 3, X86_64RelocType::Unsigned),
+                (20, "g", 0, 2, X86_64RelocType::Subtractor),
+                (20, "_ext", 0, 2, X86_64RelocType::Unsigned),
+                // `.inner` はシンボルテーブルにないので、それを含む f からのオフセットにする
+                (28, "f", 0, 3, X86_64RelocType::Subtractor),
+                (28, "g", -1, 3, X86_64RelocType::Unsigned),
+            ]
+        );
+        assert_eq!(
+            errors(".subsections_via_symbols\n.x:\n  ret\nf:\n  ret\nsection .data\n  dq f - .x\n"),
+            vec![("no symbol precedes .x in its atom".to_string(), 7)]
+        );
+
+        assert_eq!(
+            errors("f:\nsection .data\n  dw f - $\n"),
+            vec![("expression can not be resolved: f - $".to_string(), 3)]
+        );
+    }
+
+    #[test]
+    fn label_is_not_a_constant() {
+        assert_eq!(
+            errors("msg:\n  mov rax, msg\n"),
+            vec![("expression is not constant: msg".to_string(), 2)]
+        );
+    }
+
+    #[test]
+    fn report_all_errors() {
+        let src = "\
+global main, other
+main:
+  mov rax, [rbx*3]
+main:
+  mov al, 1
+section .bss
+  db 1
+";
+        let (_, diagnostics) = try_assemble(src);
+        let errors = diagnostics
+            .iter()
+            .map(|d| (d.message.as_str(), d.loc.line))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            vec![
+                ("unexpected token `,`", 1),
+                ("invalid scale 3", 3),
+                ("symbol main is defined more than once", 4),
+                ("data can not be placed in __DATA,__bss", 7),
+                // 命令は配置した後に変換する
+                ("unsupported instruction: mov al, 1", 5),
+            ]
+        );
+        assert_eq!(diagnostics[2].notes, vec!["previous definition at test.s:2"]);
+    }
+
+    #[test]
+    fn undefined_global() {
+        assert_eq!(
+            errors("global main\n  ret\n"),
+            vec![("global symbol main is not defined".to_string(), 1)]
+        );
+    }
+}
+
@@ -0,0 +1,259 @@
+use crate::{
+    parser::Span,
+    preprocessor::{Location, SourceLine},
+};
+use std::{
+    cell::RefCell,
+    fmt::{self, Display},
+    rc::Rc,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// ソース上の位置を持つエラーや警告
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub loc: Location,
+    /// 行内の位置. `None` であれば行全体.
+    pub span: Option<Span>,
+    /// 抜粋として表示する行の内容
+    pub source: Option<String>,
+    /// 以前の定義の位置などの補足
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn error(loc: Location, message: impl Into<String>) -> Diagnostic {
+        Diagnostic::new(Severity::Error, loc, message)
+    }
+
+    pub fn warning(loc: Location, message: impl Into<String>) -> Diagnostic {
+        Diagnostic::new(Severity::Warning, loc, message)
+    }
+
+    /// `line` の内容を抜粋として持つエラー
+    pub fn error_at(line: &SourceLine, message: impl Into<String>) -> Diagnostic {
+        Diagnostic::error(line.loc.clone(), message).with_source(line.text.clone())
+    }
+
+    /// `line` の内容を抜粋として持つ警告
+    pub fn warning_at(line: &SourceLine, message: impl Into<String>) -> Diagnostic {
+        Diagnostic::warning(line.loc.clone(), message).with_source(line.text.clone())
+    }
+
+    fn new(severity: Severity, loc: Location, message: impl Into<String>) -> Diagnostic {
+        Diagnostic {
+            severity,
+            message: message.into(),
+            loc,
+            span: None,
+            source: None,
+            notes: Vec::new(),
+        }
+    }
+
+    pub fn with_span(mut self, span: Span) -> Diagnostic {
+        self.span = Some(span);
+        self
+    }
+
+    pub fn with_source(mut self, source: String) -> Diagnostic {
+        self.source = Some(source);
+        self
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Diagnostic {
+        self.notes.push(note.into());
+        self
+    }
+
+    /// 1始まりの列番号
+    fn column(&self) -> Option<usize> {
+        let source = self.source.as_ref()?;
+        let span = self.span?;
+        let start = span.start.min(source.len());
+        Some(source[..start].chars().count() + 1)
+    }
+
+    /// 抜粋の下に引く線. タブはそのまま残して位置を揃える.
+    fn underline(&self, source: &str) -> String {
+        let (start, end) = match self.span {
+            Some(span) => (span.start.min(source.len()), span.end.min(source.len())),
+            None => {
+                let trimmed = source.trim();
+                let start = source.len() - source.trim_start().len();
+                (start, start + trimmed.len())
+            }
+        };
+        let mut line = source[..start]
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect::<String>();
+        let len = source[start..end].chars().count().max(1);
+        line.push_str(&"^".repeat(len));
+        line
+    }
+}
+
+/// rustc と同じ形式で表示する
+///
+/// ```text
+/// error: invalid scale 3
+///  --> main.s:3:14
+///   |
+/// 3 |     mov rax, [rbx*3]
+///   |              ^^^^^^^
+/// ```
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}: {}", self.severity, self.message)?;
+
+        let line = self.loc.line.to_string();
+        let gutter = " ".repeat(line.len());
+        match self.column() {
+            Some(column) => writeln!(f, "{}--> {}:{}", gutter, self.loc.position(), column)?,
+            None => writeln!(f, "{}--> {}", gutter, self.loc.position())?,
+        }
+        if let Some(source) = &self.source {
+            writeln!(f, "{} |", gutter)?;
+            writeln!(f, "{} | {}", line, source)?;
+            writeln!(f, "{} | {}", gutter, self.underline(source))?;
+        }
+
+        // マクロの展開元とインクルード元
+        let mut loc = &self.loc;
+        while let Some(e) = &loc.expansion {
+            writeln!(
+                f,
+                "{} = note: in macro {} called at {} (defined at {})",
+                gutter,
+                e.name,
+                e.call.position(),
+                e.def.position()
+            )?;
+            loc = &e.call;
+        }
+        while let Some(include) = &loc.included_from {
+            writeln!(f, "{} = note: included from {}", gutter, include.position())?;
+            loc = include;
+        }
+        for note in &self.notes {
+            writeln!(f, "{} = note: {}", gutter, note)?;
+        }
+        Ok(())
+    }
+}
+
+/// ファイル全体から集めたエラーと警告.
+/// 各段階で共有するため、クローンしても同じものを指す.
+#[derive(Debug, Clone, Default)]
+pub struct Diagnostics(Rc<RefCell<Vec<Diagnostic>>>);
+
+impl Diagnostics {
+    pub fn new() -> Self {
+        Diagnostics::default()
+    }
+
+    pub fn push(&self, diagnostic: Diagnostic) {
+        self.0.borrow_mut().push(diagnostic);
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.0
+            .borrow()
+            .iter()
+            .any(|d| d.severity == Severity::Error)
+    }
+
+    /// 集めたものを取り出す
+    pub fn take(&self) -> Vec<Diagnostic> {
+        self.0.borrow_mut().split_off(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn location(line: usize) -> Location {
+        Location {
+            file: Rc::from(Path::new("main.s")),
+            line,
+            expansion: None,
+            included_from: None,
+        }
+    }
+
+    #[test]
+    fn display_with_span() {
+        let line = SourceLine {
+            text: "\tmov rax, [rbx*3]".to_string(),
+            loc: location(12),
+            index: 0,
+        };
+        let diagnostic =
+            Diagnostic::error_at(&line, "invalid scale 3").with_span(Span::new(10, 16));
+        assert_eq!(
+            diagnostic.to_string(),
+            "\
+error: invalid scale 3
+  --> main.s:12:11
+   |
+12 | \tmov rax, [rbx*3]
+   | \t         ^^^^^^
+"
+        );
+    }
+
+    #[test]
+    fn display_whole_line() {
+        let line = SourceLine {
+            text: "  foo: ret".to_string(),
+            loc: location(3),
+            index: 0,
+        };
+        let diagnostic = Diagnostic::warning(line.loc, "symbol foo is defined twice")
+            .with_source(line.text)
+            .with_note("previous definition at main.s:1");
+        assert_eq!(
+            diagnostic.to_string(),
+            "\
+warning: symbol foo is defined twice
+ --> main.s:3
+  |
+3 |   foo: ret
+  |   ^^^^^^^^
+  = note: previous definition at main.s:1
+"
+        );
+    }
+
+    #[test]
+    fn collect_diagnostics() {
+        let diagnostics = Diagnostics::new();
+        diagnostics
+            .clone()
+            .push(Diagnostic::warning(location(1), "w"));
+        assert!(!diagnostics.has_errors());
+        diagnostics.push(Diagnostic::error(location(2), "e"));
+        assert!(diagnostics.has_errors());
+        assert_eq!(diagnostics.take().len(), 2);
+        assert!(!diagnostics.has_errors());
+    }
+}
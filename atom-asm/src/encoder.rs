@@ -0,0 +1,286 @@
+use crate::{
+    eval::{Evaluator, Value},
+    parser::{Expr, Instruction, MemOperand, Operand},
+};
+use atom_x86_64::{
+    addr::{Base, Mem, Rel},
+    instructions::*,
+    reg::Reg,
+    Code, Encode as _,
+};
+use std::convert::TryFrom as _;
+
+/// 命令を機械語に変換する.
+/// 式は `ev` で評価する.
+pub fn encode(inst: &Instruction, ev: &Evaluator) -> Result<Code, String> {
+    use Operand::{Imm, Mem, Reg as R};
+    use Reg::{Reg64 as R64, Reg8 as R8};
+
+    // アドレスが決まっていない飛び先
+    if let Some(target) = branch_target(inst) {
+        let value = ev.eval(target)?;
+        let (symbol, addend) = value
+            .as_symbol()
+            .ok_or_else(|| format!("invalid branch target: {}", target))?;
+        let mut code = encode_branch(inst, Rel::Symbol(symbol.to_string()))?;
+        // rel32 にシンボルからのオフセットを書き込む
+        let offset = code.fixups[0].offset;
+        code.bytes[offset..offset + 4].copy_from_slice(&expect_i32(addend)?.to_le_bytes());
+        return Ok(code);
+    }
+
+    let imm = |expr| ev.eval_const(expr);
+    let mem = |m| to_mem(m, ev);
+
+    let mut code = Code::new();
+
+    let result = match (inst.mnemonic.as_str(), inst.operands.as_slice()) {
+        ("mov", [R(R64(r1)), R(R64(r2))]) => mov(*r1, *r2).encode(&mut code),
+        ("mov", [R(R64(r)), Imm(n)]) => mov(*r, imm(n)?).encode(&mut code),
+        ("mov", [R(R64(r)), Mem(m)]) => mov(*r, mem(m)?).encode(&mut code),
+        ("mov", [Mem(m), R(R64(r))]) => mov(mem(m)?, *r).encode(&mut code),
+        ("mov", [Mem(m), Imm(n)]) => mov(mem(m)?, imm(n)?).encode(&mut code),
+
+        ("movzx", [R(R64(r1)), R(R8(r2))]) => movzx(*r1, *r2).encode(&mut code),
+        ("movzx", [R(R64(r)), Mem(m)]) => movzx(*r, mem(m)?).encode(&mut code),
+
+        ("lea", [R(R64(r)), Mem(m)]) => lea(*r, mem(m)?).encode(&mut code),
+
+        ("add", [R(R64(r1)), R(R64(r2))]) => add(*r1, *r2).encode(&mut code),
+        ("add", [R(R64(r)), Imm(n)]) => add(*r, imm(n)?).encode(&mut code),
+        ("add", [R(R64(r)), Mem(m)]) => add(*r, mem(m)?).encode(&mut code),
+        ("add", [Mem(m), R(R64(r))]) => add(mem(m)?, *r).encode(&mut code),
+        ("add", [Mem(m), Imm(n)]) => add(mem(m)?, imm(n)?).encode(&mut code),
+
+        ("sub", [R(R64(r1)), R(R64(r2))]) => sub(*r1, *r2).encode(&mut code),
+        ("sub", [R(R64(r)), Imm(n)]) => sub(*r, imm(n)?).encode(&mut code),
+        ("sub", [R(R64(r)), Mem(m)]) => sub(*r, mem(m)?).encode(&mut code),
+        ("sub", [Mem(m), R(R64(r))]) => sub(mem(m)?, *r).encode(&mut code),
+        ("sub", [Mem(m), Imm(n)]) => sub(mem(m)?, imm(n)?).encode(&mut code),
+
+        ("cmp", [R(R64(r1)), R(R64(r2))]) => cmp(*r1, *r2).encode(&mut code),
+        ("cmp", [R(R64(r)), Imm(n)]) => cmp(*r, imm(n)?).encode(&mut code),
+        ("cmp", [R(R64(r)), Mem(m)]) => cmp(*r, mem(m)?).encode(&mut code),
+        ("cmp", [Mem(m), R(R64(r))]) => cmp(mem(m)?, *r).encode(&mut code),
+        ("cmp", [Mem(m), Imm(n)]) => cmp(mem(m)?, imm(n)?).encode(&mut code),
+
+        ("imul", [R(R64(r1)), R(R64(r2))]) => imul(*r1, *r2).encode(&mut code),
+        ("imul", [R(R64(r)), Mem(m)]) => imul(*r, mem(m)?).encode(&mut code),
+
+        ("idiv", [R(R64(r))]) => idiv(*r).encode(&mut code),
+        ("idiv", [Mem(m)]) => idiv(mem(m)?).encode(&mut code),
+
+        ("push", [R(R64(r))]) => push(*r).encode(&mut code),
+        ("push", [Imm(n)]) => push(imm(n)?).encode(&mut code),
+        ("push", [Mem(m)]) => push(mem(m)?).encode(&mut code),
+
+        ("pop", [R(R64(r))]) => pop(*r).encode(&mut code),
+        ("pop", [Mem(m)]) => pop(mem(m)?).encode(&mut code),
+
+        ("sete", [R(R8(r))]) => sete(*r).encode(&mut code),
+        ("sete", [Mem(m)]) => sete(mem(m)?).encode(&mut code),
+        ("setne", [R(R8(r))]) => setne(*r).encode(&mut code),
+        ("setne", [Mem(m)]) => setne(mem(m)?).encode(&mut code),
+        ("setl", [R(R8(r))]) => setl(*r).encode(&mut code),
+        ("setl", [Mem(m)]) => setl(mem(m)?).encode(&mut code),
+        ("setle", [R(R8(r))]) => setle(*r).encode(&mut code),
+        ("setle", [Mem(m)]) => setle(mem(m)?).encode(&mut code),
+
+        ("jmp", [R(R64(r))]) => jmp(*r).encode(&mut code),
+        ("jmp", [Mem(m)]) => jmp(mem(m)?).encode(&mut code),
+        ("call", [R(R64(r))]) => call(*r).encode(&mut code),
+        ("call", [Mem(m)]) => call(mem(m)?).encode(&mut code),
+
+        ("cqo", []) => cqo().encode(&mut code),
+        ("ret", []) => ret().encode(&mut code),
+        ("syscall", []) => syscall().encode(&mut code),
+        ("ud2", []) => ud2().encode(&mut code),
+        ("int3", []) => int3().encode(&mut code),
+        ("nop", []) => nop().encode(&mut code),
+
+        _ => return Err(format!("unsupported instruction: {}", inst)),
+    };
+    result.map_err(|err| err.to_string())?;
+
+    Ok(code)
+}
+
+/// `jmp label` などの分岐命令であれば飛び先の式を返す
+pub fn branch_target(inst: &Instruction) -> Option<&Expr> {
+    match inst.operands.as_slice() {
+        [Operand::Imm(target)] if is_branch(&inst.mnemonic) => Some(target),
+        _ => None,
+    }
+}
+
+/// `rel8` 形式を持つ分岐命令かどうか
+pub fn has_short_form(inst: &Instruction) -> bool {
+    inst.mnemonic != "call"
+}
+
+/// 飛び先を `rel` として分岐命令を機械語に変換する
+pub fn encode_branch(inst: &Instruction, rel: Rel) -> Result<Code, String> {
+    let code = match inst.mnemonic.as_str() {
+        "jmp" => jmp(rel).to_code(),
+        "call" => call(rel).to_code(),
+        m => match jcc_cond(m) {
+            Some(cond) => jcc(cond, rel).to_code(),
+            None => return Err(format!("{} is not a branch instruction", inst)),
+        },
+    };
+    code.map_err(|err| err.to_string())
+}
+
+/// displacementを評価してメモリオペランドに変換する.
+/// シンボルを含むアドレスはRIP相対になるので、レジスタと一緒には使えない.
+fn to_mem(m: &MemOperand, ev: &Evaluator) -> Result<Mem, String> {
+    let disp = match &m.disp {
+        Some(disp) => ev.eval(disp)?,
+        None => Value::constant(0),
+    };
+    let (symbol, disp) = match disp.as_const() {
+        Some(n) => (None, n),
+        None => match disp.as_symbol() {
+            Some((symbol, addend)) => (Some(symbol.to_string()), addend),
+            None => return Err(format!("displacement can not be resolved: {}", m)),
+        },
+    };
+
+    if symbol.is_some() && (m.base.is_some() || m.index.is_some()) {
+        return Err(format!(
+            "symbol can only be used in a RIP-relative operand: {}",
+            m
+        ));
+    }
+
+    let base = match m.base {
+        Some(reg) => Some(Base::Reg(reg)),
+        None if m.rel || symbol.is_some() => Some(Base::Rip),
+        None => None,
+    };
+
+    Ok(Mem {
+        size: m.size,
+        base,
+        index: m.index,
+        disp: expect_i32(disp)?,
+        symbol,
+    })
+}
+
+fn expect_i32(n: i64) -> Result<i32, String> {
+    i32::try_from(n).map_err(|_| format!("displacement {} does not fit in 32 bits", n))
+}
+
+fn is_branch(mnemonic: &str) -> bool {
+    mnemonic == "jmp" || mnemonic == "call" || jcc_cond(mnemonic).is_some()
+}
+
+/// `je`, `jnz` などの条件
+fn jcc_cond(mnemonic: &str) -> Option<Cond> {
+    mnemonic.strip_prefix('j').and_then(Cond::from_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{object::SectionId, parser::parse_instruction};
+    use std::collections::HashMap;
+
+    fn try_encode(s: &str) -> Result<Code, String> {
+        let symbols = HashMap::new();
+        let inst = parse_instruction(s, 0).unwrap();
+        encode(&inst, &Evaluator::new(&symbols, SectionId(0), 0))
+    }
+
+    fn encode_str(s: &str) -> Code {
+        try_encode(s).unwrap()
+    }
+
+    fn bytes(s: &str) -> Vec<u8> {
+        encode_str(s).bytes
+    }
+
+    #[test]
+    fn encode_parsed_instruction() {
+        assert_eq!(bytes("mov rax, 1"), vec![0xB8, 1, 0, 0, 0]);
+        assert_eq!(bytes("mov [rbp-8], rdi"), vec![0x48, 0x89, 0x7D, 0xF8]);
+        assert_eq!(bytes("add rsp, 4 * 4"), vec![0x48, 0x83, 0xC4, 0x10]);
+        assert_eq!(bytes("syscall"), vec![0x0F, 0x05]);
+        assert_eq!(bytes("ud2"), vec![0x0F, 0x0B]);
+    }
+
+    #[test]
+    fn rip_relative_operand_has_fixup() {
+        let code = encode_str("lea rsi, [rel msg]");
+        assert_eq!(code.bytes, vec![0x48, 0x8D, 0x35, 0, 0, 0, 0]);
+        assert_eq!(code.fixups[0].offset, 3);
+        assert_eq!(code.fixups[0].symbol, "msg");
+
+        // `rel` がなくてもシンボルを含めばRIP相対
+        let code = encode_str("mov rax, [msg + 8]");
+        assert_eq!(code.bytes, vec![0x48, 0x8B, 0x05, 8, 0, 0, 0]);
+
+        // 定数だけなら絶対アドレス
+        assert_eq!(bytes("mov rax, [0x10]"), vec![0x48, 0x8B, 0x04, 0x25, 0x10, 0, 0, 0]);
+    }
+
+    #[test]
+    fn branch_to_label() {
+        let inst = parse_instruction("jnz loop", 0).unwrap();
+        assert_eq!(branch_target(&inst), Some(&Expr::Symbol("loop".to_string())));
+        assert_eq!(encode_branch(&inst, Rel::Rel8(-4)).unwrap().bytes, vec![0x75, 0xFC]);
+
+        let code = encode_str("call _exit + 2");
+        assert_eq!(code.bytes, vec![0xE8, 2, 0, 0, 0]);
+        assert_eq!(code.fixups[0].symbol, "_exit");
+
+        let inst = parse_instruction("push 1", 0).unwrap();
+        assert_eq!(branch_target(&inst), None);
+    }
+
+    #[test]
+    fn immediate_must_be_constant() {
+        assert_eq!(
+            try_encode("mov rax, msg").unwrap_err(),
+            "expression is not constant: msg"
+        );
+    }
+
+    #[test]
+    fn invalid_operands_are_errors() {
+        let cases = [
+            ("mov rax, [rbx + rsp*2]", "rsp can not be used as an index register"),
+            (
+                "mov rax, dword [rbx]",
+                "operand size mismatch: expected qword but got dword [rbx]",
+            ),
+            (
+                "movzx rax, qword [rbx]",
+                "operand size mismatch: expected byte but got qword [rbx]",
+            ),
+            ("add rax, 0x100000000", "immediate 4294967296 does not fit in 32 bits"),
+            ("mov qword [rbx], 0x100000000", "immediate 4294967296 does not fit in 32 bits"),
+            ("push 0x100000000", "immediate 4294967296 does not fit in 32 bits"),
+            (
+                "mov rax, [rbx + msg]",
+                "symbol can only be used in a RIP-relative operand: [rbx+msg]",
+            ),
+            (
+                "mov rax, [foo + rcx*8]",
+                "symbol can only be used in a RIP-relative operand: [rcx*8+foo]",
+            ),
+        ];
+        for (s, message) in cases.iter() {
+            assert_eq!(try_encode(s).unwrap_err(), *message, "{}", s);
+        }
+    }
+
+    #[test]
+    fn unsupported_operand_combination() {
+        assert_eq!(
+            try_encode("mov al, rbx").unwrap_err(),
+            "unsupported instruction: mov al, rbx"
+        );
+    }
+}
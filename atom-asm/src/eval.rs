@@ -0,0 +1,440 @@
+use crate::{
+    object::SectionId,
+    parser::{BinaryOp, Expr, UnaryOp},
+};
+use std::collections::HashMap;
+
+/// 式から参照されるシンボルの定義
+#[derive(Debug, Clone, Copy)]
+pub enum Def<'a> {
+    /// ラベル. `addr` はセクションの先頭からのオフセット.
+    /// `atom` はセクション内で属する atom の番号. リンカは atom ごとに並べ替える.
+    Label {
+        section: SectionId,
+        addr: u64,
+        atom: usize,
+    },
+    /// `equ` による定義. 式中の `$` は定義した行のアドレスになる.
+    Equ {
+        section: SectionId,
+        addr: u64,
+        expr: &'a Expr,
+    },
+}
+
+/// リンク時にアドレスが決まるもの
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Term {
+    /// セクション内のラベル
+    Label {
+        name: String,
+        section: SectionId,
+        addr: u64,
+        atom: usize,
+    },
+    /// `$`, `$$` が指すセクションの先頭
+    Section(SectionId),
+    /// このファイルで定義されていないシンボル
+    Extern(String),
+}
+
+impl Term {
+    fn section(&self) -> Option<SectionId> {
+        match self {
+            Term::Label { section, .. } | Term::Section(section) => Some(*section),
+            Term::Extern(_) => None,
+        }
+    }
+
+    /// シンボルであれば名前とセクション内のアドレス
+    fn symbol(&self) -> Option<(&str, u64)> {
+        match self {
+            Term::Label { name, addr, .. } => Some((name, *addr)),
+            Term::Extern(name) => Some((name, 0)),
+            Term::Section(_) => None,
+        }
+    }
+}
+
+/// 式の評価結果. `n + Σ coeff * term` を表す.
+/// ラベルのセクション内のアドレスは `n` に含まれている.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Value {
+    pub n: i64,
+    pub terms: Vec<(Term, i64)>,
+}
+
+impl Value {
+    pub fn constant(n: i64) -> Value {
+        Value {
+            n,
+            terms: Vec::new(),
+        }
+    }
+
+    pub fn as_const(&self) -> Option<i64> {
+        if self.terms.is_empty() {
+            Some(self.n)
+        } else {
+            None
+        }
+    }
+
+    /// `symbol + addend` の形であればシンボル名とaddendを返す
+    pub fn as_symbol(&self) -> Option<(&str, i64)> {
+        match self.terms.as_slice() {
+            [(term, 1)] => term
+                .symbol()
+                .map(|(name, addr)| (name, self.n - addr as i64)),
+            _ => None,
+        }
+    }
+
+    /// `a - b + addend` の形であれば `a`, `b` とaddendを返す.
+    /// 同じセクション内の差は定数になるので、`a`, `b` は別のセクションか外部のシンボル.
+    pub fn as_difference(&self) -> Option<(&str, &str, i64)> {
+        let (plus, minus) = match self.terms.as_slice() {
+            [(plus, 1), (minus, -1)] | [(minus, -1), (plus, 1)] => (plus, minus),
+            _ => return None,
+        };
+        let (plus, plus_addr) = plus.symbol()?;
+        let (minus, minus_addr) = minus.symbol()?;
+        Some((plus, minus, self.n - plus_addr as i64 + minus_addr as i64))
+    }
+
+    /// セクション内のアドレスであればセクションとアドレスを返す
+    pub fn as_section_addr(&self) -> Option<(SectionId, u64)> {
+        match self.terms.as_slice() {
+            [(term, 1)] => term.section().map(|section| (section, self.n as u64)),
+            _ => None,
+        }
+    }
+
+    fn neg(self) -> Value {
+        Value {
+            n: self.n.wrapping_neg(),
+            terms: self
+                .terms
+                .into_iter()
+                .map(|(term, coeff)| (term, -coeff))
+                .collect(),
+        }
+    }
+
+    fn add(mut self, other: Value) -> Value {
+        self.n = self.n.wrapping_add(other.n);
+        self.terms.extend(other.terms);
+        self.normalize();
+        self
+    }
+
+    /// 同じシンボルをまとめ、同じセクション内の差を打ち消す
+    fn normalize(&mut self) {
+        let mut terms: Vec<(Term, i64)> = Vec::new();
+        for (term, coeff) in self.terms.drain(..) {
+            match terms.iter_mut().find(|(t, _)| *t == term) {
+                Some((_, c)) => *c += coeff,
+                None => terms.push((term, coeff)),
+            }
+        }
+
+        // `$ - msg` のように係数の和が0になるセクションはアドレスの差だけが残る.
+        // 別の atom のラベルの差はリンク時に変わるので残す.
+        let mut sections = terms.iter().filter_map(|(t, _)| t.section()).collect::<Vec<_>>();
+        sections.dedup();
+        for section in sections {
+            let sum = terms
+                .iter()
+                .filter(|(t, _)| t.section() == Some(section))
+                .map(|(_, coeff)| coeff)
+                .sum::<i64>();
+            let mut atoms = terms
+                .iter()
+                .filter(|(t, coeff)| t.section() == Some(section) && *coeff != 0)
+                .filter_map(|(t, _)| match t {
+                    Term::Label { atom, .. } => Some(*atom),
+                    _ => None,
+                });
+            let same_atom = match atoms.next() {
+                Some(first) => atoms.all(|atom| atom == first),
+                None => true,
+            };
+            if sum == 0 && same_atom {
+                terms.retain(|(t, _)| t.section() != Some(section));
+            }
+        }
+
+        terms.retain(|(_, coeff)| *coeff != 0);
+        self.terms = terms;
+    }
+}
+
+/// 式を評価する
+pub struct Evaluator<'a> {
+    symbols: &'a HashMap<String, Def<'a>>,
+    section: SectionId,
+    /// `$` のセクション内のアドレス
+    here: u64,
+}
+
+impl<'a> Evaluator<'a> {
+    pub fn new(symbols: &'a HashMap<String, Def<'a>>, section: SectionId, here: u64) -> Self {
+        Evaluator {
+            symbols,
+            section,
+            here,
+        }
+    }
+
+    pub fn section(&self) -> SectionId {
+        self.section
+    }
+
+    pub fn here(&self) -> u64 {
+        self.here
+    }
+
+    /// 同じセクションの `here` で評価する
+    pub fn at(&self, here: u64) -> Evaluator<'a> {
+        Evaluator::new(self.symbols, self.section, here)
+    }
+
+    pub fn eval(&self, expr: &Expr) -> Result<Value, String> {
+        self.eval_with(expr, &mut Vec::new())
+    }
+
+    /// アセンブル時に値が決まる式を評価する
+    pub fn eval_const(&self, expr: &Expr) -> Result<i64, String> {
+        let value = self.eval(expr)?;
+        expect_const(expr, value)
+    }
+
+    /// `equs` は評価中の `equ` (循環参照の検出に使う)
+    fn eval_with(&self, expr: &Expr, equs: &mut Vec<String>) -> Result<Value, String> {
+        let value = match expr {
+            Expr::Int(n) => Value::constant(*n),
+            Expr::Here => Value {
+                n: self.here as i64,
+                terms: vec![(Term::Section(self.section), 1)],
+            },
+            Expr::SectionStart => Value {
+                n: 0,
+                terms: vec![(Term::Section(self.section), 1)],
+            },
+            Expr::Symbol(name) => self.eval_symbol(name, equs)?,
+            Expr::Unary(UnaryOp::Neg, expr) => self.eval_with(expr, equs)?.neg(),
+            Expr::Unary(op, expr) => {
+                let n = expect_const(expr, self.eval_with(expr, equs)?)?;
+                Value::constant(eval_unary(*op, n))
+            }
+            Expr::Binary(BinaryOp::Add, lhs, rhs) => {
+                self.eval_with(lhs, equs)?.add(self.eval_with(rhs, equs)?)
+            }
+            Expr::Binary(BinaryOp::Sub, lhs, rhs) => {
+                self.eval_with(lhs, equs)?.add(self.eval_with(rhs, equs)?.neg())
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let l = expect_const(lhs, self.eval_with(lhs, equs)?)?;
+                let r = expect_const(rhs, self.eval_with(rhs, equs)?)?;
+                Value::constant(eval_binary(*op, l, r)?)
+            }
+        };
+        Ok(value)
+    }
+
+    fn eval_symbol(&self, name: &str, equs: &mut Vec<String>) -> Result<Value, String> {
+        let value = match self.symbols.get(name) {
+            Some(Def::Label {
+                section,
+                addr,
+                atom,
+            }) => Value {
+                n: *addr as i64,
+                terms: vec![(
+                    Term::Label {
+                        name: name.to_string(),
+                        section: *section,
+                        addr: *addr,
+                        atom: *atom,
+                    },
+                    1,
+                )],
+            },
+            Some(Def::Equ {
+                section,
+                addr,
+                expr,
+            }) => {
+                if equs.iter().any(|equ| equ == name) {
+                    return Err(format!("{} is defined recursively", name));
+                }
+                equs.push(name.to_string());
+                let value = Evaluator::new(self.symbols, *section, *addr).eval_with(expr, equs);
+                equs.pop();
+                value?
+            }
+            None => Value {
+                n: 0,
+                terms: vec![(Term::Extern(name.to_string()), 1)],
+            },
+        };
+        Ok(value)
+    }
+}
+
+fn expect_const(expr: &Expr, value: Value) -> Result<i64, String> {
+    value
+        .as_const()
+        .ok_or_else(|| format!("expression is not constant: {}", expr))
+}
+
+fn eval_unary(op: UnaryOp, n: i64) -> i64 {
+    match op {
+        UnaryOp::Neg => n.wrapping_neg(),
+        UnaryOp::Not => !n,
+        UnaryOp::LogNot => (n == 0) as i64,
+    }
+}
+
+/// 比較と論理演算の結果は0か1になる
+fn eval_binary(op: BinaryOp, l: i64, r: i64) -> Result<i64, String> {
+    let n = match op {
+        BinaryOp::Add => l.wrapping_add(r),
+        BinaryOp::Sub => l.wrapping_sub(r),
+        BinaryOp::Mul => l.wrapping_mul(r),
+        BinaryOp::Div | BinaryOp::Rem if r == 0 => return Err("division by zero".to_string()),
+        BinaryOp::Div => l.wrapping_div(r),
+        BinaryOp::Rem => l.wrapping_rem(r),
+        // シフト量が64以上なら0になる
+        BinaryOp::Shl => (l as u64).checked_shl(r as u32).unwrap_or(0) as i64,
+        BinaryOp::Shr => (l as u64).checked_shr(r as u32).unwrap_or(0) as i64,
+        BinaryOp::And => l & r,
+        BinaryOp::Or => l | r,
+        BinaryOp::Xor => l ^ r,
+        BinaryOp::Eq => (l == r) as i64,
+        BinaryOp::Ne => (l != r) as i64,
+        BinaryOp::Lt => (l < r) as i64,
+        BinaryOp::Le => (l <= r) as i64,
+        BinaryOp::Gt => (l > r) as i64,
+        BinaryOp::Ge => (l >= r) as i64,
+        BinaryOp::LogAnd => (l != 0 && r != 0) as i64,
+        BinaryOp::LogOr => (l != 0 || r != 0) as i64,
+    };
+    Ok(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{parse_instruction, Operand};
+
+    /// `push <expr>` としてパースした式を評価する
+    fn eval(symbols: &HashMap<String, Def>, here: u64, s: &str) -> Value {
+        let inst = parse_instruction(&format!("push {}", s), 0).unwrap();
+        match &inst.operands[0] {
+            Operand::Imm(expr) => Evaluator::new(symbols, SectionId(1), here)
+                .eval(expr)
+                .unwrap(),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn eval_constant() {
+        let symbols = HashMap::new();
+        let n = |s| eval(&symbols, 0, s).as_const().unwrap();
+        assert_eq!(n("0x2000000 + 4"), 0x2000004);
+        assert_eq!(n("(1 + 2) * 3 - 7 / 2 % 2"), 8);
+        assert_eq!(n("1 << 4 | 3 & ~1 ^ 8"), 26);
+        assert_eq!(n("-1 >> 60"), 15);
+        assert_eq!(n("1 + 1 == 2 && !(3 < -1)"), 1);
+        assert_eq!(n("2 <> 2 || 4 >= 5"), 0);
+    }
+
+    #[test]
+    fn eval_label_difference() {
+        let len = Expr::binary(BinaryOp::Sub, Expr::Here, Expr::Symbol("msg".to_string()));
+        let mut symbols = HashMap::new();
+        symbols.insert(
+            "msg".to_string(),
+            Def::Label {
+                section: SectionId(1),
+                addr: 4,
+                atom: 0,
+            },
+        );
+        symbols.insert(
+            "len".to_string(),
+            Def::Equ {
+                section: SectionId(1),
+                addr: 17,
+                expr: &len,
+            },
+        );
+
+        assert_eq!(eval(&symbols, 0, "len").as_const(), Some(13));
+        assert_eq!(eval(&symbols, 32, "$ - $$").as_const(), Some(32));
+        assert_eq!(eval(&symbols, 32, "($ - msg) * 2").as_const(), Some(56));
+        assert_eq!(eval(&symbols, 0, "msg + len").as_symbol(), Some(("msg", 13)));
+        assert_eq!(eval(&symbols, 0, "_printf - 1").as_symbol(), Some(("_printf", -1)));
+        assert_eq!(
+            eval(&symbols, 8, "$ + 2").as_section_addr(),
+            Some((SectionId(1), 10))
+        );
+        assert_eq!(eval(&symbols, 0, "_printf - msg").as_symbol(), None);
+        assert_eq!(
+            eval(&symbols, 0, "_printf - msg + 2").as_difference(),
+            Some(("_printf", "msg", 2))
+        );
+        assert_eq!(eval(&symbols, 0, "msg - $ + 2").as_difference(), None);
+
+        // 別の atom のラベルの差は定数にならない
+        symbols.insert(
+            "next".to_string(),
+            Def::Label {
+                section: SectionId(1),
+                addr: 24,
+                atom: 1,
+            },
+        );
+        assert_eq!(eval(&symbols, 0, "next - msg").as_const(), None);
+        assert_eq!(
+            eval(&symbols, 0, "next - msg + 2").as_difference(),
+            Some(("next", "msg", 2))
+        );
+        assert_eq!(eval(&symbols, 32, "$ - next").as_const(), Some(8));
+    }
+
+    #[test]
+    fn not_constant() {
+        let symbols = HashMap::new();
+        let expr = Expr::binary(BinaryOp::Mul, Expr::Symbol("msg".to_string()), Expr::Int(2));
+        assert_eq!(
+            Evaluator::new(&symbols, SectionId(0), 0).eval(&expr),
+            Err("expression is not constant: msg".to_string())
+        );
+        let expr = Expr::binary(BinaryOp::Div, Expr::Int(1), Expr::Int(0));
+        assert_eq!(
+            Evaluator::new(&symbols, SectionId(0), 0).eval(&expr),
+            Err("division by zero".to_string())
+        );
+    }
+
+    #[test]
+    fn recursive_equ() {
+        let a = Expr::Symbol("b".to_string());
+        let b = Expr::Symbol("a".to_string());
+        let mut symbols = HashMap::new();
+        let def = |expr| Def::Equ {
+            section: SectionId(0),
+            addr: 0,
+            expr,
+        };
+        symbols.insert("a".to_string(), def(&a));
+        symbols.insert("b".to_string(), def(&b));
+        assert_eq!(
+            Evaluator::new(&symbols, SectionId(0), 0).eval(&a),
+            Err("b is defined recursively".to_string())
+        );
+    }
+}
@@ -14,32 +14,51 @@
 //!  90 |                               |
 //!  A0 |               ________________|
 //!  B0 |_______________| SymtabCommand |
-//!  C0 |_______________________________|
-//!  D0 |         SectionData           |
-//!  E0 |               ________________|
-//!  F0 |_______________|               |
-//! 100 |_________RelocationInfo________|
-//! 110 |                               |
-//! 120 |_________SymbolTable___________|
-//! 130 |_________StringTable_______|
+//!  C0 |_______________|               |
+//!  D0 |                               |
+//!  E0 |        DysymtabCommand        |
+//!  F0 |                               |
+//! 100 |                               |
+//! 110 |_______________|               |
+//! 120 |         SectionData           |
+//! 130 |               ________________|
+//! 140 |_______________|               |
+//! 150 |_________RelocationInfo________|
+//! 160 |                               |
+//! 170 |_________SymbolTable___________|
+//! 180 |_________StringTable_______|
 use crate::{
     num::NumExt as _,
-    object::{BssSection, DataSection, Object, SectionRef, Symbol, TextSection},
+    object::{Object, Reloc, Section, Symbol},
 };
 use atom_macho::{
-    header::{CpuSubTypeX86_64, CpuType, FileType, Flags, Header64, Magic},
+    header::{CpuSubTypeX86_64, CpuType, FileType, Flag, Flags, Header64, Magic},
     load_command::{
-        segment64::{Section64, SectionAttr, SectionAttrs, SectionType, SegmentCommand64},
+        build_version::BuildVersionCommand,
+        dysymtab::DysymtabCommand,
+        segment64::{Section64, SectionAttr, SectionType, SegmentCommand64},
         symtab::SymtabCommand,
     },
     nlist::{NList64, NType, NTypeField},
-    reloc::{RelocLength, RelocationInfo},
+    reloc::{RelocLength, RelocationInfo, X86_64RelocType},
     string_table::StringTable,
 };
 use std::io::Write;
 
-/// ObjectをMach-O形式で書き込む
-pub fn write_object_into<W: Write>(object: &Object, write: &mut W) {
+/// ObjectをMach-O形式で書き込む.
+/// 書き込めないリロケーションがあればエラーを返し、何も書き込まない.
+pub fn write_object_into<W: Write>(object: &Object, write: &mut W) -> Result<(), String> {
+    let sections = gen_section64s(object);
+
+    // create StringTable (write later)
+    let stab = gen_string_table(object);
+
+    // create Vec<NList64> (write later)
+    let symbols = gen_nlist64s(object, &sections, &stab);
+
+    // create Vec<RelocationInfo> (write later)
+    let relocs = gen_relocation_infos(object, &symbols, &stab)?;
+
     // write Header64
     gen_header64(object).write_into(write);
 
@@ -47,31 +66,31 @@ pub fn write_object_into<W: Write>(object: &Object, write: &mut W) {
     gen_segment_command64(object).write_into(write);
 
     // write Section64
-    let sections = gen_section64s(object);
     sections.iter().for_each(|sect| sect.write_into(write));
 
     // write SymtabCommand
     gen_symtab_command(object).write_into(write);
 
-    // write SectionData
-    write_section_data_into(object, write);
+    // write DysymtabCommand
+    gen_dysymtab_command(object).write_into(write);
 
-    // create StringTable (write later)
-    let stab = gen_string_table(object);
+    // write BuildVersionCommand
+    if let Some(cmd) = gen_build_version_command(object) {
+        cmd.write_into(write);
+    }
 
-    // create Vec<NList64> (write later)
-    let symbols = gen_nlist64s(object, &sections, &stab);
+    // write SectionData
+    write_section_data_into(object, &sections, write)?;
 
     // write Vec<RelocationInfo>
-    gen_relocation_infos(object, &symbols, &stab)
-        .iter()
-        .for_each(|reloc| reloc.write_into(write));
+    relocs.iter().for_each(|reloc| reloc.write_into(write));
 
     // write Vec<NList64>
     symbols.iter().for_each(|sym| sym.write_into(write));
 
     // write StringTable
     write.write_all(stab.as_ref()).unwrap();
+    Ok(())
 }
 
 /// object形式の `Header64` を生成する.
@@ -81,11 +100,22 @@ fn gen_header64(object: &Object) -> Header64 {
         magic: Magic::Magic64,
         cpu_type: CpuType::X86_64(CpuSubTypeX86_64::All),
         file_type: FileType::Object,
-        n_cmds: 2,
-        size_of_cmds: SegmentCommand64::SIZE
-            + object.sections().len() * Section64::SIZE
-            + SymtabCommand::SIZE,
-        flags: Flags::new(),
+        n_cmds: if object.build_version.is_some() { 4 } else { 3 },
+        size_of_cmds: load_commands_size(object) - Header64::SIZE,
+        flags: {
+            let mut flags = Flags::new();
+            if object.subsections_via_symbols {
+                flags.push(Flag::SubsectionsViaSymbols);
+            }
+            let tlv = object
+                .sections()
+                .iter()
+                .any(|sect| sect.sect_type == SectionType::ThreadLocalVariables);
+            if tlv {
+                flags.push(Flag::HasTlvDescriptors);
+            }
+            flags
+        },
         reserved: 0,
     }
 }
@@ -93,131 +123,122 @@ fn gen_header64(object: &Object) -> Header64 {
 fn gen_segment_command64(object: &Object) -> SegmentCommand64 {
     SegmentCommand64 {
         cmd: SegmentCommand64::TYPE,
-        cmdsize: SegmentCommand64::SIZE + object.sections().len() * Section64::SIZE,
+        cmdsize: SegmentCommand64::SIZE + object.sections().len() as u32 * Section64::SIZE,
         // object fileのsegnameは常に空文字
         segname: "".to_string(),
         vmaddr: 0,
-        vmsize: object.sections().iter().map(|sect| sect.vm_size()).sum(),
-        fileoff: (Header64::SIZE
-            + SegmentCommand64::SIZE
-            + object.sections().len() * Section64::SIZE
-            + SymtabCommand::SIZE) as u64,
-        filesize: object
-            .sections()
-            .iter()
-            .map(|sect| sect.file_size())
-            .sum::<u32>()
-            .aligned(8) as u64,
+        // filesize より小さいと llvm-objdump などが壊れたファイルとして扱う
+        vmsize: sections_vm_size(object).aligned(8),
+        fileoff: load_commands_size(object) as u64,
+        filesize: sections_file_size(object) as u64,
         // object fileのprotectionは常に7
         // つまりrwxの全てのbitが立っている状態
         maxprot: 7,
         initprot: 7,
-        nsects: object.sections().len(),
+        nsects: object.sections().len() as u32,
         flags: 0,
     }
 }
 
-fn gen_section64s(object: &Object) -> Vec<Section64> {
-    let mut vmaddr = 0_u64;
-    let mut data_start = Header64::SIZE
+/// ヘッダとロードコマンドの大きさ. セクションデータはこの後に続く.
+fn load_commands_size(object: &Object) -> u32 {
+    Header64::SIZE
         + SegmentCommand64::SIZE
-        + object.sections().len() * Section64::SIZE
-        + SymtabCommand::SIZE;
-    let mut reloc_start = data_start
-        + object
-            .sections()
-            .iter()
-            .map(|sect| sect.file_size())
-            .sum::<u32>()
-            .aligned(8);
+        + object.sections().len() as u32 * Section64::SIZE
+        + SymtabCommand::SIZE
+        + DysymtabCommand::SIZE
+        + match object.build_version {
+            Some(_) => BuildVersionCommand::SIZE,
+            None => 0,
+        }
+}
+
+/// 各セクションのアドレスと、セクションデータの先頭からのファイル上の位置.
+/// どちらもセクションのアラインメントに揃える.
+fn layout_sections(object: &Object) -> Vec<(u64, u32)> {
+    let mut vmaddr = 0_u64;
+    let mut file_pos = 0_u32;
 
     object
         .sections()
         .iter()
         .map(|section| {
+            vmaddr = vmaddr.aligned(section.align);
             let addr = vmaddr;
             vmaddr += section.vm_size();
 
-            let offset = data_start;
-            data_start += section.file_size();
-
-            let reloff = reloc_start;
-            reloc_start += RelocationInfo::SIZE * section.relocs().len() as u32;
+            if !section.is_zerofill() {
+                file_pos = file_pos.aligned(section.align as u32);
+            }
+            let pos = file_pos;
+            file_pos += section.file_size();
 
-            gen_section64(section, addr, offset, reloff)
+            (addr, pos)
         })
         .collect()
 }
 
-fn gen_section64<'a>(section: SectionRef<'a>, addr: u64, offset: u32, reloff: u32) -> Section64 {
-    use SectionRef::*;
+/// 全セクションのメモリ上の大きさ
+fn sections_vm_size(object: &Object) -> u64 {
+    object
+        .sections()
+        .iter()
+        .zip(layout_sections(object))
+        .map(|(section, (addr, _))| addr + section.vm_size())
+        .max()
+        .unwrap_or(0)
+}
 
-    match section {
-        Text(text) => gen_section64_from_text(text, addr, offset, reloff),
-        Data(data) => gen_section64_from_data(data, addr, offset, reloff),
-        Bss(bss) => gen_section64_from_bss(bss, addr),
-    }
+/// 8バイト境界まで埋めた全セクションのファイル上の大きさ
+fn sections_file_size(object: &Object) -> u32 {
+    object
+        .sections()
+        .iter()
+        .zip(layout_sections(object))
+        .map(|(section, (_, pos))| pos + section.file_size())
+        .max()
+        .unwrap_or(0)
+        .aligned(8)
 }
 
-fn gen_section64_from_text(text: &TextSection, addr: u64, offset: u32, reloff: u32) -> Section64 {
-    let mut attrs = SectionAttrs::new();
-    attrs.push(SectionAttr::SomeInstructions);
-    attrs.push(SectionAttr::PureInstructions);
-    if !text.relocs.is_empty() {
-        attrs.push(SectionAttr::LocReloc);
-        attrs.push(SectionAttr::ExtReloc);
-    }
+fn gen_section64s(object: &Object) -> Vec<Section64> {
+    let data_start = load_commands_size(object);
+    let mut reloc_start = data_start + sections_file_size(object);
 
-    Section64 {
-        sectname: "__text".to_string(),
-        segname: "__TEXT".to_string(),
-        addr,
-        size: text.bytes.len() as u64,
-        offset,
-        align: 0,
-        reloff,
-        nreloc: text.relocs.len() as u32,
-        flags: (attrs, SectionType::Regular),
-        reserved1: 0,
-        reserved2: 0,
-        reserved3: 0,
-    }
+    object
+        .sections()
+        .iter()
+        .zip(layout_sections(object))
+        .map(|(section, (addr, pos))| {
+            let offset = data_start + pos;
+
+            let reloff = reloc_start;
+            reloc_start += RelocationInfo::SIZE * section.relocs().len() as u32;
+
+            gen_section64(section, addr, offset, reloff)
+        })
+        .collect()
 }
 
-fn gen_section64_from_data(data: &DataSection, addr: u64, offset: u32, reloff: u32) -> Section64 {
-    let mut attrs = SectionAttrs::new();
-    if !data.relocs.is_empty() {
+fn gen_section64(section: &Section, addr: u64, offset: u32, reloff: u32) -> Section64 {
+    let mut attrs = section.attrs.clone();
+    if !section.relocs.is_empty() {
         attrs.push(SectionAttr::LocReloc);
         attrs.push(SectionAttr::ExtReloc);
     }
 
     Section64 {
-        sectname: "__data".to_string(),
-        segname: "__DATA".to_string(),
+        sectname: section.sectname.clone(),
+        segname: section.segname.clone(),
         addr,
-        size: data.bytes.len() as u64,
-        offset,
-        align: 0,
-        reloff,
-        nreloc: data.relocs.len() as u32,
-        flags: (attrs, SectionType::Regular),
-        reserved1: 0,
-        reserved2: 0,
-        reserved3: 0,
-    }
-}
-
-fn gen_section64_from_bss(bss: &BssSection, addr: u64) -> Section64 {
-    Section64 {
-        sectname: "__bss".to_string(),
-        segname: "__DATA".to_string(),
-        addr,
-        size: bss.size,
-        offset: 0,
-        align: 0,
-        reloff: 0,
-        nreloc: 0,
-        flags: (SectionAttrs::new(), SectionType::Zerofill),
+        size: section.vm_size(),
+        // zerofillのセクションはファイル上に中身を持たない
+        offset: if section.is_zerofill() { 0 } else { offset },
+        // アラインメントは2の累乗の指数で表す
+        align: section.align.trailing_zeros(),
+        reloff: if section.relocs.is_empty() { 0 } else { reloff },
+        nreloc: section.relocs.len() as u32,
+        flags: (attrs, section.sect_type),
         reserved1: 0,
         reserved2: 0,
         reserved3: 0,
@@ -225,37 +246,20 @@ fn gen_section64_from_bss(bss: &BssSection, addr: u64) -> Section64 {
 }
 
 fn gen_symtab_command(object: &Object) -> SymtabCommand {
-    let symoff = Header64::SIZE
-        + SegmentCommand64::SIZE
-        + object.sections().len() * Section64::SIZE
-        + SymtabCommand::SIZE
-        + object
-            .sections()
-            .iter()
-            .map(|s| s.file_size())
-            .sum::<u32>()
-            .aligned(8)
+    let symoff = load_commands_size(object)
+        + sections_file_size(object)
         + object
             .sections()
             .iter()
             .map(|s| s.relocs().len() as u32)
             .sum::<u32>()
             * RelocationInfo::SIZE;
-    let nsyms = object
-        .sections()
-        .iter()
-        .map(|s| s.symbols().len() as u32)
-        .sum::<u32>();
+    let symbols = ordered_symbols(object);
+    let nsyms = symbols.len() as u32;
     let stroff = symoff + nsyms * NList64::SIZE;
-    let strsize = object
-        .sections()
+    let strsize = symbols
         .iter()
-        .map(|s| {
-            s.symbols()
-                .iter()
-                .map(|sym| sym.name().len() as u32 + 1)
-                .sum::<u32>()
-        })
+        .map(|(_, sym)| sym.name().len() as u32 + 1)
         .sum::<u32>()
         + 1;
 
@@ -269,28 +273,219 @@ fn gen_symtab_command(object: &Object) -> SymtabCommand {
     }
 }
 
-fn write_section_data_into<W: Write>(object: &Object, write: &mut W) {
+/// シンボルテーブルは `SymbolGroup` ごとに分けて並べる
+fn gen_dysymtab_command(object: &Object) -> DysymtabCommand {
+    let symbols = ordered_symbols(object);
+    let count = |group| {
+        symbols
+            .iter()
+            .filter(|(_, sym)| symbol_group(sym) == group)
+            .count() as u32
+    };
+    let nlocalsym = count(SymbolGroup::Local);
+    let nextdefsym = count(SymbolGroup::ExtDef);
+    let nundefsym = count(SymbolGroup::Undef);
+
+    DysymtabCommand {
+        cmd: DysymtabCommand::TYPE,
+        cmdsize: DysymtabCommand::SIZE,
+        ilocalsym: 0,
+        nlocalsym,
+        iextdefsym: nlocalsym,
+        nextdefsym,
+        iundefsym: nlocalsym + nextdefsym,
+        nundefsym,
+        // 以下は共有ライブラリでのみ使う
+        tocoff: 0,
+        ntoc: 0,
+        modtaboff: 0,
+        nmodtab: 0,
+        extrefsymoff: 0,
+        nextrefsyms: 0,
+        indirectsymoff: 0,
+        nindirectsyms: 0,
+        extreloff: 0,
+        nextrel: 0,
+        locreloff: 0,
+        nlocrel: 0,
+    }
+}
+
+/// `LC_BUILD_VERSION`.
+/// このアセンブラを表すツールの値はないので、ツールのバージョンは記録しない.
+fn gen_build_version_command(object: &Object) -> Option<BuildVersionCommand> {
+    let build_version = object.build_version?;
+    Some(BuildVersionCommand {
+        cmd: BuildVersionCommand::TYPE,
+        cmdsize: BuildVersionCommand::SIZE,
+        platform: build_version.platform,
+        minos: build_version.minos,
+        sdk: build_version.sdk,
+        ntools: 0,
+    })
+}
+
+/// `LC_DYSYMTAB` で区別するシンボルの種類. この順にシンボルテーブルに並べる.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum SymbolGroup {
+    Local,
+    /// このファイルで定義した外部シンボル
+    ExtDef,
+    Undef,
+}
+
+fn symbol_group(sym: &Symbol) -> SymbolGroup {
+    match sym {
+        // 共通シンボルも未定義シンボルとして扱う
+        Symbol::Undef { .. } | Symbol::Common { .. } => SymbolGroup::Undef,
+        Symbol::Abs { ext: true, .. } | Symbol::Ref { ext: true, .. } => SymbolGroup::ExtDef,
+        Symbol::Abs { ext: false, .. } | Symbol::Ref { ext: false, .. } => SymbolGroup::Local,
+    }
+}
+
+/// シンボルテーブルに並べる順のシンボルと、それを定義したセクションの番号 (1始まり、なければ0).
+/// ローカルなシンボルは定義順、外部定義シンボルと未定義シンボルは名前順に並べる.
+/// アセンブラの中だけで使うラベルは並べない.
+fn ordered_symbols(object: &Object) -> Vec<(usize, &Symbol)> {
+    let mut symbols = object
+        .sections()
+        .iter()
+        .enumerate()
+        .flat_map(|(i, sect)| sect.symbols().iter().map(move |sym| (i + 1, sym)))
+        .chain(object.symbols.iter().map(|sym| (0, sym)))
+        .filter(|(_, sym)| !sym.is_assembler_local())
+        .collect::<Vec<_>>();
+    fn key(sym: &Symbol) -> (SymbolGroup, &str) {
+        match symbol_group(sym) {
+            SymbolGroup::Local => (SymbolGroup::Local, ""),
+            group => (group, sym.name()),
+        }
+    }
+    symbols.sort_by(|(_, a), (_, b)| key(a).cmp(&key(b)));
+    symbols
+}
+
+fn write_section_data_into<W: Write>(
+    object: &Object,
+    sections: &[Section64],
+    write: &mut W,
+) -> Result<(), String> {
+    // セクション間のアラインメントの詰め物は0で埋める
     let mut file_size = 0;
-    object.sections().iter().for_each(|sect| {
-        write.write_all(sect.file_data()).unwrap();
-        file_size += sect.file_size();
-    });
+    for (i, (sect, (_, pos))) in object
+        .sections()
+        .iter()
+        .zip(layout_sections(object))
+        .enumerate()
+    {
+        if sect.is_zerofill() {
+            continue;
+        }
+        let padding = vec![0; (pos - file_size) as usize];
+        write.write_all(&padding).unwrap();
+
+        let mut data = sect.file_data().to_vec();
+        for (j, reloc) in sect.relocs().iter().enumerate() {
+            // 差の値は続く UNSIGNED の addend として書き込む
+            if reloc.kind == X86_64RelocType::Subtractor {
+                continue;
+            }
+            let local = local_target(object, sect.relocs(), j);
+            let value = reloc_field_value(sections, sections[i].addr, reloc, local);
+            let start = reloc.addr as usize;
+            match reloc.len {
+                2 => data[start..start + 4].copy_from_slice(&(value as i32).to_le_bytes()),
+                3 => data[start..start + 8].copy_from_slice(&value.to_le_bytes()),
+                len => return Err(unsupported_length(len)),
+            }
+        }
+        write.write_all(&data).unwrap();
+        file_size = pos + sect.file_size();
+    }
     let padding = [0u8; 7];
     let n_padding = file_size.padding(8) as usize;
     write.write_all(&padding[..n_padding]).unwrap();
+    Ok(())
+}
+
+/// 書き込めるリロケーションの大きさは4バイトと8バイト
+fn unsupported_length(len: u8) -> String {
+    format!("unsupported relocation length {}", len)
+}
+
+/// セクション内で定義されたローカルなシンボルであれば、
+/// そのセクションの番号 (1始まり) とセクション内のアドレスを返す.
+/// ローカルなシンボルへのリロケーションはセクション相対にする.
+fn local_symbol(object: &Object, name: &str) -> Option<(usize, u64)> {
+    object.sections().iter().enumerate().find_map(|(i, sect)| {
+        sect.symbols().iter().find_map(|sym| match sym {
+            Symbol::Ref {
+                name: n,
+                addr,
+                ext: false,
+                ..
+            } if n == name => Some((i + 1, *addr)),
+            _ => None,
+        })
+    })
+}
+
+/// `a - b` を表す SUBTRACTOR とそれに続く UNSIGNED の組であれば `true`.
+/// 組になったリロケーションはローカルなシンボルであってもシンボルを指す.
+fn in_subtractor_pair(relocs: &[Reloc], i: usize) -> bool {
+    relocs[i].kind == X86_64RelocType::Subtractor
+        || (i > 0 && relocs[i - 1].kind == X86_64RelocType::Subtractor)
+}
+
+/// セクション相対にするリロケーションであれば、対象のシンボルのセクションの番号とアドレス.
+/// SUBTRACTOR の組と TLV はリンカがシンボルを必要とするので常にシンボルを指す.
+fn local_target(object: &Object, relocs: &[Reloc], i: usize) -> Option<(usize, u64)> {
+    if in_subtractor_pair(relocs, i) || relocs[i].kind == X86_64RelocType::Tlv {
+        return None;
+    }
+    local_symbol(object, &relocs[i].symbol)
+}
+
+/// リロケーションを適用する箇所に書き込む値.
+/// 外部のシンボルへのリロケーションではaddendを、
+/// セクション相対のリロケーションではこのファイルのアドレスで解決した値を書き込む.
+fn reloc_field_value(
+    sections: &[Section64],
+    sect_addr: u64,
+    reloc: &Reloc,
+    local: Option<(usize, u64)>,
+) -> i64 {
+    // 変位の後に続くバイト数. リンカはその分だけ変位が小さく書かれていると考える.
+    let trailing = match reloc.kind {
+        X86_64RelocType::Signed1 => 1,
+        X86_64RelocType::Signed2 => 2,
+        X86_64RelocType::Signed4 => 4,
+        _ => 0,
+    };
+    match local {
+        None => reloc.addend - trailing,
+        Some((idx, addr)) => {
+            let target = (sections[idx - 1].addr + addr) as i64 + reloc.addend;
+            if reloc.pcrel {
+                // 変位は命令の末尾から数える
+                let next = sect_addr as i64 + reloc.addr as i64 + (1 << reloc.len) + trailing;
+                target - next
+            } else {
+                target
+            }
+        }
+    }
 }
 
 fn gen_string_table(object: &Object) -> StringTable {
     let mut stab = StringTable::with_null();
-    object.sections().iter().for_each(|sect| {
-        sect.symbols()
-            .iter()
-            .for_each(|sym| stab.push_with_null(sym.name()))
-    });
+    ordered_symbols(object)
+        .iter()
+        .for_each(|(_, sym)| stab.push_with_null(sym.name()));
     stab
 }
 
-fn gen_nlist64s(object: &Object, sections: &Vec<Section64>, stab: &StringTable) -> Vec<NList64> {
+fn gen_nlist64s(object: &Object, sections: &[Section64], stab: &StringTable) -> Vec<NList64> {
     fn get_strx(stab: &StringTable, name: &str) -> u32 {
         let mut idx = 0;
         for s in stab.iter() {
@@ -304,81 +499,436 @@ fn gen_nlist64s(object: &Object, sections: &Vec<Section64>, stab: &StringTable)
         idx
     }
 
-    let mut nlists = Vec::new();
-
-    object.sections().iter().enumerate().for_each(|(i, sect)| {
-        let idx = i + 1;
-        sect.symbols().iter().for_each(|sym| {
-            nlists.push(match sym {
-                Symbol::Undef { name } => NList64 {
-                    n_strx: get_strx(stab, name.as_str()),
-                    n_type: NTypeField::Norm {
-                        n_pext: false,
-                        n_type: NType::Undf,
-                        n_ext: true,
-                    },
-                    n_sect: 0,
-                    n_desc: 0,
-                    n_value: 0,
-                },
-                Symbol::Abs { name, val, ext } => NList64 {
-                    n_strx: get_strx(stab, name.as_str()),
-                    n_type: NTypeField::Norm {
-                        n_pext: false,
-                        n_type: NType::Abs,
-                        n_ext: *ext,
-                    },
-                    n_sect: idx as u8,
-                    n_desc: 0,
-                    n_value: *val,
-                },
-                Symbol::Ref { name, addr, ext } => NList64 {
-                    n_strx: get_strx(stab, name.as_str()),
-                    n_type: NTypeField::Norm {
-                        n_pext: false,
-                        n_type: NType::Sect,
-                        n_ext: *ext,
-                    },
-                    n_sect: idx as u8,
-                    n_desc: 0,
-                    n_value: sections[idx - 1].addr + *addr,
-                },
-            })
-        });
-    });
-
-    nlists
+    let gen_nlist64 = |sym: &Symbol, idx: usize| match sym {
+        // 他のファイルで定義されるシンボル (N_UNDF | N_EXT, NO_SECT)
+        Symbol::Undef { name, desc } => NList64 {
+            n_strx: get_strx(stab, name.as_str()),
+            n_type: NTypeField::Norm {
+                n_pext: false,
+                n_type: NType::Undf,
+                n_ext: true,
+            },
+            n_sect: 0,
+            n_desc: desc.to_u16(),
+            n_value: 0,
+        },
+        // 共通シンボルは大きさを n_value に、アラインメントを n_desc に持つ未定義シンボル
+        Symbol::Common {
+            name,
+            size,
+            align,
+            desc,
+        } => NList64 {
+            n_strx: get_strx(stab, name.as_str()),
+            n_type: NTypeField::Norm {
+                n_pext: false,
+                n_type: NType::Undf,
+                n_ext: true,
+            },
+            n_sect: 0,
+            // SET_COMM_ALIGN
+            n_desc: desc.to_u16() | (*align as u16 & 0x0F) << 8,
+            n_value: *size,
+        },
+        // 絶対シンボルはどのセクションにも属さない (NO_SECT)
+        Symbol::Abs {
+            name,
+            val,
+            ext,
+            pext,
+            desc,
+        } => NList64 {
+            n_strx: get_strx(stab, name.as_str()),
+            n_type: NTypeField::Norm {
+                n_pext: *pext,
+                n_type: NType::Abs,
+                n_ext: *ext,
+            },
+            n_sect: 0,
+            n_desc: desc.to_u16(),
+            n_value: *val,
+        },
+        Symbol::Ref {
+            name,
+            addr,
+            ext,
+            pext,
+            desc,
+        } => NList64 {
+            n_strx: get_strx(stab, name.as_str()),
+            n_type: NTypeField::Norm {
+                n_pext: *pext,
+                n_type: NType::Sect,
+                n_ext: *ext,
+            },
+            n_sect: idx as u8,
+            n_desc: desc.to_u16(),
+            n_value: sections[idx - 1].addr + *addr,
+        },
+    };
+
+    ordered_symbols(object)
+        .iter()
+        .map(|(idx, sym)| gen_nlist64(sym, *idx))
+        .collect()
 }
 
 fn gen_relocation_infos(
     object: &Object,
     symbols: &[NList64],
     stab: &StringTable,
-) -> Vec<RelocationInfo> {
-    fn get_sym_idx(symbols: &[NList64], stab: &StringTable, name: &str) -> u32 {
+) -> Result<Vec<RelocationInfo>, String> {
+    fn get_sym_idx(symbols: &[NList64], stab: &StringTable, name: &str) -> Result<u32, String> {
         symbols
             .iter()
-            .enumerate()
-            .find(|(_, sym)| stab.get(sym.n_strx as usize) == name)
-            .map(|(idx, _)| idx as u32)
-            .unwrap()
+            .position(|sym| stab.get(sym.n_strx as usize) == name)
+            .map(|idx| idx as u32)
+            .ok_or_else(|| format!("relocation against unknown symbol {}", name))
     }
 
     let mut reloc_infos = Vec::new();
 
-    object.sections().iter().for_each(|sect| {
-        sect.relocs().iter().for_each(|reloc| {
+    for sect in object.sections() {
+        for (i, reloc) in sect.relocs().iter().enumerate() {
+            // セクション相対であればシンボルではなくセクションの番号を指す
+            let (r_symbolnum, r_extern) = match local_target(object, sect.relocs(), i) {
+                Some((idx, _)) => (idx as u32, false),
+                None => (get_sym_idx(symbols, stab, reloc.symbol.as_str())?, true),
+            };
+            let r_length = match reloc.len {
+                2 => RelocLength::Long,
+                3 => RelocLength::Quad,
+                len => return Err(unsupported_length(len)),
+            };
             let reloc_info = RelocationInfo {
                 r_address: reloc.addr,
-                r_symbolnum: get_sym_idx(symbols, stab, reloc.symbol.as_str()),
+                r_symbolnum,
                 r_pcrel: reloc.pcrel,
-                r_length: RelocLength::from_u32(reloc.len as u32),
-                r_extern: true,
-                r_type: 1,
+                r_length,
+                r_extern,
+                r_type: reloc.kind.to_u8(),
             };
             reloc_infos.push(reloc_info);
+        }
+    }
+
+    Ok(reloc_infos)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{object::BuildVersion, parser::parse_version};
+    use atom_macho::{load_command::build_version::Platform, nlist::NDesc};
+
+    #[test]
+    fn align_sections() {
+        let mut text = Section::new("__TEXT", "__text");
+        text.bytes = vec![0xC3; 5];
+        let mut bss = Section::new("__DATA", "__bss");
+        bss.zerofill_size = 4;
+        bss.align = 8;
+        let mut data = Section::new("__DATA", "__data");
+        data.bytes = vec![1; 3];
+        data.align = 16;
+        let mut object = Object::new();
+        object.sections = vec![text, data, bss];
+
+        let sections = gen_section64s(&object);
+        let layout = sections
+            .iter()
+            .map(|s| (s.addr, s.offset, s.align))
+            .collect::<Vec<_>>();
+        assert_eq!(layout, vec![(0, 448, 0), (16, 464, 4), (24, 0, 3)]);
+
+        let segment = gen_segment_command64(&object);
+        assert_eq!((segment.vmsize, segment.filesize), (32, 24));
+
+        let mut buf = Vec::new();
+        write_object_into(&object, &mut buf).unwrap();
+        // __text の後は __data のアラインメントまで0で埋める
+        assert_eq!(&buf[448..453], &[0xC3; 5]);
+        assert_eq!(&buf[453..464], &[0; 11]);
+        assert_eq!(&buf[464..472], &[1, 1, 1, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn relocated_fields() {
+        let reloc = |addr, symbol: &str, addend, pcrel, kind| Reloc {
+            addr,
+            symbol: symbol.to_string(),
+            addend,
+            pcrel,
+            len: if pcrel { 2 } else { 3 },
+            kind,
+        };
+        let mut text = Section::new("__TEXT", "__text");
+        // add qword [rel counter], 1
+        // call _exit + 4
+        text.bytes = vec![0x48, 0x83, 0x05, 0, 0, 0, 0, 1, 0xE8, 0, 0, 0, 0];
+        text.relocs = vec![
+            reloc(3, "counter", 0, true, X86_64RelocType::Signed1),
+            reloc(9, "_exit", 4, true, X86_64RelocType::Branch),
+        ];
+        let mut data = Section::new("__DATA", "__data");
+        data.bytes = vec![0; 16];
+        data.symbols.push(Symbol::Ref {
+            name: "counter".to_string(),
+            addr: 8,
+            ext: false,
+            pext: false,
+            desc: NDesc::new(),
+        });
+        // dq counter + 2
+        data.relocs = vec![reloc(0, "counter", 2, false, X86_64RelocType::Unsigned)];
+        let mut object = Object::new();
+        object.sections = vec![text, data];
+        object.symbols.push(Symbol::Undef {
+            name: "_exit".to_string(),
+            desc: NDesc::new(),
         });
-    });
 
-    reloc_infos
+        let mut buf = Vec::new();
+        write_object_into(&object, &mut buf).unwrap();
+        // セクションデータは368バイト目から. counter は 0x15 に置かれる.
+        assert_eq!(&buf[371..375], &[0x15 - 8, 0, 0, 0]);
+        assert_eq!(&buf[377..381], &[4, 0, 0, 0]);
+        assert_eq!(&buf[381..389], &[0x15 + 2, 0, 0, 0, 0, 0, 0, 0]);
+
+        let sections = gen_section64s(&object);
+        let stab = gen_string_table(&object);
+        let symbols = gen_nlist64s(&object, &sections, &stab);
+        let relocs = gen_relocation_infos(&object, &symbols, &stab)
+            .unwrap()
+            .iter()
+            .map(|r| (r.r_symbolnum, r.r_extern))
+            .collect::<Vec<_>>();
+        assert_eq!(relocs, vec![(2, false), (1, true), (2, false)]);
+    }
+
+    #[test]
+    fn subtractor_pair() {
+        let reloc = |symbol: &str, addend, kind| Reloc {
+            addr: 0,
+            symbol: symbol.to_string(),
+            addend,
+            pcrel: false,
+            len: 2,
+            kind,
+        };
+        let symbol = |name: &str| Symbol::Ref {
+            name: name.to_string(),
+            addr: 0,
+            ext: false,
+            pext: false,
+            desc: NDesc::new(),
+        };
+        let mut text = Section::new("__TEXT", "__text");
+        text.bytes = vec![0xC3];
+        text.symbols.push(symbol("f"));
+        // table: dd f - table + 4
+        let mut data = Section::new("__DATA", "__data");
+        data.bytes = vec![0; 4];
+        data.symbols.push(symbol("table"));
+        data.relocs = vec![
+            reloc("table", 0, X86_64RelocType::Subtractor),
+            reloc("f", 4, X86_64RelocType::Unsigned),
+        ];
+        let mut object = Object::new();
+        object.sections = vec![text, data];
+
+        let mut buf = Vec::new();
+        write_object_into(&object, &mut buf).unwrap();
+        // __data は369バイト目から. 差は addend だけを書き込む.
+        assert_eq!(&buf[369..373], &[4, 0, 0, 0]);
+
+        let sections = gen_section64s(&object);
+        let stab = gen_string_table(&object);
+        let symbols = gen_nlist64s(&object, &sections, &stab);
+        let relocs = gen_relocation_infos(&object, &symbols, &stab)
+            .unwrap()
+            .iter()
+            .map(|r| (r.r_symbolnum, r.r_extern, r.r_type))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            relocs,
+            vec![
+                (1, true, X86_64RelocType::Subtractor.to_u8()),
+                (0, true, X86_64RelocType::Unsigned.to_u8()),
+            ]
+        );
+    }
+
+    #[test]
+    fn thread_local_variables() {
+        let mut tlv = Section::new("__DATA", "__thread_vars");
+        tlv.bytes = vec![0; 24];
+        tlv.symbols.push(Symbol::Ref {
+            name: "_var".to_string(),
+            addr: 0,
+            ext: false,
+            pext: false,
+            desc: NDesc::new(),
+        });
+        let mut text = Section::new("__TEXT", "__text");
+        // mov rdi, [rel _var@TLVP]
+        text.bytes = vec![0x48, 0x8B, 0x3D, 0, 0, 0, 0];
+        text.relocs.push(Reloc {
+            addr: 3,
+            symbol: "_var".to_string(),
+            addend: 0,
+            pcrel: true,
+            len: 2,
+            kind: X86_64RelocType::Tlv,
+        });
+        let mut object = Object::new();
+        object.sections = vec![tlv, text];
+
+        let flags = gen_header64(&object).flags.to_u32();
+        assert_ne!(flags & Flag::HasTlvDescriptors.to_u32(), 0);
+
+        // 同じファイルのシンボルでもリンカがディスクリプタを見つけられるようにシンボルを指す
+        let sections = gen_section64s(&object);
+        let stab = gen_string_table(&object);
+        let symbols = gen_nlist64s(&object, &sections, &stab);
+        let relocs = gen_relocation_infos(&object, &symbols, &stab)
+            .unwrap()
+            .iter()
+            .map(|r| (r.r_symbolnum, r.r_extern, r.r_type))
+            .collect::<Vec<_>>();
+        assert_eq!(relocs, vec![(0, true, X86_64RelocType::Tlv.to_u8())]);
+    }
+
+    #[test]
+    fn partition_symbols() {
+        let symbol = |name: &str, ext| Symbol::Ref {
+            name: name.to_string(),
+            addr: 0,
+            ext,
+            pext: false,
+            desc: NDesc::new(),
+        };
+        let mut text = Section::new("__TEXT", "__text");
+        text.bytes = vec![0xE8, 0, 0, 0, 0];
+        text.symbols = vec![
+            symbol("_main", true),
+            symbol("loop", false),
+            symbol(".next", false),
+        ];
+        text.relocs.push(Reloc {
+            addr: 1,
+            symbol: "_exit".to_string(),
+            addend: 0,
+            pcrel: true,
+            len: 2,
+            kind: X86_64RelocType::Branch,
+        });
+        let mut data = Section::new("__DATA", "__data");
+        data.symbols = vec![symbol("_buf", true), symbol("count", false)];
+        let mut object = Object::new();
+        object.sections = vec![text, data];
+        object.symbols = vec![
+            Symbol::Undef {
+                name: "_exit".to_string(),
+                desc: NDesc::new(),
+            },
+            Symbol::Abs {
+                name: "SIZE".to_string(),
+                val: 8,
+                ext: false,
+                pext: false,
+                desc: NDesc::new(),
+            },
+            Symbol::Undef {
+                name: "_abort".to_string(),
+                desc: NDesc::new(),
+            },
+        ];
+
+        let sections = gen_section64s(&object);
+        let stab = gen_string_table(&object);
+        let symbols = gen_nlist64s(&object, &sections, &stab);
+        let names = symbols
+            .iter()
+            .map(|sym| stab.get(sym.n_strx as usize))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec!["loop", "count", "SIZE", "_buf", "_main", "_abort", "_exit"]
+        );
+        // アセンブラの中だけのラベルは書き込まない
+        assert_eq!(gen_symtab_command(&object).nsyms, 7);
+
+        let dysymtab = gen_dysymtab_command(&object);
+        assert_eq!((dysymtab.ilocalsym, dysymtab.nlocalsym), (0, 3));
+        assert_eq!((dysymtab.iextdefsym, dysymtab.nextdefsym), (3, 2));
+        assert_eq!((dysymtab.iundefsym, dysymtab.nundefsym), (5, 2));
+
+        // 並べ替えた後の番号を指す
+        let relocs = gen_relocation_infos(&object, &symbols, &stab).unwrap();
+        assert_eq!(relocs[0].r_symbolnum, 6);
+    }
+
+    #[test]
+    fn invalid_relocations() {
+        let mut text = Section::new("__TEXT", "__text");
+        text.bytes = vec![0; 8];
+        text.relocs = vec![Reloc {
+            addr: 0,
+            symbol: "missing".to_string(),
+            addend: 0,
+            pcrel: false,
+            len: 3,
+            kind: X86_64RelocType::Unsigned,
+        }];
+        let mut object = Object::new();
+        object.sections = vec![text];
+
+        // エラーであれば何も書き込まない
+        let mut buf = Vec::new();
+        assert_eq!(
+            write_object_into(&object, &mut buf),
+            Err("relocation against unknown symbol missing".to_string())
+        );
+        assert!(buf.is_empty());
+
+        object.symbols.push(Symbol::Undef {
+            name: "missing".to_string(),
+            desc: NDesc::new(),
+        });
+        object.sections[0].relocs[0].len = 1;
+        assert_eq!(
+            write_object_into(&object, &mut buf),
+            Err("unsupported relocation length 1".to_string())
+        );
+    }
+
+    #[test]
+    fn build_version() {
+        let mut object = Object::new();
+        let mut text = Section::new("__TEXT", "__text");
+        text.bytes = vec![0xC3];
+        object.sections.push(text);
+        object.build_version = Some(BuildVersion {
+            platform: Platform::MacOS,
+            minos: parse_version("11.0").unwrap(),
+            sdk: parse_version("12.0").unwrap(),
+        });
+
+        let header = gen_header64(&object);
+        assert_eq!(header.n_cmds, 4);
+        assert_eq!(header.size_of_cmds, 72 + 80 + 24 + 80 + 24);
+
+        let mut buf = Vec::new();
+        write_object_into(&object, &mut buf).unwrap();
+        let start = 32 + 72 + 80 + 24 + 80;
+        let words = buf[start..start + 24]
+            .chunks(4)
+            .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+            .collect::<Vec<_>>();
+        // cmd, cmdsize, platform, minos, sdk, ntools
+        assert_eq!(words, vec![0x32, 24, 1, 0x000B_0000, 0x000C_0000, 0]);
+        // セクションデータはロードコマンドの直後
+        assert_eq!(buf[header.size_of_cmds as usize + 32], 0xC3);
+    }
 }
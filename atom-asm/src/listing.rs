@@ -0,0 +1,189 @@
+use crate::object::{Object, Symbol};
+use std::{
+    cell::RefCell,
+    io::{self, Write},
+    rc::Rc,
+};
+
+/// 1行に表示するバイト数
+const BYTES_PER_ROW: usize = 10;
+
+/// `-l` で出力するリスティング.
+/// プリプロセッサが読んだ行に、アセンブラが配置したアドレスとバイト列を書き加える.
+#[derive(Debug, Clone, Default)]
+pub struct Listing(Rc<RefCell<Vec<ListingLine>>>);
+
+#[derive(Debug)]
+struct ListingLine {
+    text: String,
+    /// ファイル内の行番号
+    line: usize,
+    /// マクロ展開や `%include` のネストの深さ
+    depth: usize,
+    /// セクションの先頭からのアドレス. 何も配置されなければ `None`.
+    addr: Option<u64>,
+    bytes: Vec<u8>,
+    /// `resb` などで確保した大きさ
+    reserved: u64,
+}
+
+impl Listing {
+    pub fn new() -> Self {
+        Listing::default()
+    }
+
+    /// 読み込んだ行を追加して、その行の番号を返す
+    pub fn push_line(&self, text: &str, line: usize, depth: usize) -> usize {
+        let mut lines = self.0.borrow_mut();
+        lines.push(ListingLine {
+            text: text.to_string(),
+            line,
+            depth,
+            addr: None,
+            bytes: Vec::new(),
+            reserved: 0,
+        });
+        lines.len() - 1
+    }
+
+    /// `index` 番目の行から `addr` に `bytes` を出力した
+    pub fn place_bytes(&self, index: usize, addr: u64, bytes: &[u8]) {
+        let mut lines = self.0.borrow_mut();
+        let line = &mut lines[index];
+        line.addr.get_or_insert(addr);
+        line.bytes.extend_from_slice(bytes);
+    }
+
+    /// `index` 番目の行で `addr` から `size` バイトを確保した
+    pub fn place_reserve(&self, index: usize, addr: u64, size: u64) {
+        let mut lines = self.0.borrow_mut();
+        let line = &mut lines[index];
+        line.addr.get_or_insert(addr);
+        line.reserved += size;
+    }
+
+    /// 各行の後に `obj` のシンボルテーブルを書き込む
+    pub fn write_into<W: Write>(&self, obj: &Object, write: &mut W) -> io::Result<()> {
+        for line in self.0.borrow().iter() {
+            line.write_into(write)?;
+        }
+
+        writeln!(write)?;
+        writeln!(write, "Symbols:")?;
+        writeln!(write, "{:16} {:15} {:6} Name", "Value", "Section", "Scope")?;
+        let symbols = obj
+            .sections
+            .iter()
+            .flat_map(|section| section.symbols.iter().map(move |sym| (section.name(), sym)))
+            .chain(obj.symbols.iter().map(|sym| (String::new(), sym)));
+        for (section, sym) in symbols {
+            let scope = |ext| if ext { "global" } else { "local" };
+            let (val, section, scope) = match sym {
+                Symbol::Ref { addr, ext, .. } => (*addr, section.as_str(), scope(*ext)),
+                Symbol::Abs { val, ext, .. } => (*val, "*ABS*", scope(*ext)),
+                Symbol::Undef { .. } => (0, "*UND*", "extern"),
+                Symbol::Common { size, .. } => (*size, "*COM*", "common"),
+            };
+            writeln!(
+                write,
+                "{:016X} {:15} {:6} {}",
+                val,
+                section,
+                scope,
+                sym.name()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl ListingLine {
+    /// `行番号 アドレス バイト列 ソース` の形式で書き込む.
+    /// 1行に収まらないバイト列は次の行に続ける.
+    fn write_into<W: Write>(&self, write: &mut W) -> io::Result<()> {
+        let addr = match self.addr {
+            Some(addr) => format!("{:08X}", addr),
+            None => String::new(),
+        };
+        let mut rows = self.bytes.chunks(BYTES_PER_ROW).map(hex);
+        let first = match rows.next() {
+            Some(row) => row,
+            None if self.reserved > 0 => format!("<res {:X}>", self.reserved),
+            None => String::new(),
+        };
+        let indent = "  ".repeat(self.depth);
+        writeln!(
+            write,
+            "{:6} {:8} {:20} {}{}",
+            self.line, addr, first, indent, self.text
+        )?;
+
+        for (i, row) in rows.enumerate() {
+            let addr = self.addr.unwrap() + ((i + 1) * BYTES_PER_ROW) as u64;
+            writeln!(write, "{:6} {:08X} {}-", self.line, addr, row)?;
+        }
+        Ok(())
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::Section;
+    use atom_macho::nlist::NDesc;
+
+    #[test]
+    fn write_lines_and_symbols() {
+        let listing = Listing::new();
+        let label = listing.push_line("msg:", 1, 0);
+        let data = listing.push_line("  db 'hello, world', 0", 2, 1);
+        let buf = listing.push_line("buf resb 16", 3, 0);
+        listing.push_line("; end", 4, 0);
+        listing.place_bytes(label, 0, &[]);
+        listing.place_bytes(data, 0, b"hello, world\0");
+        listing.place_reserve(buf, 0, 16);
+
+        let mut obj = Object::new();
+        obj.sections.push(Section::new("__DATA", "__data"));
+        obj.sections[0].symbols.push(Symbol::Ref {
+            name: "msg".to_string(),
+            addr: 0,
+            ext: true,
+            pext: false,
+            desc: NDesc::new(),
+        });
+        obj.symbols.push(Symbol::Abs {
+            name: "len".to_string(),
+            val: 13,
+            ext: false,
+            pext: false,
+            desc: NDesc::new(),
+        });
+        obj.symbols.push(Symbol::Undef {
+            name: "_exit".to_string(),
+            desc: NDesc::new(),
+        });
+
+        let mut out = Vec::new();
+        listing.write_into(&obj, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "     1 00000000                      msg:
+     2 00000000 68656C6C6F2C20776F72     db 'hello, world', 0
+     2 0000000A 6C6400-
+     3 00000000 <res 10>             buf resb 16
+     4                               ; end
+
+Symbols:
+Value            Section         Scope  Name
+0000000000000000 __DATA,__data   global msg
+000000000000000D *ABS*           local  len
+0000000000000000 *UND*           extern _exit
+"
+        );
+    }
+}
@@ -0,0 +1,189 @@
+use crate::object::Section;
+use atom_macho::load_command::segment64::SectionType;
+use std::{collections::HashMap, ops::Range};
+
+/// `__cstring` と `__literal4/8/16` のセクションで同じ値を1つにまとめた配置
+pub struct Merge {
+    /// 各値の元のオフセット、移動先のオフセット、残すかどうか. 元のオフセットの順に並べる.
+    moves: Vec<(u64, u64, bool)>,
+    old_len: u64,
+    /// まとめた後の中身
+    pub bytes: Vec<u8>,
+}
+
+impl Merge {
+    /// まとめる前の中身から配置を決める.
+    /// まとめる値がなければ `None` を返す.
+    pub fn new(section: &Section) -> Result<Option<Merge>, String> {
+        let size = match literal_size(section) {
+            Some(size) => size,
+            None => return Ok(None),
+        };
+        let literals = split_literals(&section.bytes, size)
+            .map_err(|message| format!("{} in {}", message, section.name()))?;
+
+        let mut moves = Vec::with_capacity(literals.len());
+        let mut bytes = Vec::new();
+        let mut offsets = HashMap::new();
+        for range in literals {
+            let value = &section.bytes[range.clone()];
+            // リロケーションを含む値はリンク時に中身が変わるのでまとめない
+            let mergeable = !section
+                .relocs
+                .iter()
+                .any(|reloc| range.contains(&(reloc.addr as usize)));
+            match offsets.get(value) {
+                Some(&offset) if mergeable => moves.push((range.start as u64, offset, false)),
+                _ => {
+                    let offset = bytes.len() as u64;
+                    bytes.extend_from_slice(value);
+                    if mergeable {
+                        offsets.insert(value, offset);
+                    }
+                    moves.push((range.start as u64, offset, true));
+                }
+            }
+        }
+        if bytes.len() == section.bytes.len() {
+            return Ok(None);
+        }
+        Ok(Some(Merge {
+            moves,
+            old_len: section.bytes.len() as u64,
+            bytes,
+        }))
+    }
+
+    /// 元のアドレスがまとめた後に指すアドレス.
+    /// 取り除いた値の中であれば、残した値の同じ位置を指す.
+    pub fn remap(&self, addr: u64) -> u64 {
+        match self.literal(addr) {
+            Some((start, offset, _)) => offset + (addr - start),
+            None => addr - self.old_len + self.bytes.len() as u64,
+        }
+    }
+
+    /// 元のアドレスのバイトがまとめた後も残るかどうか
+    pub fn is_kept(&self, addr: u64) -> bool {
+        !matches!(self.literal(addr), Some((_, _, false)))
+    }
+
+    /// 元のアドレスを含む値. セクションの末尾以降であれば `None`.
+    fn literal(&self, addr: u64) -> Option<(u64, u64, bool)> {
+        if addr >= self.old_len {
+            return None;
+        }
+        let i = self.moves.partition_point(|&(start, _, _)| start <= addr);
+        Some(self.moves[i - 1])
+    }
+}
+
+/// まとめる値の大きさ. C 文字列は長さが決まっていないので `Some(None)`.
+/// リテラルのセクションでなければ `None`.
+fn literal_size(section: &Section) -> Option<Option<usize>> {
+    match section.sect_type {
+        SectionType::CstringLiterals => Some(None),
+        SectionType::FourByteLiterals => Some(Some(4)),
+        SectionType::EightByteLiterals => Some(Some(8)),
+        SectionType::SixteenByteLiterals => Some(Some(16)),
+        _ => None,
+    }
+}
+
+/// 同じ値をまとめるセクションかどうか
+pub fn is_literal_section(section: &Section) -> bool {
+    literal_size(section).is_some()
+}
+
+/// セクションの中身を値ごとに分ける. `size` が `None` であれば NUL 終端の文字列.
+fn split_literals(bytes: &[u8], size: Option<usize>) -> Result<Vec<Range<usize>>, String> {
+    match size {
+        Some(size) => {
+            if !bytes.chunks_exact(size).remainder().is_empty() {
+                let message = format!("size {} is not a multiple of {}", bytes.len(), size);
+                return Err(message);
+            }
+            Ok((0..bytes.len())
+                .step_by(size)
+                .map(|start| start..start + size)
+                .collect())
+        }
+        None => {
+            if matches!(bytes.last(), Some(&b) if b != 0) {
+                return Err("string is not null-terminated".to_string());
+            }
+            let mut start = 0;
+            let mut literals = Vec::new();
+            for (i, &b) in bytes.iter().enumerate() {
+                if b == 0 {
+                    literals.push(start..i + 1);
+                    start = i + 1;
+                }
+            }
+            Ok(literals)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::Reloc;
+    use atom_macho::reloc::X86_64RelocType;
+
+    #[test]
+    fn merge_cstrings() {
+        let mut cstring = Section::new("__TEXT", "__cstring");
+        cstring.bytes = b"hello\0world\0hello\0\0".to_vec();
+        let merge = Merge::new(&cstring).unwrap().unwrap();
+        assert_eq!(merge.bytes, b"hello\0world\0\0");
+        let addrs = [0, 6, 12, 14, 18, 19]
+            .iter()
+            .map(|&addr| merge.remap(addr))
+            .collect::<Vec<_>>();
+        assert_eq!(addrs, vec![0, 6, 0, 2, 12, 13]);
+        let kept = (0..19).filter(|&addr| merge.is_kept(addr)).count();
+        assert_eq!(kept, merge.bytes.len());
+        assert!(!merge.is_kept(12) && merge.is_kept(18));
+
+        // 重複がなければまとめない
+        cstring.bytes = b"hello\0world\0".to_vec();
+        assert!(Merge::new(&cstring).unwrap().is_none());
+    }
+
+    #[test]
+    fn merge_fixed_size_literals() {
+        let mut literal8 = Section::new("__TEXT", "__literal8");
+        literal8.bytes = [1.5f64, 2.5, 1.5, 1.5]
+            .iter()
+            .flat_map(|f| f.to_le_bytes().to_vec())
+            .collect();
+        // リロケーションを含む値は同じ中身でも残す
+        literal8.relocs = vec![Reloc {
+            addr: 24,
+            symbol: "x".to_string(),
+            addend: 0,
+            pcrel: false,
+            len: 3,
+            kind: X86_64RelocType::Unsigned,
+        }];
+        let merge = Merge::new(&literal8).unwrap().unwrap();
+        assert_eq!(merge.bytes.len(), 24);
+        assert_eq!((merge.remap(16), merge.remap(24)), (0, 16));
+        assert!(merge.is_kept(24));
+
+        let mut literal4 = Section::new("__TEXT", "__literal4");
+        literal4.bytes = vec![0; 6];
+        assert_eq!(
+            Merge::new(&literal4).err(),
+            Some("size 6 is not a multiple of 4 in __TEXT,__literal4".to_string())
+        );
+        let mut cstring = Section::new("__TEXT", "__cstring");
+        cstring.bytes = b"abc".to_vec();
+        assert_eq!(
+            Merge::new(&cstring).err(),
+            Some("string is not null-terminated in __TEXT,__cstring".to_string())
+        );
+        assert!(!is_literal_section(&Section::new("__TEXT", "__text")));
+    }
+}
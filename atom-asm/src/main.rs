@@ -1,64 +1,189 @@
+mod assembler;
+mod diagnostic;
+mod encoder;
+mod eval;
 mod generator;
+mod listing;
+mod literal;
 mod num;
 mod object;
 mod parser;
+mod preprocessor;
 
 use self::{
+    assembler::assemble,
+    diagnostic::Diagnostics,
     generator::macho::write_object_into,
-    object::{DataSection, Object, Reloc, Symbol, TextSection},
+    listing::Listing,
+    object::BuildVersion,
+    parser::{parse_version, platform_from_name, LineStream},
+    preprocessor::Preprocessor,
 };
-use std::fs::{File, OpenOptions};
+use atom_macho::load_command::build_version::Version;
+use std::{fs::File, io::BufReader, path::PathBuf};
 
 fn main() {
-    let mut file = open_file("mach.o");
+    let args = Args::parse();
 
-    let mut obj = Object::new();
+    let input = File::open(&args.input).unwrap_or_else(|e| {
+        eprintln!("could not open {}: {}", args.input.display(), e);
+        std::process::exit(1)
+    });
+    let diagnostics = Diagnostics::new();
+    let mut preprocessor =
+        Preprocessor::new(&args.input, BufReader::new(input), diagnostics.clone());
+    for (name, value) in &args.defines {
+        preprocessor.define(name, value);
+    }
+    for dir in &args.include_dirs {
+        preprocessor.add_include_dir(dir.clone());
+    }
+    let listing = args.listing.as_ref().map(|_| Listing::new());
+    if let Some(listing) = &listing {
+        preprocessor.set_listing(listing.clone());
+    }
+    let lines = LineStream::new(preprocessor, diagnostics.clone());
+    let mut obj = assemble(lines, &diagnostics, listing.as_ref());
+    // ソース中の `.build_version` を優先する
+    if obj.build_version.is_none() {
+        obj.build_version = args.build_version;
+    }
 
-    obj.sections.text = TextSection {
-        bytes: vec![
-            0xB8, 0x04, 0x00, 0x00, 0x02, 0xBF, 0x01, 0x00, 0x00, 0x00, 0x48, 0x8D, 0x35, 0x00,
-            0x00, 0x00, 0x00, 0xBA, 0x0E, 0x00, 0x00, 0x00, 0x0F, 0x05, 0xB8, 0x01, 0x00, 0x00,
-            0x02, 0xBF, 0x00, 0x00, 0x00, 0x00, 0x0F, 0x05,
-        ],
-        symbols: vec![Symbol::Ref {
-            name: "start".to_string(),
-            addr: 0,
-            ext: true,
-        }],
-        relocs: vec![Reloc {
-            addr: 13,
-            symbol: "msg".to_string(),
-            pcrel: true,
-            len: 2,
-        }],
-    };
+    // エラーがあればオブジェクトファイルを作らない
+    let has_errors = diagnostics.has_errors();
+    for diagnostic in diagnostics.take() {
+        eprintln!("{}", diagnostic);
+    }
+    if has_errors {
+        std::process::exit(1);
+    }
 
-    obj.sections.data = DataSection {
-        bytes: vec![
-            0x68, 0x65, 0x6C, 0x6C, 0x6F, 0x2C, 0x20, 0x77, 0x6F, 0x72, 0x6C, 0x64, 0x0A,
-        ],
-        symbols: vec![
-            Symbol::Ref {
-                name: "msg".to_string(),
-                addr: 0,
-                ext: true,
-            },
-            Symbol::Abs {
-                name: "len".to_string(),
-                val: 14,
-                ext: true,
-            },
-        ],
-        relocs: vec![],
-    };
+    // 書き込みの途中で失敗しても中途半端なファイルが残らないよう、先に全体を作る
+    let mut bytes = Vec::new();
+    if let Err(message) = write_object_into(&obj, &mut bytes) {
+        eprintln!("error: {}", message);
+        std::process::exit(1);
+    }
+    if let Err(e) = std::fs::write(&args.output, bytes) {
+        eprintln!("could not write {}: {}", args.output.display(), e);
+        std::process::exit(1);
+    }
 
-    write_object_into(&obj, &mut file);
+    if let (Some(listing), Some(path)) = (listing, &args.listing) {
+        let mut bytes = Vec::new();
+        listing.write_into(&obj, &mut bytes).unwrap();
+        if let Err(e) = std::fs::write(path, bytes) {
+            eprintln!("could not write {}: {}", path.display(), e);
+            std::process::exit(1);
+        }
+    }
 }
 
-fn open_file(path: &str) -> File {
-    OpenOptions::new()
-        .write(true)
-        .create(true)
-        .open(path)
-        .unwrap()
+/// コマンドライン引数
+///
+/// `atom-asm input.s -o output.o -l output.lst -D NAME=VALUE -I dir
+///  --platform macos --min-os 11.0 --sdk 12.0`
+struct Args {
+    input: PathBuf,
+    output: PathBuf,
+    /// `-l` で指定したリスティングファイル
+    listing: Option<PathBuf>,
+    /// `-D` で定義するシンボルと値
+    defines: Vec<(String, String)>,
+    /// `-I` で指定した `%include` の検索先
+    include_dirs: Vec<PathBuf>,
+    /// `--platform`, `--min-os`, `--sdk` で指定した `LC_BUILD_VERSION` の内容
+    build_version: Option<BuildVersion>,
+}
+
+impl Args {
+    fn parse() -> Args {
+        let mut input = None;
+        let mut output = None;
+        let mut listing = None;
+        let mut defines = Vec::new();
+        let mut include_dirs = Vec::new();
+        let mut platform = None;
+        let mut minos = None;
+        let mut sdk = None;
+
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-o" => match args.next() {
+                    Some(path) => output = Some(PathBuf::from(path)),
+                    None => Args::exit_with_usage(),
+                },
+                "-l" => match args.next() {
+                    Some(path) => listing = Some(PathBuf::from(path)),
+                    None => Args::exit_with_usage(),
+                },
+                "-D" => match args.next() {
+                    Some(def) => defines.push(Args::parse_define(&def)),
+                    None => Args::exit_with_usage(),
+                },
+                _ if arg.starts_with("-D") => defines.push(Args::parse_define(&arg[2..])),
+                "-I" => match args.next() {
+                    Some(dir) => include_dirs.push(PathBuf::from(dir)),
+                    None => Args::exit_with_usage(),
+                },
+                _ if arg.starts_with("-I") => include_dirs.push(PathBuf::from(&arg[2..])),
+                "--platform" => match args.next().as_deref().and_then(platform_from_name) {
+                    Some(p) => platform = Some(p),
+                    None => Args::exit_with_usage(),
+                },
+                "--min-os" => match args.next().as_deref().and_then(parse_version) {
+                    Some(version) => minos = Some(version),
+                    None => Args::exit_with_usage(),
+                },
+                "--sdk" => match args.next().as_deref().and_then(parse_version) {
+                    Some(version) => sdk = Some(version),
+                    None => Args::exit_with_usage(),
+                },
+                _ if input.is_none() => input = Some(PathBuf::from(arg)),
+                _ => Args::exit_with_usage(),
+            }
+        }
+
+        let input = input.unwrap_or_else(|| Args::exit_with_usage());
+        // 出力先が指定されなければ入力ファイルの拡張子を `.o` にする
+        let output = output.unwrap_or_else(|| input.with_extension("o"));
+
+        // `--platform` と `--min-os` はどちらも必要. SDK は省略すると 0.0 (不明) にする.
+        let build_version = match (platform, minos) {
+            (Some(platform), Some(minos)) => Some(BuildVersion {
+                platform,
+                minos,
+                sdk: sdk.unwrap_or_else(|| Version::from_u32(0)),
+            }),
+            (None, None) if sdk.is_none() => None,
+            _ => Args::exit_with_usage(),
+        };
+
+        Args {
+            input,
+            output,
+            listing,
+            defines,
+            include_dirs,
+            build_version,
+        }
+    }
+
+    /// `NAME=VALUE`. 値を省略すると空になる.
+    fn parse_define(s: &str) -> (String, String) {
+        match s.find('=') {
+            Some(i) => (s[..i].to_string(), s[i + 1..].to_string()),
+            None => (s.to_string(), String::new()),
+        }
+    }
+
+    fn exit_with_usage() -> ! {
+        println!(
+            "usage: atom-asm <input.s> [-o <output.o>] [-l <listing.lst>] \
+             [-D <name>[=<value>]]... [-I <dir>]... \
+             [--platform <platform> --min-os <version> [--sdk <version>]]"
+        );
+        std::process::exit(1)
+    }
 }
@@ -1,185 +1,217 @@
+use atom_macho::{
+    load_command::{
+        build_version::{Platform, Version},
+        segment64::{SectionAttr, SectionAttrs, SectionType},
+    },
+    nlist::NDesc,
+    reloc::X86_64RelocType,
+};
+
 pub struct Object {
-    pub sections: Sections,
+    /// ファイルに書き込む順に並べたセクション
+    pub sections: Vec<Section>,
+    /// `equ` で定義した定数など、どのセクションにも属さないシンボル
+    pub symbols: Vec<Symbol>,
+    /// `LC_BUILD_VERSION` に書き込むプラットフォーム. `None` であれば書き込まない.
+    pub build_version: Option<BuildVersion>,
+    /// シンボルごとにセクションを分割できる (`MH_SUBSECTIONS_VIA_SYMBOLS`).
+    /// リンカは参照されないシンボルから始まる部分を `-dead_strip` で取り除ける.
+    pub subsections_via_symbols: bool,
 }
 
 impl Object {
     pub fn new() -> Self {
         Object {
-            sections: Sections {
-                text: TextSection::new(),
-                data: DataSection::new(),
-                bss: BssSection::new(),
-            },
+            sections: Vec::new(),
+            symbols: Vec::new(),
+            build_version: None,
+            subsections_via_symbols: false,
         }
     }
 
-    pub fn sections(&self) -> &Sections {
+    pub fn sections(&self) -> &[Section] {
         &self.sections
     }
 }
 
-pub struct Sections {
-    pub text: TextSection,
-    pub data: DataSection,
-    pub bss: BssSection,
+/// 対象のプラットフォームと、最低限必要な OS と SDK のバージョン
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BuildVersion {
+    pub platform: Platform,
+    pub minos: Version,
+    pub sdk: Version,
 }
 
-impl Sections {
-    /// iterate of all sections except empty section.
-    pub fn iter<'a>(&'a self) -> impl Iterator<Item = SectionRef<'a>> {
-        let arr = [
-            SectionRef::Text(&self.text),
-            SectionRef::Data(&self.data),
-            SectionRef::Bss(&self.bss),
-        ];
-        std::array::IntoIter::new(arr).filter(|sect| !sect.is_empty())
-    }
-
-    pub fn len(&self) -> u32 {
-        self.iter().count() as u32
-    }
-}
+/// `Object.sections` の添字
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SectionId(pub usize);
 
-pub enum SectionRef<'a> {
-    Text(&'a TextSection),
-    Data(&'a DataSection),
-    Bss(&'a BssSection),
+pub struct Section {
+    pub segname: String,
+    pub sectname: String,
+    pub sect_type: SectionType,
+    pub attrs: SectionAttrs,
+    /// アラインメントのバイト数. 2の累乗.
+    pub align: u64,
+    /// 中身. zerofill のセクションでは常に空.
+    pub bytes: Vec<u8>,
+    /// `Zerofill` と `ThreadLocalZerofill` のセクションの大きさ
+    pub zerofill_size: u64,
+    pub symbols: Vec<Symbol>,
+    pub relocs: Vec<Reloc>,
 }
 
-impl<'a> SectionRef<'a> {
-    pub fn vm_size(&self) -> u64 {
-        use SectionRef::*;
-
-        match self {
-            Text(text) => text.bytes.len() as u64,
-            Data(data) => data.bytes.len() as u64,
-            Bss(bss) => bss.size,
+impl Section {
+    /// 名前から種類と属性を決めてセクションを作る
+    pub fn new(segname: &str, sectname: &str) -> Section {
+        let sect_type = match sectname {
+            "__bss" | "__common" => SectionType::Zerofill,
+            "__cstring" => SectionType::CstringLiterals,
+            "__literal4" => SectionType::FourByteLiterals,
+            "__literal8" => SectionType::EightByteLiterals,
+            "__literal16" => SectionType::SixteenByteLiterals,
+            "__thread_data" => SectionType::ThreadLocalRegular,
+            "__thread_bss" => SectionType::ThreadLocalZerofill,
+            "__thread_vars" => SectionType::ThreadLocalVariables,
+            _ => SectionType::Regular,
+        };
+        let mut attrs = SectionAttrs::new();
+        if (segname, sectname) == ("__TEXT", "__text") {
+            attrs.push(SectionAttr::PureInstructions);
+            attrs.push(SectionAttr::SomeInstructions);
         }
-    }
-
-    pub fn is_empty(&self) -> bool {
-        self.vm_size() == 0
-    }
-
-    pub fn file_data(&self) -> &[u8] {
-        use SectionRef::*;
-
-        const EMPTY: [u8; 0] = [];
 
-        match self {
-            Text(text) => text.bytes.as_slice(),
-            Data(data) => data.bytes.as_slice(),
-            Bss(_) => &EMPTY,
+        Section {
+            segname: segname.to_string(),
+            sectname: sectname.to_string(),
+            sect_type,
+            attrs,
+            align: match sect_type {
+                SectionType::FourByteLiterals => 4,
+                // TLV ディスクリプタはポインタを並べたもの
+                SectionType::EightByteLiterals | SectionType::ThreadLocalVariables => 8,
+                SectionType::SixteenByteLiterals => 16,
+                _ => 1,
+            },
+            bytes: Vec::new(),
+            zerofill_size: 0,
+            symbols: Vec::new(),
+            relocs: Vec::new(),
         }
     }
 
-    pub fn file_size(&self) -> u32 {
-        self.file_data().len() as u32
+    /// `__TEXT,__text` のような表記
+    pub fn name(&self) -> String {
+        format!("{},{}", self.segname, self.sectname)
     }
 
-    pub fn symbols(&self) -> &[Symbol] {
-        use SectionRef::*;
-
-        match self {
-            Text(text) => text.symbols.as_slice(),
-            Data(data) => data.symbols.as_slice(),
-            Bss(bss) => bss.symbols.as_slice(),
-        }
+    /// ファイル上に中身を持たないセクションかどうか
+    pub fn is_zerofill(&self) -> bool {
+        matches!(
+            self.sect_type,
+            SectionType::Zerofill | SectionType::ThreadLocalZerofill
+        )
     }
 
-    pub fn relocs(&self) -> &[Reloc] {
-        use SectionRef::*;
-
-        const EMPTY: [Reloc; 0] = [];
-
-        match self {
-            Text(text) => text.relocs.as_slice(),
-            Data(data) => data.relocs.as_slice(),
-            Bss(_) => &EMPTY,
+    pub fn vm_size(&self) -> u64 {
+        if self.is_zerofill() {
+            self.zerofill_size
+        } else {
+            self.bytes.len() as u64
         }
     }
-}
-
-pub trait Section {
-    fn vm_size(&self) -> u64;
-    fn file_data(&self) -> &[u8];
-    fn symbols(&self) -> &[Symbol];
-    fn relocs(&self) -> &[Reloc];
 
-    fn file_size(&self) -> u32 {
-        self.file_data().len() as u32
+    pub fn file_data(&self) -> &[u8] {
+        self.bytes.as_slice()
     }
-}
-
-pub struct TextSection {
-    pub bytes: Vec<u8>,
-    pub symbols: Vec<Symbol>,
-    pub relocs: Vec<Reloc>,
-}
 
-impl TextSection {
-    pub fn new() -> TextSection {
-        TextSection {
-            bytes: Vec::new(),
-            symbols: Vec::new(),
-            relocs: Vec::new(),
-        }
+    pub fn file_size(&self) -> u32 {
+        self.file_data().len() as u32
     }
-}
-
-pub struct DataSection {
-    pub bytes: Vec<u8>,
-    pub symbols: Vec<Symbol>,
-    pub relocs: Vec<Reloc>,
-}
 
-impl DataSection {
-    pub fn new() -> DataSection {
-        DataSection {
-            bytes: Vec::new(),
-            symbols: Vec::new(),
-            relocs: Vec::new(),
-        }
+    pub fn symbols(&self) -> &[Symbol] {
+        self.symbols.as_slice()
     }
-}
-
-pub struct BssSection {
-    pub size: u64,
-    pub symbols: Vec<Symbol>,
-}
 
-impl BssSection {
-    pub fn new() -> Self {
-        BssSection {
-            size: 0,
-            symbols: Vec::new(),
-        }
+    pub fn relocs(&self) -> &[Reloc] {
+        self.relocs.as_slice()
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Reloc {
     /// offset from the start of the section to the
     /// item containing the address requiring relocation
     pub addr: i32,
     pub symbol: String,
+    /// シンボルのアドレスに加える値
+    pub addend: i64,
     pub pcrel: bool,
     // 0 => 1 byte, 1 => 2 byte, 2 => 4 byte, 3 => 8 byte
     pub len: u8,
+    pub kind: X86_64RelocType,
 }
 
+/// `ext` は他のファイルから見えるかどうか, `pext` は `.private_extern` でリンク後に
+/// ローカルになるかどうか. `desc` は `.weak_definition` などで指定した属性.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Symbol {
-    Undef { name: String },
-    Abs { name: String, val: u64, ext: bool },
-    Ref { name: String, addr: u64, ext: bool },
+    Undef {
+        name: String,
+        desc: NDesc,
+    },
+    Abs {
+        name: String,
+        val: u64,
+        ext: bool,
+        pext: bool,
+        desc: NDesc,
+    },
+    Ref {
+        name: String,
+        addr: u64,
+        ext: bool,
+        pext: bool,
+        desc: NDesc,
+    },
+    /// `.comm` による共通シンボル. リンカが `size` バイトの領域を確保する.
+    /// `align` は2の累乗の指数.
+    Common {
+        name: String,
+        size: u64,
+        align: u8,
+        desc: NDesc,
+    },
 }
 
 impl Symbol {
     pub fn name(&self) -> &str {
         match self {
-            Symbol::Undef { name } => name.as_str(),
+            Symbol::Undef { name, .. } => name.as_str(),
             Symbol::Abs { name, .. } => name.as_str(),
             Symbol::Ref { name, .. } => name.as_str(),
+            Symbol::Common { name, .. } => name.as_str(),
+        }
+    }
+
+    pub fn desc_mut(&mut self) -> &mut NDesc {
+        match self {
+            Symbol::Undef { desc, .. }
+            | Symbol::Abs { desc, .. }
+            | Symbol::Ref { desc, .. }
+            | Symbol::Common { desc, .. } => desc,
         }
     }
+
+    /// シンボルテーブルに書き込まない、アセンブラの中だけで使うラベルかどうか.
+    /// これへのリロケーションはセクション相対にする.
+    pub fn is_assembler_local(&self) -> bool {
+        matches!(self, Symbol::Ref { name, ext: false, .. } if is_assembler_local(name))
+    }
+}
+
+/// Mach-O の慣習で `L_` や `l_` で始まるラベルと、`.` で始まるローカルラベル.
+/// マクロの `%%` ラベル (`..@`) も含む.
+pub fn is_assembler_local(name: &str) -> bool {
+    name.starts_with("L_") || name.starts_with("l_") || name.starts_with('.')
 }
@@ -0,0 +1,95 @@
+use super::token::{ParseError, ParseResult, Token, Tokens};
+use crate::object::BuildVersion;
+use atom_macho::load_command::build_version::{Platform, Version};
+
+/// `--platform` や `.build_version` で指定するプラットフォームの名前
+pub fn platform_from_name(name: &str) -> Option<Platform> {
+    let platform = match name.to_ascii_lowercase().as_str() {
+        "macos" => Platform::MacOS,
+        "ios" => Platform::IOS,
+        "tvos" => Platform::TvOS,
+        "watchos" => Platform::WatchOS,
+        "bridgeos" => Platform::BridgeOS,
+        "maccatalyst" => Platform::MacCatalyst,
+        "iossimulator" => Platform::IOSSimulator,
+        "tvossimulator" => Platform::TvOSSimulator,
+        "watchossimulator" => Platform::WatchOSSimulator,
+        "driverkit" => Platform::Driverkit,
+        _ => return None,
+    };
+    Some(platform)
+}
+
+/// `11.0` や `10.15.4` 形式のバージョン
+pub fn parse_version(s: &str) -> Option<Version> {
+    let mut parts = s.split('.');
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    let release = match parts.next() {
+        Some(release) => release.parse().ok()?,
+        None => 0,
+    };
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(Version {
+        major,
+        minor,
+        release,
+    })
+}
+
+/// `.build_version macos, 11, 0 sdk_version 12, 0` の `.build_version` 以降をパースする.
+/// リリース番号と `sdk_version` は省略できる.
+pub fn parse_build_version(tokens: &mut Tokens) -> ParseResult<BuildVersion> {
+    let platform = match tokens.peek() {
+        Some(Token::Ident(name)) => match platform_from_name(name) {
+            Some(platform) => platform,
+            None => return Err(tokens.error(format!("unknown platform {}", name))),
+        },
+        _ => return Err(tokens.unexpected("platform name")),
+    };
+    tokens.next_token();
+    tokens.expect_punct(',')?;
+    let minos = parse_version_numbers(tokens)?;
+
+    let sdk = if tokens.eat_keyword("sdk_version") {
+        parse_version_numbers(tokens)?
+    } else {
+        // 0.0 は SDK が不明であることを表す
+        Version::from_u32(0)
+    };
+    tokens.expect_end()?;
+
+    Ok(BuildVersion {
+        platform,
+        minos,
+        sdk,
+    })
+}
+
+/// `major, minor[, release]`
+fn parse_version_numbers(tokens: &mut Tokens) -> ParseResult<Version> {
+    let major = expect_component(tokens, u16::MAX as i64)? as u16;
+    tokens.expect_punct(',')?;
+    let minor = expect_component(tokens, u8::MAX as i64)? as u8;
+    let release = if tokens.eat_punct(',') {
+        expect_component(tokens, u8::MAX as i64)? as u8
+    } else {
+        0
+    };
+    Ok(Version {
+        major,
+        minor,
+        release,
+    })
+}
+
+fn expect_component(tokens: &mut Tokens, max: i64) -> ParseResult<i64> {
+    let n = tokens.expect_int()?;
+    if !(0..=max).contains(&n) {
+        let message = format!("version number {} is out of range", n);
+        return Err(ParseError::new(tokens.prev_span(), message));
+    }
+    Ok(n)
+}
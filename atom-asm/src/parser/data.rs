@@ -0,0 +1,123 @@
+use super::{
+    expr::{parse_expr, Expr},
+    token::{ParseError, ParseResult, Token, Tokens},
+};
+use atom_x86_64::addr::Size;
+use std::path::PathBuf;
+
+/// `db`, `dw`, `dd`, `dq` によるデータ定義
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Data {
+    /// 1要素の大きさ
+    pub size: Size,
+    pub items: Vec<DataItem>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DataItem {
+    Expr(Expr),
+    /// 要素の大きさの倍数になるまで0で埋める
+    Str(Vec<u8>),
+}
+
+/// `incbin "file", offset, len` によるファイルの埋め込み
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Incbin {
+    pub path: PathBuf,
+    /// ファイルの先頭から読み飛ばすバイト数
+    pub offset: Option<Expr>,
+    /// 埋め込むバイト数. 省略するとファイルの終わりまで.
+    pub len: Option<Expr>,
+}
+
+/// `db` などのデータ定義であれば要素の大きさを返す
+pub fn data_size(name: &str) -> Option<Size> {
+    match name.to_ascii_lowercase().as_str() {
+        "db" => Some(Size::Byte),
+        "dw" => Some(Size::Word),
+        "dd" => Some(Size::Dword),
+        "dq" => Some(Size::Qword),
+        _ => None,
+    }
+}
+
+/// `resb` などの領域確保であれば要素の大きさを返す
+pub fn reserve_size(name: &str) -> Option<Size> {
+    match name.to_ascii_lowercase().as_str() {
+        "resb" => Some(Size::Byte),
+        "resw" => Some(Size::Word),
+        "resd" => Some(Size::Dword),
+        "resq" => Some(Size::Qword),
+        _ => None,
+    }
+}
+
+/// `db 1, 2, 'abc'` の `db` 以降をパースする
+pub fn parse_data_items(tokens: &mut Tokens) -> ParseResult<Vec<DataItem>> {
+    let mut items = vec![parse_data_item(tokens)?];
+    while tokens.eat_punct(',') {
+        items.push(parse_data_item(tokens)?);
+    }
+    tokens.expect_end()?;
+    Ok(items)
+}
+
+/// `.asciz "a", "b"` の `.asciz` 以降をパースする. 各文字列の後には NUL を置く.
+pub fn parse_asciz(tokens: &mut Tokens) -> ParseResult<Data> {
+    let mut items = Vec::new();
+    loop {
+        match tokens.peek() {
+            Some(Token::Str(s)) => items.push(DataItem::Str(s.clone())),
+            _ => return Err(tokens.unexpected("string")),
+        }
+        tokens.next_token();
+        items.push(DataItem::Expr(Expr::Int(0)));
+        if !tokens.eat_punct(',') {
+            break;
+        }
+    }
+    tokens.expect_end()?;
+    Ok(Data {
+        size: Size::Byte,
+        items,
+    })
+}
+
+/// `incbin "file", offset, len` の `incbin` 以降をパースする
+pub fn parse_incbin(tokens: &mut Tokens) -> ParseResult<Incbin> {
+    let span = tokens.peek_span();
+    let path = match tokens.peek() {
+        Some(Token::Str(s)) => match String::from_utf8(s.clone()) {
+            Ok(s) => PathBuf::from(s),
+            Err(_) => return Err(ParseError::new(span, "file name is not valid UTF-8")),
+        },
+        _ => return Err(tokens.unexpected("file name")),
+    };
+    tokens.next_token();
+    let offset = if tokens.eat_punct(',') {
+        Some(parse_expr(tokens)?)
+    } else {
+        None
+    };
+    let len = if offset.is_some() && tokens.eat_punct(',') {
+        Some(parse_expr(tokens)?)
+    } else {
+        None
+    };
+    tokens.expect_end()?;
+
+    Ok(Incbin { path, offset, len })
+}
+
+fn parse_data_item(tokens: &mut Tokens) -> ParseResult<DataItem> {
+    // `'a' + 1` のように演算に使われていれば文字定数として扱う
+    let is_str = matches!(tokens.peek_nth(1), None | Some(Token::Punct(',')));
+    match tokens.peek() {
+        Some(Token::Str(s)) if is_str => {
+            let item = DataItem::Str(s.clone());
+            tokens.next_token();
+            Ok(item)
+        }
+        _ => parse_expr(tokens).map(DataItem::Expr),
+    }
+}
@@ -0,0 +1,256 @@
+use super::token::{ParseError, ParseResult, Token, Tokens};
+use std::fmt::{self, Display};
+
+/// アセンブル時に評価する式
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Int(i64),
+    Symbol(String),
+    /// `$`
+    Here,
+    /// `$$`
+    SectionStart,
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+    /// `!`
+    LogNot,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Shl,
+    Shr,
+    And,
+    Or,
+    Xor,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    LogAnd,
+    LogOr,
+}
+
+impl Expr {
+    pub fn binary(op: BinaryOp, lhs: Expr, rhs: Expr) -> Expr {
+        Expr::Binary(op, Box::new(lhs), Box::new(rhs))
+    }
+}
+
+impl BinaryOp {
+    /// 優先順位. 大きいほど強く結合する.
+    fn precedence(self) -> u8 {
+        match self {
+            BinaryOp::LogOr => 0,
+            BinaryOp::LogAnd => 1,
+            BinaryOp::Eq
+            | BinaryOp::Ne
+            | BinaryOp::Lt
+            | BinaryOp::Le
+            | BinaryOp::Gt
+            | BinaryOp::Ge => 2,
+            BinaryOp::Or => 3,
+            BinaryOp::Xor => 4,
+            BinaryOp::And => 5,
+            BinaryOp::Shl | BinaryOp::Shr => 6,
+            BinaryOp::Add | BinaryOp::Sub => 7,
+            BinaryOp::Mul | BinaryOp::Div | BinaryOp::Rem => 8,
+        }
+    }
+
+    fn from_token(token: &Token) -> Option<BinaryOp> {
+        let op = match token {
+            Token::Punct('+') => BinaryOp::Add,
+            Token::Punct('-') => BinaryOp::Sub,
+            Token::Punct('*') => BinaryOp::Mul,
+            Token::Punct('/') => BinaryOp::Div,
+            Token::Punct('%') => BinaryOp::Rem,
+            Token::Shl => BinaryOp::Shl,
+            Token::Shr => BinaryOp::Shr,
+            Token::Punct('&') => BinaryOp::And,
+            Token::Punct('|') => BinaryOp::Or,
+            Token::Punct('^') => BinaryOp::Xor,
+            Token::Eq => BinaryOp::Eq,
+            Token::Ne => BinaryOp::Ne,
+            Token::Lt => BinaryOp::Lt,
+            Token::Le => BinaryOp::Le,
+            Token::Gt => BinaryOp::Gt,
+            Token::Ge => BinaryOp::Ge,
+            Token::LogAnd => BinaryOp::LogAnd,
+            Token::LogOr => BinaryOp::LogOr,
+            _ => return None,
+        };
+        Some(op)
+    }
+}
+
+impl Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expr::Int(n) => n.fmt(f),
+            Expr::Symbol(name) => name.fmt(f),
+            Expr::Here => write!(f, "$"),
+            Expr::SectionStart => write!(f, "$$"),
+            Expr::Unary(op, expr) => write!(f, "{}{}", op, Paren(expr)),
+            Expr::Binary(op, lhs, rhs) => write!(f, "{} {} {}", Paren(lhs), op, Paren(rhs)),
+        }
+    }
+}
+
+/// 二項演算であれば括弧を付けて表示する
+pub(super) struct Paren<'a>(pub &'a Expr);
+
+impl Display for Paren<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Expr::Binary(..) => write!(f, "({})", self.0),
+            expr => expr.fmt(f),
+        }
+    }
+}
+
+impl Display for UnaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UnaryOp::Neg => write!(f, "-"),
+            UnaryOp::Not => write!(f, "~"),
+            UnaryOp::LogNot => write!(f, "!"),
+        }
+    }
+}
+
+impl Display for BinaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Rem => "%",
+            BinaryOp::Shl => "<<",
+            BinaryOp::Shr => ">>",
+            BinaryOp::And => "&",
+            BinaryOp::Or => "|",
+            BinaryOp::Xor => "^",
+            BinaryOp::Eq => "==",
+            BinaryOp::Ne => "!=",
+            BinaryOp::Lt => "<",
+            BinaryOp::Le => "<=",
+            BinaryOp::Gt => ">",
+            BinaryOp::Ge => ">=",
+            BinaryOp::LogAnd => "&&",
+            BinaryOp::LogOr => "||",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// 式をパースする
+pub fn parse_expr(tokens: &mut Tokens) -> ParseResult<Expr> {
+    parse_binary(tokens, 0)
+}
+
+/// 優先順位が `min` 以上の二項演算
+fn parse_binary(tokens: &mut Tokens, min: u8) -> ParseResult<Expr> {
+    let mut lhs = parse_unary(tokens)?;
+    while let Some(op) = tokens.peek().and_then(BinaryOp::from_token) {
+        if op.precedence() < min {
+            break;
+        }
+        tokens.next_token();
+        // 左結合
+        let rhs = parse_binary(tokens, op.precedence() + 1)?;
+        lhs = Expr::binary(op, lhs, rhs);
+    }
+    Ok(lhs)
+}
+
+/// 乗除算以上に強く結合する式.
+/// メモリオペランドの項をパースするのに使う.
+pub fn parse_term(tokens: &mut Tokens) -> ParseResult<Expr> {
+    parse_binary(tokens, BinaryOp::Mul.precedence())
+}
+
+fn parse_unary(tokens: &mut Tokens) -> ParseResult<Expr> {
+    if tokens.eat_punct('-') {
+        Ok(Expr::Unary(UnaryOp::Neg, Box::new(parse_unary(tokens)?)))
+    } else if tokens.eat_punct('~') {
+        Ok(Expr::Unary(UnaryOp::Not, Box::new(parse_unary(tokens)?)))
+    } else if tokens.eat_punct('!') {
+        Ok(Expr::Unary(UnaryOp::LogNot, Box::new(parse_unary(tokens)?)))
+    } else if tokens.eat_punct('+') {
+        parse_unary(tokens)
+    } else {
+        parse_primary(tokens)
+    }
+}
+
+fn parse_primary(tokens: &mut Tokens) -> ParseResult<Expr> {
+    let span = tokens.peek_span();
+    let expr = match tokens.peek() {
+        Some(Token::Int(n)) => Expr::Int(*n),
+        Some(Token::Ident(name)) => Expr::Symbol(name.clone()),
+        Some(Token::Here) => Expr::Here,
+        Some(Token::SectionStart) => Expr::SectionStart,
+        // 'ab' のような文字定数はリトルエンディアンの整数になる
+        Some(Token::Str(s)) => {
+            if s.len() > 8 {
+                return Err(ParseError::new(span, "character constant is too long"));
+            }
+            let mut bytes = [0; 8];
+            bytes[..s.len()].copy_from_slice(s);
+            Expr::Int(i64::from_le_bytes(bytes))
+        }
+        Some(Token::Punct('(')) => {
+            tokens.next_token();
+            let expr = parse_expr(tokens)?;
+            tokens.expect_punct(')')?;
+            return Ok(expr);
+        }
+        _ => return Err(tokens.unexpected("expression")),
+    };
+    tokens.next_token();
+    Ok(expr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::token::tokenize, *};
+
+    fn parse(s: &str) -> Expr {
+        let mut tokens = Tokens::new(tokenize(s, 0).unwrap(), s.len());
+        let expr = parse_expr(&mut tokens).unwrap();
+        tokens.expect_end().unwrap();
+        expr
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(parse("1 + 2 * 3 - 4").to_string(), "(1 + (2 * 3)) - 4");
+        assert_eq!(parse("1 | 2 ^ 3 & 4 << 5").to_string(), "1 | (2 ^ (3 & (4 << 5)))");
+        assert_eq!(parse("-(1 + 2) * ~x").to_string(), "-(1 + 2) * ~x");
+        assert_eq!(parse("$ - $$").to_string(), "$ - $$");
+        assert_eq!(
+            parse("!a || b + 1 == 2 && c").to_string(),
+            "!a || (((b + 1) == 2) && c)"
+        );
+    }
+
+    #[test]
+    fn char_constant() {
+        assert_eq!(parse("'ab'"), Expr::Int(0x6261));
+    }
+}
@@ -0,0 +1,345 @@
+use super::{
+    expr::{parse_expr, parse_term, BinaryOp, Expr, Paren, UnaryOp},
+    token::{tokenize, ParseError, ParseResult, Span, Token, Tokens},
+};
+use atom_x86_64::{
+    addr::{Scale, Size},
+    reg::{Reg, Reg64},
+};
+use std::fmt::{self, Display};
+
+/// `mov rax, [rbp-8]` などの1命令
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Instruction {
+    /// 小文字に正規化した命令名
+    pub mnemonic: String,
+    pub operands: Vec<Operand>,
+    /// 行内での位置
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Operand {
+    Reg(Reg),
+    /// 即値. `call func` などの飛び先もこれになる.
+    Imm(Expr),
+    Mem(MemOperand),
+}
+
+/// displacementが式のままのメモリオペランド.
+/// 式を評価してから `atom_x86_64::addr::Mem` に変換する.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemOperand {
+    pub size: Option<Size>,
+    /// `rel` が指定されているか
+    pub rel: bool,
+    pub base: Option<Reg64>,
+    pub index: Option<(Reg64, Scale)>,
+    pub disp: Option<Expr>,
+}
+
+impl Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.mnemonic)?;
+        for (i, operand) in self.operands.iter().enumerate() {
+            let sep = if i == 0 { " " } else { ", " };
+            write!(f, "{}{}", sep, operand)?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::Reg(reg) => reg.fmt(f),
+            Operand::Imm(imm) => imm.fmt(f),
+            Operand::Mem(mem) => mem.fmt(f),
+        }
+    }
+}
+
+impl Display for MemOperand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(size) = self.size {
+            write!(f, "{} ", size)?;
+        }
+        write!(f, "[")?;
+        if self.rel {
+            write!(f, "rel ")?;
+        }
+
+        let mut has_reg = false;
+        if let Some(base) = self.base {
+            write!(f, "{}", base)?;
+            has_reg = true;
+        }
+        if let Some((index, scale)) = self.index {
+            let sep = if has_reg { "+" } else { "" };
+            write!(f, "{}{}*{}", sep, index, scale.to_u8())?;
+            has_reg = true;
+        }
+        match &self.disp {
+            None => {}
+            Some(Expr::Unary(UnaryOp::Neg, disp)) if has_reg => write!(f, "-{}", Paren(disp))?,
+            Some(disp) if has_reg => write!(f, "+{}", Paren(disp))?,
+            Some(disp) => write!(f, "{}", disp)?,
+        }
+
+        write!(f, "]")
+    }
+}
+
+/// 命令をパースする.
+/// `offset` は `s` の行内での開始位置.
+pub fn parse_instruction(s: &str, offset: usize) -> ParseResult<Instruction> {
+    let mut tokens = Tokens::new(tokenize(s, offset)?, offset + s.len());
+
+    let start = tokens.peek_span();
+    let mnemonic = match tokens.peek() {
+        Some(Token::Ident(s)) => s.to_ascii_lowercase(),
+        _ => return Err(tokens.unexpected("instruction")),
+    };
+    tokens.next_token();
+
+    let mut operands = Vec::new();
+    if !tokens.is_end() {
+        operands.push(parse_operand(&mut tokens)?);
+        while tokens.eat_punct(',') {
+            operands.push(parse_operand(&mut tokens)?);
+        }
+    }
+    tokens.expect_end()?;
+
+    Ok(Instruction {
+        mnemonic,
+        operands,
+        span: start.to(tokens.prev_span()),
+    })
+}
+
+fn parse_operand(tokens: &mut Tokens) -> ParseResult<Operand> {
+    // サイズ指定付きのメモリオペランド
+    if let Some(size) = parse_size(tokens) {
+        tokens.eat_keyword("ptr");
+        let mut mem = parse_mem(tokens)?;
+        mem.size = Some(size);
+        return Ok(Operand::Mem(mem));
+    }
+
+    match tokens.peek() {
+        Some(Token::Punct('[')) => parse_mem(tokens).map(Operand::Mem),
+        Some(Token::Ident(s)) if s.parse::<Reg>().is_ok() => {
+            let reg = s.parse().unwrap();
+            tokens.next_token();
+            Ok(Operand::Reg(reg))
+        }
+        _ => parse_expr(tokens).map(Operand::Imm),
+    }
+}
+
+fn parse_size(tokens: &mut Tokens) -> Option<Size> {
+    let size = match tokens.peek() {
+        Some(Token::Ident(s)) => match s.to_ascii_lowercase().as_str() {
+            "byte" => Size::Byte,
+            "word" => Size::Word,
+            "dword" => Size::Dword,
+            "qword" => Size::Qword,
+            _ => return None,
+        },
+        _ => return None,
+    };
+    tokens.next_token();
+    Some(size)
+}
+
+/// `[base + index * scale + disp]`, `[rel symbol + disp]` 形式のメモリオペランド
+fn parse_mem(tokens: &mut Tokens) -> ParseResult<MemOperand> {
+    let start = tokens.peek_span();
+    tokens.expect_punct('[')?;
+
+    let mut mem = MemOperand {
+        size: None,
+        rel: tokens.eat_keyword("rel"),
+        base: None,
+        index: None,
+        disp: None,
+    };
+
+    let mut neg = tokens.eat_punct('-');
+    loop {
+        match (tokens.peek(), tokens.peek_nth(1)) {
+            // `8 * rcx` の形式
+            (Some(Token::Int(n)), Some(Token::Punct('*'))) if is_reg64(tokens.peek_nth(2)) => {
+                let scale = *n;
+                let span = tokens.peek_span();
+                tokens.next_token();
+                tokens.next_token();
+                let reg = expect_reg64(tokens)?;
+                set_index(&mut mem, reg, scale, neg, span.to(tokens.prev_span()))?;
+            }
+            (Some(Token::Ident(_)), _) if is_reg64(tokens.peek()) => {
+                let span = tokens.peek_span();
+                let reg = expect_reg64(tokens)?;
+                if tokens.eat_punct('*') {
+                    let scale = tokens.expect_int()?;
+                    set_index(&mut mem, reg, scale, neg, span.to(tokens.prev_span()))?;
+                } else if mem.base.is_none() && !neg {
+                    mem.base = Some(reg);
+                } else {
+                    set_index(&mut mem, reg, 1, neg, span)?;
+                }
+            }
+            // レジスタ以外の項はdisplacementにまとめる
+            _ => {
+                let term = parse_term(tokens)?;
+                mem.disp = Some(match mem.disp.take() {
+                    None if neg => Expr::Unary(UnaryOp::Neg, Box::new(term)),
+                    None => term,
+                    Some(disp) if neg => Expr::binary(BinaryOp::Sub, disp, term),
+                    Some(disp) => Expr::binary(BinaryOp::Add, disp, term),
+                });
+            }
+        }
+
+        if tokens.eat_punct(']') {
+            break;
+        }
+        neg = match tokens.peek() {
+            Some(Token::Punct('+')) => false,
+            Some(Token::Punct('-')) => true,
+            _ => return Err(tokens.unexpected("`]`")),
+        };
+        tokens.next_token();
+    }
+
+    if mem.rel && (mem.base.is_some() || mem.index.is_some()) {
+        return Err(ParseError::new(
+            start.to(tokens.prev_span()),
+            format!("RIP relative addressing can not have registers: {}", mem),
+        ));
+    }
+
+    Ok(mem)
+}
+
+fn is_reg64(token: Option<&Token>) -> bool {
+    match token {
+        Some(Token::Ident(s)) => s.parse::<Reg64>().is_ok(),
+        _ => false,
+    }
+}
+
+fn expect_reg64(tokens: &mut Tokens) -> ParseResult<Reg64> {
+    let reg = match tokens.peek() {
+        Some(Token::Ident(s)) => s.parse().ok(),
+        _ => None,
+    };
+    let reg = reg.ok_or_else(|| tokens.unexpected("64bit register"))?;
+    tokens.next_token();
+    Ok(reg)
+}
+
+/// `span` はインデックスレジスタと倍率の位置
+fn set_index(
+    mem: &mut MemOperand,
+    reg: Reg64,
+    scale: i64,
+    neg: bool,
+    span: Span,
+) -> ParseResult<()> {
+    if neg {
+        return Err(ParseError::new(span, "register can not be subtracted"));
+    }
+    if mem.index.is_some() {
+        return Err(ParseError::new(
+            span,
+            "memory operand can have only one index register",
+        ));
+    }
+    let scale = Scale::from_u8(scale as u8)
+        .filter(|s| s.to_u8() as i64 == scale)
+        .ok_or_else(|| ParseError::new(span, format!("invalid scale {}", scale)))?;
+    mem.index = Some((reg, scale));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use atom_x86_64::reg::Reg8;
+
+    #[test]
+    fn parse_register_operands() {
+        let inst = parse_instruction("MOV rax, RBX", 2).unwrap();
+        assert_eq!(inst.mnemonic, "mov");
+        assert_eq!(
+            inst.operands,
+            vec![
+                Operand::Reg(Reg::Reg64(Reg64::RAX)),
+                Operand::Reg(Reg::Reg64(Reg64::RBX))
+            ]
+        );
+        assert_eq!(inst.span, Span::new(2, 14));
+    }
+
+    #[test]
+    fn parse_immediate_and_symbol() {
+        let inst = parse_instruction("push -42", 0).unwrap();
+        assert_eq!(
+            inst.operands,
+            vec![Operand::Imm(Expr::Unary(UnaryOp::Neg, Box::new(Expr::Int(42))))]
+        );
+
+        let inst = parse_instruction("call _printf", 0).unwrap();
+        assert_eq!(inst.operands, vec![Operand::Imm(Expr::Symbol("_printf".to_string()))]);
+
+        let inst = parse_instruction("mov rdx, len * 2", 0).unwrap();
+        assert_eq!(inst.operands[1].to_string(), "len * 2");
+
+        let inst = parse_instruction("sete al", 0).unwrap();
+        assert_eq!(inst.operands, vec![Operand::Reg(Reg::Reg8(Reg8::AL))]);
+    }
+
+    #[test]
+    fn parse_memory_operand() {
+        let inst = parse_instruction("mov qword ptr [rbp - 8], rax", 0).unwrap();
+        assert_eq!(
+            inst.operands[0],
+            Operand::Mem(MemOperand {
+                size: Some(Size::Qword),
+                rel: false,
+                base: Some(Reg64::RBP),
+                index: None,
+                disp: Some(Expr::Unary(UnaryOp::Neg, Box::new(Expr::Int(8)))),
+            })
+        );
+
+        let inst = parse_instruction("lea rax, [rbx + 8*rcx + 0x10]", 0).unwrap();
+        assert_eq!(
+            inst.operands[1],
+            Operand::Mem(MemOperand {
+                size: None,
+                rel: false,
+                base: Some(Reg64::RBX),
+                index: Some((Reg64::RCX, Scale::S8)),
+                disp: Some(Expr::Int(16)),
+            })
+        );
+
+        let inst = parse_instruction("mov rax, [rbp - 8]", 0).unwrap();
+        assert_eq!(inst.operands[1].to_string(), "[rbp-8]");
+
+        let inst = parse_instruction("lea rsi, [rel msg + 4 - OFFSET*2]", 0).unwrap();
+        assert_eq!(inst.operands[1].to_string(), "[rel (msg + 4) - (OFFSET * 2)]");
+
+        let inst = parse_instruction("mov rax, [r8*4 + rdi + (1 << 3)]", 0).unwrap();
+        assert_eq!(inst.operands[1].to_string(), "[rdi+r8*4+(1 << 3)]");
+    }
+
+    #[test]
+    fn invalid_scale() {
+        let err = parse_instruction("lea rax, [rbx + rcx*3]", 0).unwrap_err();
+        assert_eq!(err, ParseError::new(Span::new(16, 21), "invalid scale 3"));
+    }
+}
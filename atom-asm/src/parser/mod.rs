@@ -0,0 +1,620 @@
+mod build_version;
+mod data;
+mod expr;
+mod instruction;
+mod section;
+mod token;
+
+pub use self::{
+    build_version::{parse_version, platform_from_name},
+    data::{Data, DataItem, Incbin},
+    expr::{BinaryOp, Expr, UnaryOp},
+    instruction::{parse_instruction, Instruction, MemOperand, Operand},
+    section::{SectionDecl, ZerofillSymbol},
+    token::{is_ident_char, is_ident_start, ParseResult, Span},
+};
+use self::{
+    build_version::parse_build_version,
+    data::{data_size, parse_asciz, parse_data_items, parse_incbin, reserve_size},
+    expr::parse_expr,
+    section::{parse_section, parse_zerofill, parse_zerofill_symbol, section_alias},
+    token::{describe, tokenize, Token, Tokens},
+};
+use crate::{
+    diagnostic::{Diagnostic, Diagnostics},
+    object::BuildVersion,
+    preprocessor::SourceLine,
+};
+use atom_x86_64::addr::Size;
+
+/// プリプロセス済みの行を `Line` に変換する.
+/// パースエラーは `diagnostics` に追加して、その行を読み飛ばす.
+pub struct LineStream<I> {
+    lines: I,
+    diagnostics: Diagnostics,
+}
+
+impl<I: Iterator<Item = SourceLine>> LineStream<I> {
+    pub fn new(lines: I, diagnostics: Diagnostics) -> Self {
+        LineStream { lines, diagnostics }
+    }
+}
+
+impl<I: Iterator<Item = SourceLine>> Iterator for LineStream<I> {
+    /// 元の行と、そこから得られた `Line`.
+    /// 空行やコメント行からは何も得られない.
+    type Item = (SourceLine, Vec<Line>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let line = self.lines.next()?;
+        let lines = parse_line(&line.text).unwrap_or_else(|err| {
+            self.diagnostics
+                .push(Diagnostic::error_at(&line, err.message).with_span(err.span));
+            Vec::new()
+        });
+        Some((line, lines))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Line {
+    SectionDeclare(SectionDecl),
+    GlobalSymbol(String),
+    /// `extern name`. 他のファイルで定義されるシンボル.
+    ExternSymbol(String),
+    SymbolDef(String),
+    Content(Instruction),
+    /// `db`, `dw`, `dd`, `dq`, `.asciz` によるデータ定義
+    Data(Data),
+    /// `resb` などによる領域の確保. 要素の大きさと個数.
+    Reserve(Size, Expr),
+    /// `name equ expr`
+    Equ(String, Expr),
+    /// `times N` による繰り返し
+    Times(Expr, Box<Line>),
+    Incbin(Incbin),
+    /// `align N` によるアラインメント
+    Align(Expr),
+    /// `.build_version` で指定したプラットフォームとバージョン
+    BuildVersion(BuildVersion),
+    /// `.subsections_via_symbols`
+    SubsectionsViaSymbols,
+    /// `.private_extern name` などによるシンボルの属性の指定
+    SymbolDirective(SymbolDirective, String),
+    /// `.comm name, size, align`. リンク時に領域を確保する外部シンボル.
+    Common(ZerofillSymbol),
+    /// `.lcomm name, size, align`. `__DATA,__bss` に確保するローカルなシンボル.
+    LocalCommon(ZerofillSymbol),
+    /// `.zerofill segname, sectname, name, size, align`
+    Zerofill(SectionDecl, Option<ZerofillSymbol>),
+    /// `.tbss name, size, align`. 初期値が0のスレッドローカル変数と、その TLV ディスクリプタ.
+    ThreadLocalZerofill(ZerofillSymbol),
+}
+
+/// シンボルに属性を付けるディレクティブ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolDirective {
+    /// `.private_extern`. 他のファイルから見えるが、リンク後はローカルになる.
+    PrivateExtern,
+    /// `.weak_definition`. 他に定義があればそちらを使う.
+    WeakDefinition,
+    /// `.weak_reference`. 実行時に見つからなければ0になる.
+    WeakReference,
+    /// `.no_dead_strip`. 参照されなくても `-dead_strip` で取り除かない.
+    NoDeadStrip,
+    /// `.alt_entry`. 直前のシンボルと同じまとまりに属する.
+    AltEntry,
+    /// `.reference`. 使っていなくても未定義シンボルとして残す.
+    Reference,
+}
+
+fn symbol_directive(s: &str) -> Option<SymbolDirective> {
+    let directive = match s {
+        ".private_extern" => SymbolDirective::PrivateExtern,
+        ".weak_definition" => SymbolDirective::WeakDefinition,
+        ".weak_reference" => SymbolDirective::WeakReference,
+        ".no_dead_strip" => SymbolDirective::NoDeadStrip,
+        ".alt_entry" => SymbolDirective::AltEntry,
+        ".reference" => SymbolDirective::Reference,
+        _ => return None,
+    };
+    Some(directive)
+}
+
+/// 1行全体を式としてパースする
+pub fn parse_expression(s: &str) -> ParseResult<Expr> {
+    let mut tokens = Tokens::new(tokenize(s, 0)?, s.len());
+    let expr = parse_expr(&mut tokens)?;
+    tokens.expect_end()?;
+    Ok(expr)
+}
+
+fn parse_line(s: &str) -> ParseResult<Vec<Line>> {
+    // コメントを取り除く
+    let s = strip_comment(s);
+    let tokens = tokenize(s, 0)?;
+    let mut tokens = Tokens::new(tokens, s.len());
+
+    let token1 = match tokens.peek() {
+        Some(Token::Ident(token)) => token.clone(),
+        Some(token) => return Err(tokens.error(format!("unexpected token `{}`", token))),
+        None => return Ok(Vec::new()),
+    };
+
+    // 引数のある `.tbss` はスレッドローカル変数の確保で、なければセクションの宣言
+    if token1 == ".tbss" && tokens.peek_nth(2) == Some(&Token::Punct(',')) {
+        tokens.next_token();
+        let symbol = parse_zerofill_symbol(&mut tokens)?;
+        return Ok(vec![Line::ThreadLocalZerofill(symbol)]);
+    }
+
+    // セクションの宣言. `.cstring` のように `section` を省略してもよい.
+    if token1 == "section" || section_alias(&token1).is_some() {
+        if token1 == "section" {
+            tokens.next_token();
+        }
+        let decl = parse_section(&mut tokens)?;
+        return Ok(vec![Line::SectionDeclare(decl)]);
+    }
+
+    if token1 == ".build_version" {
+        tokens.next_token();
+        return parse_build_version(&mut tokens).map(|version| vec![Line::BuildVersion(version)]);
+    }
+
+    // 0で埋める領域の確保
+    if token1 == ".comm" || token1 == ".lcomm" {
+        tokens.next_token();
+        let symbol = parse_zerofill_symbol(&mut tokens)?;
+        if token1 == ".comm" {
+            return Ok(vec![Line::Common(symbol)]);
+        } else {
+            return Ok(vec![Line::LocalCommon(symbol)]);
+        }
+    }
+    if token1 == ".zerofill" {
+        tokens.next_token();
+        let (decl, symbol) = parse_zerofill(&mut tokens)?;
+        return Ok(vec![Line::Zerofill(decl, symbol)]);
+    }
+
+    if token1 == ".subsections_via_symbols" {
+        tokens.next_token();
+        tokens.expect_end()?;
+        return Ok(vec![Line::SubsectionsViaSymbols]);
+    }
+
+    // グローバルシンボル定義と外部シンボルの宣言、シンボルの属性
+    let directive = symbol_directive(&token1);
+    if token1 == "global" || token1 == "extern" || directive.is_some() {
+        tokens.next_token();
+        let symbol_name = match tokens.peek() {
+            Some(Token::Ident(sym)) => sym.clone(),
+            _ => return Err(tokens.unexpected("symbol name")),
+        };
+        tokens.next_token();
+        tokens.expect_end()?;
+        return match directive {
+            Some(directive) => Ok(vec![Line::SymbolDirective(directive, symbol_name)]),
+            None if token1 == "global" => Ok(vec![Line::GlobalSymbol(symbol_name)]),
+            None => Ok(vec![Line::ExternSymbol(symbol_name)]),
+        };
+    }
+
+    // 定数定義
+    if let Some(Token::Ident(s)) = tokens.peek_nth(1) {
+        if s.eq_ignore_ascii_case("equ") {
+            tokens.next_token();
+            tokens.next_token();
+            let expr = parse_expr(&mut tokens)?;
+            tokens.expect_end()?;
+            return Ok(vec![Line::Equ(token1, expr)]);
+        }
+    }
+
+    let mut lines = Vec::new();
+
+    // シンボル定義.
+    // `msg db 'hello'` のようにデータ定義の前では `:` を省略できる.
+    tokens.next_token();
+    if tokens.eat_punct(':') || is_data_directive(tokens.peek()) {
+        lines.push(Line::SymbolDef(token1));
+    } else {
+        tokens = Tokens::new(tokenize(s, 0)?, s.len());
+    }
+
+    if !tokens.is_end() {
+        lines.push(parse_content(s, tokens)?);
+    }
+    Ok(lines)
+}
+
+/// 文字列の外にある `;` 以降を取り除く
+pub fn strip_comment(s: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in s.char_indices() {
+        match quote {
+            _ if escaped => escaped = false,
+            Some('\'') => {
+                if c == '\'' {
+                    quote = None;
+                }
+            }
+            Some(q) => match c {
+                '\\' => escaped = true,
+                _ if c == q => quote = None,
+                _ => {}
+            },
+            None => match c {
+                ';' => return &s[..i],
+                '\'' | '"' | '`' => quote = Some(c),
+                _ => {}
+            },
+        }
+    }
+    s
+}
+
+fn is_data_directive(token: Option<&Token>) -> bool {
+    match token {
+        Some(Token::Ident(s)) => {
+            s.eq_ignore_ascii_case("times")
+                || s.eq_ignore_ascii_case("incbin")
+                || s == ".asciz"
+                || data_size(s).is_some()
+                || reserve_size(s).is_some()
+        }
+        _ => false,
+    }
+}
+
+/// 命令 or データ定義
+fn parse_content(s: &str, mut tokens: Tokens) -> ParseResult<Line> {
+    if tokens.eat_keyword("times") {
+        let times = parse_expr(&mut tokens)?;
+        let line = parse_content(s, tokens)?;
+        return Ok(Line::Times(times, Box::new(line)));
+    }
+
+    let start = tokens.peek_span().start;
+    let directive = match tokens.peek() {
+        Some(Token::Ident(s)) => s.clone(),
+        token => {
+            let message = format!("instruction is expected but got {}", describe(token));
+            return Err(tokens.error(message));
+        }
+    };
+
+    if let Some(size) = data_size(&directive) {
+        tokens.next_token();
+        let items = parse_data_items(&mut tokens)?;
+        return Ok(Line::Data(Data { size, items }));
+    }
+
+    if directive == ".asciz" {
+        tokens.next_token();
+        return parse_asciz(&mut tokens).map(Line::Data);
+    }
+
+    if let Some(size) = reserve_size(&directive) {
+        tokens.next_token();
+        let count = parse_expr(&mut tokens)?;
+        tokens.expect_end()?;
+        return Ok(Line::Reserve(size, count));
+    }
+
+    if directive.eq_ignore_ascii_case("align") {
+        tokens.next_token();
+        let align = parse_expr(&mut tokens)?;
+        tokens.expect_end()?;
+        return Ok(Line::Align(align));
+    }
+
+    if directive.eq_ignore_ascii_case("incbin") {
+        tokens.next_token();
+        return parse_incbin(&mut tokens).map(Line::Incbin);
+    }
+
+    parse_instruction(&s[start..], start).map(Line::Content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{token::ParseError, *};
+    use atom_macho::load_command::build_version::{Platform, Version};
+
+    #[test]
+    fn parse_label_and_data() {
+        assert_eq!(
+            parse_line("msg db 'hello; world', 0x0A ; comment\n").unwrap(),
+            vec![
+                Line::SymbolDef("msg".to_string()),
+                Line::Data(Data {
+                    size: Size::Byte,
+                    items: vec![
+                        DataItem::Str(b"hello; world".to_vec()),
+                        DataItem::Expr(Expr::Int(0x0A))
+                    ],
+                }),
+            ]
+        );
+        assert_eq!(
+            parse_line("buf: times 4 dw 1\n").unwrap(),
+            vec![
+                Line::SymbolDef("buf".to_string()),
+                Line::Times(
+                    Expr::Int(4),
+                    Box::new(Line::Data(Data {
+                        size: Size::Word,
+                        items: vec![DataItem::Expr(Expr::Int(1))],
+                    }))
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_reserve() {
+        assert_eq!(
+            parse_line("hoge resb 42\n").unwrap(),
+            vec![
+                Line::SymbolDef("hoge".to_string()),
+                Line::Reserve(Size::Byte, Expr::Int(42))
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_incbin() {
+        assert_eq!(
+            parse_line("blob incbin \"blob.bin\", 4\n").unwrap(),
+            vec![
+                Line::SymbolDef("blob".to_string()),
+                Line::Incbin(Incbin {
+                    path: "blob.bin".into(),
+                    offset: Some(Expr::Int(4)),
+                    len: None,
+                })
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_align() {
+        assert_eq!(parse_line("  align 16\n").unwrap(), vec![Line::Align(Expr::Int(16))]);
+        assert_eq!(
+            parse_line("table: align 1 << 3\n").unwrap(),
+            vec![
+                Line::SymbolDef("table".to_string()),
+                Line::Align(Expr::binary(BinaryOp::Shl, Expr::Int(1), Expr::Int(3)))
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_equ() {
+        assert_eq!(
+            parse_line("len equ $ - msg\n").unwrap(),
+            vec![Line::Equ(
+                "len".to_string(),
+                Expr::binary(BinaryOp::Sub, Expr::Here, Expr::Symbol("msg".to_string()))
+            )]
+        );
+    }
+
+    #[test]
+    fn parse_label_before_instruction() {
+        let lines = parse_line("start: ret\n").unwrap();
+        assert_eq!(lines[0], Line::SymbolDef("start".to_string()));
+        assert!(matches!(&lines[1], Line::Content(inst) if inst.mnemonic == "ret"));
+
+        assert_eq!(parse_line("  ; comment only\n").unwrap(), vec![]);
+    }
+
+    #[test]
+    fn parse_build_version() {
+        let version = |major, minor, release| Version {
+            major,
+            minor,
+            release,
+        };
+        assert_eq!(
+            parse_line(".build_version macos, 11, 0 sdk_version 12, 3, 1\n").unwrap(),
+            vec![Line::BuildVersion(BuildVersion {
+                platform: Platform::MacOS,
+                minos: version(11, 0, 0),
+                sdk: version(12, 3, 1),
+            })]
+        );
+        assert_eq!(
+            parse_line(".build_version ios, 14, 2\n").unwrap(),
+            vec![Line::BuildVersion(BuildVersion {
+                platform: Platform::IOS,
+                minos: version(14, 2, 0),
+                sdk: version(0, 0, 0),
+            })]
+        );
+        assert_eq!(
+            parse_line(".build_version linux, 5, 0\n"),
+            Err(ParseError::new(Span::new(15, 20), "unknown platform linux"))
+        );
+        assert_eq!(
+            parse_line(".build_version macos, 11, 256\n"),
+            Err(ParseError::new(
+                Span::new(26, 29),
+                "version number 256 is out of range"
+            ))
+        );
+
+        assert_eq!(parse_version("10.15.4"), Some(version(10, 15, 4)));
+        assert_eq!(parse_version("11.0"), Some(version(11, 0, 0)));
+        assert_eq!(parse_version("11"), None);
+    }
+
+    #[test]
+    fn parse_subsections_via_symbols() {
+        assert_eq!(
+            parse_line(".subsections_via_symbols\n").unwrap(),
+            vec![Line::SubsectionsViaSymbols]
+        );
+        assert_eq!(
+            parse_line(".subsections_via_symbols 1\n"),
+            Err(ParseError::new(Span::new(25, 26), "unexpected token `1`"))
+        );
+    }
+
+    #[test]
+    fn parse_symbol_directives() {
+        assert_eq!(
+            parse_line(".private_extern _helper\n").unwrap(),
+            vec![Line::SymbolDirective(
+                SymbolDirective::PrivateExtern,
+                "_helper".to_string()
+            )]
+        );
+        assert_eq!(
+            parse_line(".weak_reference _optional ; comment\n").unwrap(),
+            vec![Line::SymbolDirective(
+                SymbolDirective::WeakReference,
+                "_optional".to_string()
+            )]
+        );
+        assert_eq!(
+            parse_line(".alt_entry\n"),
+            Err(ParseError::new(
+                Span::new(11, 11),
+                "symbol name is expected but got end of line"
+            ))
+        );
+    }
+
+    #[test]
+    fn parse_zerofill() {
+        let symbol = |name: &str, size, align: Option<i64>| ZerofillSymbol {
+            name: name.to_string(),
+            size: Expr::Int(size),
+            align: align.map(Expr::Int),
+        };
+        assert_eq!(
+            parse_line(".comm _buffer, 4096, 4\n").unwrap(),
+            vec![Line::Common(symbol("_buffer", 4096, Some(4)))]
+        );
+        assert_eq!(
+            parse_line(".lcomm counter, 8\n").unwrap(),
+            vec![Line::LocalCommon(symbol("counter", 8, None))]
+        );
+        assert_eq!(
+            parse_line(".zerofill __DATA,__bss_big,table,1024,12\n").unwrap(),
+            vec![Line::Zerofill(
+                SectionDecl::new("__DATA", "__bss_big"),
+                Some(symbol("table", 1024, Some(12)))
+            )]
+        );
+        assert_eq!(
+            parse_line(".zerofill __DATA,__bss_big\n").unwrap(),
+            vec![Line::Zerofill(SectionDecl::new("__DATA", "__bss_big"), None)]
+        );
+        // 引数がなければセクションの宣言
+        assert_eq!(
+            parse_line(".tbss _state, 16, 3\n").unwrap(),
+            vec![Line::ThreadLocalZerofill(symbol("_state", 16, Some(3)))]
+        );
+        assert_eq!(
+            parse_line(".tbss\n").unwrap(),
+            vec![Line::SectionDeclare(SectionDecl::new(
+                "__DATA",
+                "__thread_bss"
+            ))]
+        );
+        assert_eq!(
+            parse_line(".comm _buffer\n"),
+            Err(ParseError::new(
+                Span::new(14, 14),
+                "`,` is expected but got end of line"
+            ))
+        );
+    }
+
+    #[test]
+    fn parse_asciz() {
+        assert_eq!(
+            parse_line("msg .asciz \"hi\", 'yo'\n").unwrap(),
+            vec![
+                Line::SymbolDef("msg".to_string()),
+                Line::Data(Data {
+                    size: Size::Byte,
+                    items: vec![
+                        DataItem::Str(b"hi".to_vec()),
+                        DataItem::Expr(Expr::Int(0)),
+                        DataItem::Str(b"yo".to_vec()),
+                        DataItem::Expr(Expr::Int(0)),
+                    ],
+                }),
+            ]
+        );
+        assert_eq!(
+            parse_line(".asciz \"a\",\n"),
+            Err(ParseError::new(
+                Span::new(12, 12),
+                "string is expected but got end of line"
+            ))
+        );
+    }
+
+    #[test]
+    fn parse_section() {
+        assert_eq!(
+            parse_line("section .bss\n").unwrap(),
+            vec![Line::SectionDeclare(SectionDecl::new("__DATA", "__bss"))]
+        );
+        assert_eq!(
+            parse_line("section .tlv\n").unwrap(),
+            vec![Line::SectionDeclare(SectionDecl::new("__DATA", "__thread_vars"))]
+        );
+        assert_eq!(
+            parse_line(".cstring\n").unwrap(),
+            vec![Line::SectionDeclare(SectionDecl::new("__TEXT", "__cstring"))]
+        );
+        assert_eq!(
+            parse_line("section __DATA,__mystuff align=16 ; comment\n").unwrap(),
+            vec![Line::SectionDeclare(SectionDecl {
+                segname: "__DATA".to_string(),
+                sectname: "__mystuff".to_string(),
+                align: Some(16),
+            })]
+        );
+        assert_eq!(
+            parse_line("section __TEXT,__const align=3\n"),
+            Err(ParseError::new(Span::new(23, 30), "alignment 3 is not a power of 2"))
+        );
+    }
+
+    #[test]
+    fn parse_global_and_extern() {
+        assert_eq!(
+            parse_line("global main\n").unwrap(),
+            vec![Line::GlobalSymbol("main".to_string())]
+        );
+        assert_eq!(
+            parse_line("extern _printf ; libc\n").unwrap(),
+            vec![Line::ExternSymbol("_printf".to_string())]
+        );
+    }
+
+    #[test]
+    fn parse_error() {
+        assert_eq!(
+            parse_line("section .rodata\n"),
+            Err(ParseError::new(Span::new(8, 15), "unrecognized section .rodata"))
+        );
+        assert_eq!(
+            parse_line("global"),
+            Err(ParseError::new(Span::new(6, 6), "symbol name is expected but got end of line"))
+        );
+        assert_eq!(
+            parse_line("ret 1 2\n"),
+            Err(ParseError::new(Span::new(6, 7), "unexpected token `2`"))
+        );
+    }
+}
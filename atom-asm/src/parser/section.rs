@@ -0,0 +1,147 @@
+use super::{
+    expr::{parse_expr, Expr},
+    token::{ParseError, ParseResult, Token, Tokens},
+};
+
+/// Mach-O のセグメント名・セクション名の最大の長さ
+const MAX_NAME_LEN: usize = 16;
+
+/// `section __DATA,__mystuff align=16` によるセクションの宣言
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SectionDecl {
+    pub segname: String,
+    pub sectname: String,
+    /// `align=N` で指定したアラインメントのバイト数
+    pub align: Option<u64>,
+}
+
+impl SectionDecl {
+    pub fn new(segname: &str, sectname: &str) -> SectionDecl {
+        SectionDecl {
+            segname: segname.to_string(),
+            sectname: sectname.to_string(),
+            align: None,
+        }
+    }
+}
+
+/// `.text` などの省略した名前で表したセクション.
+/// `.text`, `.data`, `.bss` はそれぞれ `__TEXT,__text`, `__DATA,__data`, `__DATA,__bss` になる.
+/// スレッドローカル変数の `.tdata`, `.tbss`, `.tlv` は初期値と TLV ディスクリプタのセクションになる.
+/// 引数のある `.tbss name, size, align` は変数の確保で、セクションの宣言ではない.
+/// `.cstring`, `.literal4` などは同じ値がまとめられるリテラルのセクションになる.
+pub fn section_alias(name: &str) -> Option<SectionDecl> {
+    let (segname, sectname) = match name {
+        ".text" => ("__TEXT", "__text"),
+        ".data" => ("__DATA", "__data"),
+        ".bss" => ("__DATA", "__bss"),
+        ".tdata" => ("__DATA", "__thread_data"),
+        ".tbss" => ("__DATA", "__thread_bss"),
+        ".tlv" => ("__DATA", "__thread_vars"),
+        ".cstring" => ("__TEXT", "__cstring"),
+        ".literal4" => ("__TEXT", "__literal4"),
+        ".literal8" => ("__TEXT", "__literal8"),
+        ".literal16" => ("__TEXT", "__literal16"),
+        _ => return None,
+    };
+    Some(SectionDecl::new(segname, sectname))
+}
+
+/// `section` 以降をパースする
+pub fn parse_section(tokens: &mut Tokens) -> ParseResult<SectionDecl> {
+    let name = match tokens.peek() {
+        Some(Token::Ident(name)) => name.clone(),
+        _ => return Err(tokens.unexpected("section name")),
+    };
+    let mut decl = match section_alias(&name) {
+        Some(decl) => decl,
+        None if name.starts_with('.') => {
+            return Err(tokens.error(format!("unrecognized section {}", name)))
+        }
+        None => {
+            let segname = expect_name(tokens, "segment")?;
+            tokens.expect_punct(',')?;
+            let sectname = expect_name(tokens, "section")?;
+            SectionDecl::new(&segname, &sectname)
+        }
+    };
+    if name.starts_with('.') {
+        tokens.next_token();
+    }
+
+    // 属性
+    while !tokens.is_end() {
+        let start = tokens.peek_span();
+        if !tokens.eat_keyword("align") {
+            return Err(tokens.unexpected("section attribute"));
+        }
+        if tokens.peek() != Some(&Token::Eq) {
+            return Err(tokens.unexpected("`=`"));
+        }
+        tokens.next_token();
+        let n = tokens.expect_int()?;
+        if n <= 0 || !(n as u64).is_power_of_two() {
+            let span = start.to(tokens.prev_span());
+            return Err(ParseError::new(span, format!("alignment {} is not a power of 2", n)));
+        }
+        decl.align = Some(n as u64);
+    }
+    Ok(decl)
+}
+
+/// `.comm`, `.lcomm`, `.zerofill`, `.tbss` で確保するシンボル
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ZerofillSymbol {
+    pub name: String,
+    /// 確保するバイト数
+    pub size: Expr,
+    /// アラインメント. 他の Mach-O のアセンブラと同じく2の累乗の指数で書く.
+    pub align: Option<Expr>,
+}
+
+/// `.comm`, `.lcomm`, `.tbss` の `name, size[, align]` をパースする
+pub fn parse_zerofill_symbol(tokens: &mut Tokens) -> ParseResult<ZerofillSymbol> {
+    let name = match tokens.peek() {
+        Some(Token::Ident(name)) => name.clone(),
+        _ => return Err(tokens.unexpected("symbol name")),
+    };
+    tokens.next_token();
+    tokens.expect_punct(',')?;
+    let size = parse_expr(tokens)?;
+    let align = if tokens.eat_punct(',') {
+        Some(parse_expr(tokens)?)
+    } else {
+        None
+    };
+    tokens.expect_end()?;
+    Ok(ZerofillSymbol { name, size, align })
+}
+
+/// `.zerofill segname, sectname[, name, size[, align]]` の `.zerofill` 以降をパースする.
+/// シンボルを省略するとセクションだけを宣言する.
+pub fn parse_zerofill(tokens: &mut Tokens) -> ParseResult<(SectionDecl, Option<ZerofillSymbol>)> {
+    let segname = expect_name(tokens, "segment")?;
+    tokens.expect_punct(',')?;
+    let sectname = expect_name(tokens, "section")?;
+    let decl = SectionDecl::new(&segname, &sectname);
+    if !tokens.eat_punct(',') {
+        tokens.expect_end()?;
+        return Ok((decl, None));
+    }
+    let symbol = parse_zerofill_symbol(tokens)?;
+    Ok((decl, Some(symbol)))
+}
+
+/// セグメント名かセクション名を読む
+fn expect_name(tokens: &mut Tokens, kind: &str) -> ParseResult<String> {
+    let name = match tokens.peek() {
+        Some(Token::Ident(name)) => name.clone(),
+        _ => return Err(tokens.unexpected(&format!("{} name", kind))),
+    };
+    if name.len() > MAX_NAME_LEN {
+        let message = format!("{} name {} is longer than {} bytes", kind, name, MAX_NAME_LEN);
+        return Err(tokens.error(message));
+    }
+    tokens.next_token();
+    Ok(name)
+}
@@ -0,0 +1,513 @@
+use std::{
+    fmt::{self, Display},
+    iter::Peekable,
+    str::CharIndices,
+};
+
+/// 行の中の位置 (バイト単位)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Span {
+        Span { start, end }
+    }
+
+    /// `self` から `other` までを含むSpan
+    pub fn to(self, other: Span) -> Span {
+        Span::new(self.start, other.end)
+    }
+}
+
+/// パースエラー
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub span: Span,
+    pub message: String,
+}
+
+impl ParseError {
+    pub fn new(span: Span, message: impl Into<String>) -> ParseError {
+        ParseError {
+            span,
+            message: message.into(),
+        }
+    }
+}
+
+pub type ParseResult<T> = Result<T, ParseError>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Token {
+    /// 命令名, レジスタ名, シンボル名など
+    Ident(String),
+    Int(i64),
+    /// `'...'`, `"..."`, `` `...` `` で囲まれた文字列.
+    /// `'...'` の中ではエスケープシーケンスを解釈しない.
+    Str(Vec<u8>),
+    /// `[`, `]`, `(`, `)`, `+`, `-`, `*`, `/`, `%`, `&`, `|`, `^`, `~`, `!`, `,`, `:`
+    Punct(char),
+    /// `<<`
+    Shl,
+    /// `>>`
+    Shr,
+    /// `==`, `=`
+    Eq,
+    /// `!=`, `<>`
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    /// `&&`
+    LogAnd,
+    /// `||`
+    LogOr,
+    /// `$` (その行の先頭のアドレス)
+    Here,
+    /// `$$` (セクションの先頭のアドレス)
+    SectionStart,
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Ident(s) => write!(f, "{}", s),
+            Token::Int(n) => write!(f, "{}", n),
+            Token::Str(s) => write!(f, "'{}'", String::from_utf8_lossy(s)),
+            Token::Punct(c) => write!(f, "{}", c),
+            Token::Shl => write!(f, "<<"),
+            Token::Shr => write!(f, ">>"),
+            Token::Eq => write!(f, "=="),
+            Token::Ne => write!(f, "!="),
+            Token::Lt => write!(f, "<"),
+            Token::Le => write!(f, "<="),
+            Token::Gt => write!(f, ">"),
+            Token::Ge => write!(f, ">="),
+            Token::LogAnd => write!(f, "&&"),
+            Token::LogOr => write!(f, "||"),
+            Token::Here => write!(f, "$"),
+            Token::SectionStart => write!(f, "$$"),
+        }
+    }
+}
+
+/// エラーメッセージ用のトークンの表示
+pub fn describe(token: Option<&Token>) -> String {
+    match token {
+        Some(token) => format!("`{}`", token),
+        None => "end of line".to_string(),
+    }
+}
+
+/// `s` をトークンに分割する.
+/// `offset` は `s` の行内での開始位置.
+pub fn tokenize(s: &str, offset: usize) -> ParseResult<Vec<(Token, Span)>> {
+    let mut tokens = Vec::new();
+    let mut chars = s.char_indices().peekable();
+
+    while let Some(&(start, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+
+        let mut end = start + c.len_utf8();
+        let token = if is_ident_start(c) {
+            while let Some(&(i, c)) = chars.peek() {
+                if !is_ident_char(c) {
+                    break;
+                }
+                end = i + c.len_utf8();
+                chars.next();
+            }
+            Token::Ident(s[start..end].to_string())
+        } else if c.is_ascii_digit() {
+            while let Some(&(i, c)) = chars.peek() {
+                if !c.is_ascii_alphanumeric() && c != '_' {
+                    break;
+                }
+                end = i + c.len_utf8();
+                chars.next();
+            }
+            let span = Span::new(offset + start, offset + end);
+            Token::Int(parse_int(&s[start..end]).ok_or_else(|| {
+                ParseError::new(span, format!("invalid number {}", &s[start..end]))
+            })?)
+        } else if c == '\'' || c == '"' || c == '`' {
+            chars.next();
+            let mut bytes = Vec::new();
+            loop {
+                match chars.next() {
+                    Some((i, q)) if q == c => {
+                        end = i + 1;
+                        break;
+                    }
+                    Some((i, '\\')) if c != '\'' => {
+                        parse_escape(&mut chars, &mut bytes).map_err(|message| {
+                            ParseError::new(Span::new(offset + i, offset + i + 2), message)
+                        })?
+                    }
+                    Some((_, ch)) => {
+                        let mut buf = [0; 4];
+                        bytes.extend(ch.encode_utf8(&mut buf).as_bytes());
+                    }
+                    None => {
+                        let span = Span::new(offset + start, offset + s.len());
+                        return Err(ParseError::new(span, "unterminated string"));
+                    }
+                }
+            }
+            Token::Str(bytes)
+        } else if c == '$' {
+            chars.next();
+            match chars.peek() {
+                Some(&(i, '$')) => {
+                    end = i + 1;
+                    chars.next();
+                    Token::SectionStart
+                }
+                _ => Token::Here,
+            }
+        } else if let Some(token) = two_char_op(&s[start..]) {
+            chars.next();
+            chars.next();
+            end = start + 2;
+            token
+        } else if c == '<' || c == '>' || c == '=' {
+            chars.next();
+            match c {
+                '<' => Token::Lt,
+                '>' => Token::Gt,
+                _ => Token::Eq,
+            }
+        } else if "[]()+-*/%&|^~!,:".contains(c) {
+            chars.next();
+            Token::Punct(c)
+        } else {
+            let span = Span::new(offset + start, offset + end);
+            return Err(ParseError::new(span, format!("unexpected character '{}'", c)));
+        };
+
+        tokens.push((token, Span::new(offset + start, offset + end)));
+    }
+
+    Ok(tokens)
+}
+
+/// 2文字の演算子
+fn two_char_op(s: &str) -> Option<Token> {
+    let token = match s.get(..2)? {
+        "<<" => Token::Shl,
+        ">>" => Token::Shr,
+        "==" => Token::Eq,
+        "!=" | "<>" => Token::Ne,
+        "<=" => Token::Le,
+        ">=" => Token::Ge,
+        "&&" => Token::LogAnd,
+        "||" => Token::LogOr,
+        _ => return None,
+    };
+    Some(token)
+}
+
+pub fn is_ident_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_' || c == '.' || c == '?'
+}
+
+pub fn is_ident_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "_.$#@~?".contains(c)
+}
+
+/// `42`, `0x2A`, `0o52`, `0b101010` 形式の整数.
+/// `_` は区切りとして無視する.
+fn parse_int(s: &str) -> Option<i64> {
+    let digits = s.replace('_', "");
+    let lower = digits.to_ascii_lowercase();
+    let (radix, digits) = if let Some(hex) = lower.strip_prefix("0x") {
+        (16, hex)
+    } else if let Some(oct) = lower.strip_prefix("0o") {
+        (8, oct)
+    } else if let Some(bin) = lower.strip_prefix("0b") {
+        (2, bin)
+    } else {
+        (10, lower.as_str())
+    };
+
+    // 0xFFFF_FFFF_FFFF_FFFF のような値も受け付ける
+    u64::from_str_radix(digits, radix).ok().map(|n| n as i64)
+}
+
+/// `\` に続くエスケープシーケンスを解釈して `bytes` に追加する
+fn parse_escape(chars: &mut Peekable<CharIndices>, bytes: &mut Vec<u8>) -> Result<(), String> {
+    let c = match chars.next() {
+        Some((_, c)) => c,
+        None => return Err("unterminated string".to_string()),
+    };
+    let byte = match c {
+        'n' => b'\n',
+        't' => b'\t',
+        'r' => b'\r',
+        'a' => 0x07,
+        'b' => 0x08,
+        'f' => 0x0C,
+        'v' => 0x0B,
+        'e' => 0x1B,
+        '\\' | '\'' | '"' | '`' | '?' => c as u8,
+        // \xHH
+        'x' => {
+            let digits = take_digits(chars, 16, 2);
+            if digits.is_empty() {
+                return Err("\\x is used with no following hex digits".to_string());
+            }
+            u8::from_str_radix(&digits, 16).unwrap()
+        }
+        // \ooo
+        '0'..='7' => {
+            let mut digits = c.to_string();
+            digits.push_str(&take_digits(chars, 8, 2));
+            u32::from_str_radix(&digits, 8).unwrap() as u8
+        }
+        c => return Err(format!("unknown escape sequence \\{}", c)),
+    };
+    bytes.push(byte);
+    Ok(())
+}
+
+/// `radix` 進数の数字を最大 `max` 文字読む
+fn take_digits(chars: &mut Peekable<CharIndices>, radix: u32, max: usize) -> String {
+    let mut digits = String::new();
+    while digits.len() < max {
+        match chars.peek() {
+            Some(&(_, c)) if c.is_digit(radix) => {
+                digits.push(c);
+                chars.next();
+            }
+            _ => break,
+        }
+    }
+    digits
+}
+
+/// トークン列を先頭から読み進める
+pub struct Tokens {
+    tokens: Vec<(Token, Span)>,
+    pos: usize,
+    /// 行末のSpan
+    end: Span,
+}
+
+impl Tokens {
+    pub fn new(tokens: Vec<(Token, Span)>, end: usize) -> Tokens {
+        Tokens {
+            tokens,
+            pos: 0,
+            end: Span::new(end, end),
+        }
+    }
+
+    pub fn peek(&self) -> Option<&Token> {
+        self.peek_nth(0)
+    }
+
+    /// `n` 個先のトークン
+    pub fn peek_nth(&self, n: usize) -> Option<&Token> {
+        self.tokens.get(self.pos + n).map(|(token, _)| token)
+    }
+
+    /// 次のトークンのSpan. トークンがなければ行末を返す.
+    pub fn peek_span(&self) -> Span {
+        self.tokens
+            .get(self.pos)
+            .map(|(_, span)| *span)
+            .unwrap_or(self.end)
+    }
+
+    /// 直前に読んだトークンのSpan
+    pub fn prev_span(&self) -> Span {
+        self.tokens[self.pos - 1].1
+    }
+
+    pub fn next_token(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).map(|(token, _)| token.clone());
+        if token.is_some() {
+            self.pos += 1;
+        }
+        token
+    }
+
+    pub fn is_end(&self) -> bool {
+        self.pos >= self.tokens.len()
+    }
+
+    /// 次のトークンが `c` であれば読み進めて `true` を返す
+    pub fn eat_punct(&mut self, c: char) -> bool {
+        if self.peek() == Some(&Token::Punct(c)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn expect_punct(&mut self, c: char) -> ParseResult<()> {
+        if self.eat_punct(c) {
+            Ok(())
+        } else {
+            Err(self.unexpected(&format!("`{}`", c)))
+        }
+    }
+
+    /// 次のトークンが `keyword` (大文字・小文字は区別しない) であれば
+    /// 読み進めて `true` を返す
+    pub fn eat_keyword(&mut self, keyword: &str) -> bool {
+        match self.peek() {
+            Some(Token::Ident(s)) if s.eq_ignore_ascii_case(keyword) => {
+                self.pos += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// `-` が付いているかもしれない整数を読む
+    pub fn expect_int(&mut self) -> ParseResult<i64> {
+        let neg = self.eat_punct('-');
+        let n = match self.peek() {
+            Some(Token::Int(n)) if neg => n.wrapping_neg(),
+            Some(Token::Int(n)) => *n,
+            _ => return Err(self.unexpected("number")),
+        };
+        self.pos += 1;
+        Ok(n)
+    }
+
+    pub fn expect_end(&self) -> ParseResult<()> {
+        match self.peek() {
+            Some(token) => Err(self.error(format!("unexpected token `{}`", token))),
+            None => Ok(()),
+        }
+    }
+
+    /// 次のトークンの位置のエラー
+    pub fn error(&self, message: impl Into<String>) -> ParseError {
+        ParseError::new(self.peek_span(), message)
+    }
+
+    /// `expected` が必要な位置に別のトークンがあるエラー
+    pub fn unexpected(&self, expected: &str) -> ParseError {
+        self.error(format!("{} is expected but got {}", expected, describe(self.peek())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokenize_instruction() {
+        let tokens = tokenize("mov qword [rbp-0x10], 42", 2)
+            .unwrap()
+            .into_iter()
+            .map(|(token, _)| token)
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Ident("mov".to_string()),
+                Token::Ident("qword".to_string()),
+                Token::Punct('['),
+                Token::Ident("rbp".to_string()),
+                Token::Punct('-'),
+                Token::Int(16),
+                Token::Punct(']'),
+                Token::Punct(','),
+                Token::Int(42),
+            ]
+        );
+    }
+
+    #[test]
+    fn tokenize_string() {
+        let tokens = tokenize(r#"'a\n', "a\tb\x41\101", `\0`"#, 0)
+            .unwrap()
+            .into_iter()
+            .map(|(token, _)| token)
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Str(b"a\\n".to_vec()),
+                Token::Punct(','),
+                Token::Str(b"a\tbAA".to_vec()),
+                Token::Punct(','),
+                Token::Str(vec![0]),
+            ]
+        );
+    }
+
+    #[test]
+    fn tokenize_expression() {
+        let tokens = tokenize("($ - $$) << 2 >> x", 0)
+            .unwrap()
+            .into_iter()
+            .map(|(token, _)| token)
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Punct('('),
+                Token::Here,
+                Token::Punct('-'),
+                Token::SectionStart,
+                Token::Punct(')'),
+                Token::Shl,
+                Token::Int(2),
+                Token::Shr,
+                Token::Ident("x".to_string()),
+            ]
+        );
+
+        let tokens = tokenize("!a || b <> 1 && c <= 2", 0)
+            .unwrap()
+            .into_iter()
+            .map(|(token, _)| token)
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Punct('!'),
+                Token::Ident("a".to_string()),
+                Token::LogOr,
+                Token::Ident("b".to_string()),
+                Token::Ne,
+                Token::Int(1),
+                Token::LogAnd,
+                Token::Ident("c".to_string()),
+                Token::Le,
+                Token::Int(2),
+            ]
+        );
+    }
+
+    #[test]
+    fn token_span_includes_offset() {
+        let tokens = tokenize("ret", 4).unwrap();
+        assert_eq!(tokens[0].1, Span::new(4, 7));
+    }
+
+    #[test]
+    fn tokenize_error() {
+        let err = tokenize("db 'abc", 2).unwrap_err();
+        assert_eq!(err, ParseError::new(Span::new(5, 9), "unterminated string"));
+        let err = tokenize("mov rax, 1 @ 2", 0).unwrap_err();
+        assert_eq!(err.span, Span::new(11, 12));
+    }
+}
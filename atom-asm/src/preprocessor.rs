@@ -0,0 +1,1254 @@
+use crate::{
+    diagnostic::{Diagnostic, Diagnostics},
+    eval::Evaluator,
+    listing::Listing,
+    object::SectionId,
+    parser::{is_ident_char, is_ident_start, parse_expression, strip_comment},
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::{self, Display},
+    fs::File,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+/// マクロ展開のネストの上限
+const MAX_EXPANSION_DEPTH: usize = 64;
+
+/// ソース上の位置
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub file: Rc<Path>,
+    /// 1始まりの行番号
+    pub line: usize,
+    /// マクロ展開で生成された行であれば展開元
+    pub expansion: Option<Rc<Expansion>>,
+    /// `%include` されたファイルであれば `%include` の行
+    pub included_from: Option<Rc<Location>>,
+}
+
+/// マクロの呼び出し
+#[derive(Debug, PartialEq, Eq)]
+pub struct Expansion {
+    pub name: String,
+    /// 呼び出した行
+    pub call: Location,
+    /// `%macro` の行
+    pub def: Location,
+}
+
+impl Location {
+    /// `file:line` 形式で表示する
+    pub fn position(&self) -> String {
+        format!("{}:{}", self.file.display(), self.line)
+    }
+
+    /// マクロ展開のネストの深さ
+    pub fn depth(&self) -> usize {
+        self.expansion
+            .as_ref()
+            .map_or(0, |expansion| expansion.call.depth() + 1)
+    }
+}
+
+impl Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.position())?;
+        let mut loc = self;
+        while let Some(e) = &loc.expansion {
+            write!(
+                f,
+                "\n  in macro {} called at {} (defined at {})",
+                e.name,
+                e.call.position(),
+                e.def.position()
+            )?;
+            loc = &e.call;
+        }
+        while let Some(include) = &loc.included_from {
+            write!(f, "\n  included from {}", include.position())?;
+            loc = include;
+        }
+        Ok(())
+    }
+}
+
+/// プリプロセス済みの1行
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLine {
+    /// 改行を含まない
+    pub text: String,
+    pub loc: Location,
+    /// 読み込んだ順の通し番号. マクロを展開した行も数える.
+    /// リスティングの行に対応する.
+    pub index: usize,
+}
+
+/// `%define` による1行マクロ
+struct Define {
+    /// `%define name(a, b) ...` の引数
+    params: Option<Vec<String>>,
+    body: String,
+}
+
+/// `%macro` による複数行マクロ
+struct Macro {
+    /// 引数の個数の最小値
+    min: usize,
+    /// 引数の個数の最大値. `None` であれば上限なし.
+    max: Option<usize>,
+    /// `+` が付いていれば最後の引数が残り全てを受け取る
+    greedy: bool,
+    /// `min` 個目より後の引数のデフォルト値
+    defaults: Vec<String>,
+    body: Vec<(String, Location)>,
+    def: Location,
+}
+
+impl Macro {
+    fn arity(&self) -> String {
+        match self.max {
+            Some(max) if max == self.min => max.to_string(),
+            Some(max) => format!("{}-{}", self.min, max),
+            None => format!("{} or more", self.min),
+        }
+    }
+}
+
+/// 行の読み込み元
+enum Source<'a> {
+    File {
+        read: Box<dyn BufRead + 'a>,
+        file: Rc<Path>,
+        /// `%include` の循環を検出するのに使う
+        canonical: PathBuf,
+        /// 読み込んだ行数
+        line: usize,
+        included_from: Option<Rc<Location>>,
+    },
+    /// マクロや `%rep` を展開した行
+    Lines(VecDeque<(String, Location)>),
+}
+
+/// `%if` ... `%endif`
+struct Cond {
+    /// 今の分岐の行を出力するか
+    active: bool,
+    /// いずれかの分岐が選ばれたか
+    taken: bool,
+    /// `%else` の後であるか
+    has_else: bool,
+    /// `%if` の行
+    loc: Location,
+}
+
+/// NASM形式のマクロや条件分岐を処理して、`LineStream` に渡す行を作る
+pub struct Preprocessor<'a> {
+    /// 末尾から読み込む. 読み終えたら1つ前に戻る.
+    sources: Vec<Source<'a>>,
+    defines: HashMap<String, Define>,
+    macros: HashMap<String, Rc<Macro>>,
+    conds: Vec<Cond>,
+    /// `%include` や `incbin` のファイルを探すディレクトリ
+    include_dirs: Vec<PathBuf>,
+    /// 展開した回数. `%%label` を一意にするのに使う.
+    expansions: usize,
+    /// 読み込んだ行数
+    lines_read: usize,
+    listing: Option<Listing>,
+    diagnostics: Diagnostics,
+}
+
+/// `Diagnostic` は大きいので `Box` に入れて返す
+type Result<T> = std::result::Result<T, Box<Diagnostic>>;
+
+impl<'a> Preprocessor<'a> {
+    /// `file` は `read` のファイル名
+    pub fn new(file: &Path, read: impl BufRead + 'a, diagnostics: Diagnostics) -> Self {
+        Preprocessor {
+            sources: vec![Source::File {
+                read: Box::new(read),
+                file: Rc::from(file),
+                canonical: file.canonicalize().unwrap_or_else(|_| file.to_path_buf()),
+                line: 0,
+                included_from: None,
+            }],
+            defines: HashMap::new(),
+            macros: HashMap::new(),
+            conds: Vec::new(),
+            include_dirs: Vec::new(),
+            expansions: 0,
+            lines_read: 0,
+            listing: None,
+            diagnostics,
+        }
+    }
+
+    /// 読み込んだ全ての行を `listing` に追加する
+    pub fn set_listing(&mut self, listing: Listing) {
+        self.listing = Some(listing);
+    }
+
+    /// コマンドラインの `-I dir`
+    pub fn add_include_dir(&mut self, dir: PathBuf) {
+        self.include_dirs.push(dir);
+    }
+
+    /// コマンドラインの `-D NAME=VALUE`
+    pub fn define(&mut self, name: &str, value: &str) {
+        let define = Define {
+            params: None,
+            body: value.to_string(),
+        };
+        self.defines.insert(name.to_string(), define);
+    }
+
+    /// 今の読み込み元から1行読んで、行の内容, 位置, 通し番号を返す.
+    /// 読み込みに失敗したらエラーを報告してファイルの終わりとして扱う.
+    fn read_line(&mut self) -> Option<(String, Location, usize)> {
+        let (text, loc) = match self.sources.last_mut()? {
+            Source::File {
+                read,
+                file,
+                line,
+                included_from,
+                ..
+            } => {
+                *line += 1;
+                let loc = Location {
+                    file: Rc::clone(file),
+                    line: *line,
+                    expansion: None,
+                    included_from: included_from.clone(),
+                };
+
+                let mut buf = String::new();
+                match read.read_line(&mut buf) {
+                    Ok(0) => return None,
+                    Ok(_) => {}
+                    Err(e) => {
+                        let message = format!("could not read {}: {}", file.display(), e);
+                        self.diagnostics.push(Diagnostic::error(loc, message));
+                        return None;
+                    }
+                }
+
+                let len = buf.trim_end_matches(&['\n', '\r'][..]).len();
+                buf.truncate(len);
+                (buf, loc)
+            }
+            Source::Lines(lines) => lines.pop_front()?,
+        };
+
+        let index = self.lines_read;
+        self.lines_read += 1;
+        if let Some(listing) = &self.listing {
+            // 読み込み元のネストがそのまま字下げになる
+            listing.push_line(&text, loc.line, self.sources.len() - 1);
+        }
+        Some((text, loc, index))
+    }
+
+    /// `end` までの行を読む. ネストした `begin` ... `end` はそのまま含める.
+    fn read_block(
+        &mut self,
+        begin: &str,
+        end: &str,
+        loc: &Location,
+    ) -> Result<Vec<(String, Location)>> {
+        let mut body = Vec::new();
+        let mut nest = 0;
+        loop {
+            let (text, line_loc, _) = self
+                .read_line()
+                .ok_or_else(|| error(loc, format!("{} is not closed by {}", begin, end)))?;
+            match directive(&text) {
+                Some((d, _)) if d == begin => nest += 1,
+                Some((d, _)) if d == end && nest == 0 => break,
+                Some((d, _)) if d == end => nest -= 1,
+                _ => {}
+            }
+            body.push((text, line_loc));
+        }
+        Ok(body)
+    }
+
+    /// `%macro` から `%endmacro` までを読み込む
+    fn define_macro(&mut self, args: &str, loc: &Location) -> Result<()> {
+        // 1行目が誤っていても本体は読み飛ばす
+        let header = parse_macro_header(args, loc);
+        let body = self.read_block("%macro", "%endmacro", loc)?;
+        let (name, mut mac) = header?;
+        mac.body = body;
+        if let Some(prev) = self.macros.get(&name) {
+            let message = format!("macro {} is redefined", name);
+            let note = format!("previous definition at {}", prev.def.position());
+            self.diagnostics
+                .push(Diagnostic::warning(loc.clone(), message).with_note(note));
+        }
+        self.macros.insert(name, Rc::new(mac));
+        Ok(())
+    }
+
+    /// `%rep` から `%endrep` までを読み込んで、指定回数繰り返す
+    fn repeat(&mut self, args: &str, loc: &Location) -> Result<()> {
+        let count = self.eval(args, loc);
+        let body = self.read_block("%rep", "%endrep", loc)?;
+        let count = count?;
+        if count < 0 {
+            return Err(error(loc, format!("repeat count is negative: {}", count)));
+        }
+        let lines = (0..count).flat_map(|_| body.iter().cloned()).collect();
+        self.sources.push(Source::Lines(lines));
+        Ok(())
+    }
+
+    /// `%define name(a, b) body` の `name` 以降を読む
+    fn define_directive(&mut self, args: &str, loc: &Location) -> Result<()> {
+        let name_len = ident_len(args);
+        if name_len == 0 {
+            return Err(error(loc, "macro name is not specified"));
+        }
+        let name = args[..name_len].to_string();
+        let rest = &args[name_len..];
+
+        // 名前の直後に `(` があれば引数を取る
+        let (params, body) = match rest.strip_prefix('(') {
+            Some(rest) => {
+                let close = rest.find(')').ok_or_else(|| error(loc, "')' is expected"))?;
+                let params = split_args(&rest[..close], None);
+                if let Some(p) = params.iter().find(|p| ident_len(p) != p.len()) {
+                    return Err(error(loc, format!("invalid parameter name {}", p)));
+                }
+                (Some(params), &rest[close + 1..])
+            }
+            None => (None, rest),
+        };
+
+        let define = Define {
+            params,
+            body: body.trim().to_string(),
+        };
+        self.defines.insert(name, define);
+        Ok(())
+    }
+
+    /// 1行を処理して、`LineStream` に渡す行があれば返す
+    fn process(&mut self, text: &str, loc: &Location, index: usize) -> Result<Option<SourceLine>> {
+        let directive = directive(text);
+
+        // 条件分岐は読み飛ばしている間も対応を取る
+        if let Some((name, args)) = &directive {
+            if self.conditional(name, args, loc)? {
+                return Ok(None);
+            }
+        }
+        if !self.is_active() {
+            return Ok(None);
+        }
+
+        if let Some((name, args)) = directive {
+            match name.as_str() {
+                "%define" => self.define_directive(args, loc)?,
+                "%undef" => {
+                    self.defines.remove(args);
+                }
+                "%macro" => self.define_macro(args, loc)?,
+                "%rep" => self.repeat(args, loc)?,
+                "%include" => self.include(args, loc)?,
+                "%endmacro" => return Err(error(loc, "%endmacro without %macro")),
+                "%endrep" => return Err(error(loc, "%endrep without %rep")),
+                _ => return Err(error(loc, format!("unknown directive {}", name))),
+            }
+            return Ok(None);
+        }
+
+        let text = self.expand_defines(text, &mut Vec::new(), loc)?;
+        match self.macro_call(&text) {
+            Some((label, name, args)) => {
+                self.expand_macro(&name, &args, loc)?;
+                // ラベルは展開した行より前に置く
+                Ok(label.map(|label| SourceLine {
+                    text: format!("{}:", label),
+                    loc: loc.clone(),
+                    index,
+                }))
+            }
+            None => {
+                let text = self.resolve_incbin(text, loc)?;
+                Ok(Some(SourceLine {
+                    text,
+                    loc: loc.clone(),
+                    index,
+                }))
+            }
+        }
+    }
+
+    /// `name` を `%include` している行のファイルと同じディレクトリ、
+    /// `-I` で指定されたディレクトリの順に探す
+    fn resolve(&self, name: &str, loc: &Location) -> Result<PathBuf> {
+        let base = loc.file.parent().unwrap_or_else(|| Path::new(""));
+        std::iter::once(base)
+            .chain(self.include_dirs.iter().map(PathBuf::as_path))
+            .map(|dir| dir.join(name))
+            .find(|path| path.is_file())
+            .ok_or_else(|| error(loc, format!("could not find {}", name)))
+    }
+
+    /// `%include "file"` のファイルを読み込み元に追加する
+    fn include(&mut self, args: &str, loc: &Location) -> Result<()> {
+        let name = match (args.chars().next(), args.chars().last()) {
+            (Some('"'), Some('"')) | (Some('\''), Some('\'')) | (Some('<'), Some('>'))
+                if args.len() >= 2 =>
+            {
+                &args[1..args.len() - 1]
+            }
+            _ => return Err(error(loc, "file name is expected")),
+        };
+        let path = self.resolve(name, loc)?;
+
+        let canonical = path.canonicalize().unwrap_or_else(|_| path.clone());
+        let recursive = self.sources.iter().any(|source| match source {
+            Source::File { canonical: c, .. } => *c == canonical,
+            Source::Lines(_) => false,
+        });
+        if recursive {
+            return Err(error(loc, format!("{} is included recursively", name)));
+        }
+
+        let read = File::open(&path)
+            .map_err(|e| error(loc, format!("could not open {}: {}", path.display(), e)))?;
+        self.sources.push(Source::File {
+            read: Box::new(BufReader::new(read)),
+            file: Rc::from(path),
+            canonical,
+            line: 0,
+            included_from: Some(Rc::new(loc.clone())),
+        });
+        Ok(())
+    }
+
+    /// `incbin "file"` のファイル名を `%include` と同じ規則で探したパスに置き換える
+    fn resolve_incbin(&self, text: String, loc: &Location) -> Result<String> {
+        let start = match incbin_file_name(strip_comment(&text)) {
+            Some(start) => start,
+            None => return Ok(text),
+        };
+        let end = start + string_end(&text[start..], 0);
+        let name = &text[start + 1..end - 1];
+        let path = self.resolve(name, loc)?;
+
+        // バッククォートの中では `\` と `` ` `` をエスケープする
+        let path = path.display().to_string().replace('\\', "\\\\").replace('`', "\\`");
+        Ok(format!("{}`{}`{}", &text[..start], path, &text[end..]))
+    }
+
+    fn is_active(&self) -> bool {
+        self.conds.last().is_none_or(|cond| cond.active)
+    }
+
+    /// 条件分岐のディレクティブであれば処理して `true` を返す.
+    /// 条件の評価に失敗した分岐は選ばれたものとして扱い、以降の分岐も読み飛ばす.
+    fn conditional(&mut self, name: &str, args: &str, loc: &Location) -> Result<bool> {
+        match name {
+            "%if" | "%ifdef" | "%ifndef" => {
+                // 読み飛ばしている間の `%if` はどの分岐も選ばない
+                let outer = self.is_active();
+                let condition = if outer {
+                    self.condition(name, args, loc)
+                } else {
+                    Ok(false)
+                };
+                let active = condition.as_ref().is_ok_and(|active| *active);
+                self.conds.push(Cond {
+                    active,
+                    taken: active || !outer || condition.is_err(),
+                    has_else: false,
+                    loc: loc.clone(),
+                });
+                condition?;
+            }
+            "%elif" | "%elifdef" | "%elifndef" | "%else" => {
+                let cond = self
+                    .conds
+                    .last()
+                    .ok_or_else(|| error(loc, format!("{} without %if", name)))?;
+                if cond.has_else {
+                    return Err(error(loc, format!("{} after %else", name)));
+                }
+                let condition = if cond.taken {
+                    Ok(false)
+                } else if name == "%else" {
+                    Ok(true)
+                } else {
+                    self.condition(name, args, loc)
+                };
+                let active = condition.as_ref().is_ok_and(|active| *active);
+
+                let cond = self.conds.last_mut().unwrap();
+                cond.active = active;
+                cond.taken |= active || condition.is_err();
+                cond.has_else = name == "%else";
+                condition?;
+            }
+            "%endif" => {
+                if self.conds.pop().is_none() {
+                    return Err(error(loc, "%endif without %if"));
+                }
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// `%if expr`, `%ifdef name`, `%ifndef name` などの条件
+    fn condition(&self, name: &str, args: &str, loc: &Location) -> Result<bool> {
+        if name.ends_with("def") {
+            if args.is_empty() || ident_len(args) != args.len() {
+                return Err(error(loc, format!("{} expects a symbol name", name)));
+            }
+            let defined = self.defines.contains_key(args);
+            Ok(defined != name.ends_with("ndef"))
+        } else {
+            Ok(self.eval(args, loc)? != 0)
+        }
+    }
+
+    /// `%define` を展開してから定数式を評価する
+    fn eval(&self, s: &str, loc: &Location) -> Result<i64> {
+        let s = self.expand_defines(s, &mut Vec::new(), loc)?;
+        let expr = parse_expression(&s).map_err(|e| error(loc, e.message))?;
+        let symbols = HashMap::new();
+        Evaluator::new(&symbols, SectionId(0), 0)
+            .eval_const(&expr)
+            .map_err(|message| error(loc, message))
+    }
+
+    /// `label: name args` の形であれば、ラベル, マクロ名, 引数を返す
+    fn macro_call(&self, text: &str) -> Option<(Option<String>, String, String)> {
+        let s = strip_comment(text).trim_start();
+        let len = ident_len(s);
+        let (label, s) = match s[len..].trim_start().strip_prefix(':') {
+            Some(rest) if len > 0 => (Some(s[..len].to_string()), rest.trim_start()),
+            _ => (None, s),
+        };
+
+        let len = ident_len(s);
+        if len == 0 || !self.macros.contains_key(&s[..len]) {
+            return None;
+        }
+        Some((label, s[..len].to_string(), s[len..].trim().to_string()))
+    }
+
+    /// マクロを展開した行を読み込み元に追加する
+    fn expand_macro(&mut self, name: &str, args: &str, loc: &Location) -> Result<()> {
+        if loc.depth() >= MAX_EXPANSION_DEPTH {
+            return Err(error(loc, format!("macro {} is expanded too deeply", name)));
+        }
+        let mac = Rc::clone(&self.macros[name]);
+
+        let limit = if mac.greedy { mac.max } else { None };
+        let mut args = split_args(args, limit);
+        if args.len() < mac.min || mac.max.is_some_and(|max| args.len() > max) {
+            let message = format!(
+                "macro {} (defined at {}) takes {} parameters but {} were given",
+                name,
+                mac.def.position(),
+                mac.arity(),
+                args.len()
+            );
+            return Err(error(loc, message));
+        }
+        // `%0` は省略された引数を含まない
+        let given = args.len();
+        // 省略された引数はデフォルト値か空になる
+        for i in args.len()..mac.max.unwrap_or(0) {
+            args.push(mac.defaults.get(i - mac.min).cloned().unwrap_or_default());
+        }
+
+        self.expansions += 1;
+        let id = self.expansions;
+        let expansion = Rc::new(Expansion {
+            name: name.to_string(),
+            call: loc.clone(),
+            def: mac.def.clone(),
+        });
+
+        let lines = mac
+            .body
+            .iter()
+            .map(|(line, line_loc)| {
+                let loc = Location {
+                    expansion: Some(Rc::clone(&expansion)),
+                    ..line_loc.clone()
+                };
+                Ok((substitute_params(line, &args, given, id, &loc)?, loc))
+            })
+            .collect::<Result<_>>()?;
+        self.sources.push(Source::Lines(lines));
+        Ok(())
+    }
+
+    /// `%define` されたシンボルを展開する.
+    /// `active` は展開中のシンボル (自分自身は展開しない).
+    fn expand_defines(&self, s: &str, active: &mut Vec<String>, loc: &Location) -> Result<String> {
+        let mut out = String::new();
+        let mut i = 0;
+        while let Some(c) = s[i..].chars().next() {
+            if is_quote(c) {
+                let end = string_end(s, i);
+                out.push_str(&s[i..end]);
+                i = end;
+                continue;
+            }
+            if c == ';' {
+                out.push_str(&s[i..]);
+                break;
+            }
+            if !is_ident_char(c) {
+                out.push(c);
+                i += c.len_utf8();
+                continue;
+            }
+
+            // `0x10` の `x10` などを識別子として扱わないよう、数字から始まるものも読み飛ばす
+            let end = i + s[i..].find(|c| !is_ident_char(c)).unwrap_or(s.len() - i);
+            let word = &s[i..end];
+            let define = match self.defines.get(word) {
+                Some(define) if !active.iter().any(|a| a == word) => define,
+                _ => {
+                    out.push_str(word);
+                    i = end;
+                    continue;
+                }
+            };
+
+            let (body, end) = match &define.params {
+                None => (define.body.clone(), end),
+                Some(params) => match call_args(s, end) {
+                    Some((args, end)) if args.len() == params.len() => {
+                        (substitute_idents(&define.body, params, &args), end)
+                    }
+                    Some((args, _)) => {
+                        let message = format!(
+                            "{} takes {} parameters but {} were given",
+                            word,
+                            params.len(),
+                            args.len()
+                        );
+                        return Err(error(loc, message));
+                    }
+                    // 引数がなければ展開しない
+                    None => (word.to_string(), end),
+                },
+            };
+            active.push(word.to_string());
+            let expanded = self.expand_defines(&body, active, loc);
+            active.pop();
+            out.push_str(&expanded?);
+            i = end;
+        }
+        Ok(out)
+    }
+}
+
+impl Iterator for Preprocessor<'_> {
+    type Item = SourceLine;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (text, loc, index) = match self.read_line() {
+                Some(line) => line,
+                // 展開した行を読み終えたら元の読み込み元に戻る
+                None if self.sources.len() > 1 => {
+                    self.sources.pop();
+                    continue;
+                }
+                None => {
+                    for cond in self.conds.drain(..) {
+                        let message = "%if is not closed by %endif";
+                        self.diagnostics.push(Diagnostic::error(cond.loc, message));
+                    }
+                    return None;
+                }
+            };
+
+            match self.process(&text, &loc, index) {
+                Ok(Some(line)) => return Some(line),
+                Ok(None) => {}
+                // エラーを報告して次の行に進む
+                Err(mut diagnostic) => {
+                    if diagnostic.loc == loc {
+                        diagnostic.source = Some(text);
+                    }
+                    self.diagnostics.push(*diagnostic);
+                }
+            }
+        }
+    }
+}
+
+fn error(loc: &Location, message: impl Into<String>) -> Box<Diagnostic> {
+    Box::new(Diagnostic::error(loc.clone(), message))
+}
+
+/// `%macro name 1-2 default` の `%macro` 以降を読んで、マクロ名と本体が空のマクロを返す
+fn parse_macro_header(args: &str, loc: &Location) -> Result<(String, Macro)> {
+    let mut words = args.splitn(3, char::is_whitespace);
+    let name = match words.next() {
+        Some(name) if name.starts_with(is_ident_start) => name.to_string(),
+        _ => return Err(error(loc, "macro name is not specified")),
+    };
+    let spec = words
+        .next()
+        .ok_or_else(|| error(loc, "number of parameters is not specified"))?;
+    let (min, max, greedy) = parse_arity(spec)
+        .ok_or_else(|| error(loc, format!("invalid number of parameters {}", spec)))?;
+    let defaults = split_args(words.next().unwrap_or(""), None);
+    if max.is_some_and(|max| min + defaults.len() > max) {
+        return Err(error(loc, "too many default parameters"));
+    }
+    let mac = Macro {
+        min,
+        max,
+        greedy,
+        defaults,
+        body: Vec::new(),
+        def: loc.clone(),
+    };
+    Ok((name, mac))
+}
+
+/// `%name args` の形であれば小文字にした `%name` と引数を返す
+fn directive(text: &str) -> Option<(String, &str)> {
+    let s = strip_comment(text).trim();
+    let rest = s.strip_prefix('%')?;
+    if !rest.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        return None;
+    }
+    let end = s.find(char::is_whitespace).unwrap_or(s.len());
+    Some((s[..end].to_ascii_lowercase(), s[end..].trim()))
+}
+
+/// `incbin "file"` の行であればファイル名の文字列の位置を返す.
+/// `label: incbin`, `label incbin` の形もある.
+fn incbin_file_name(s: &str) -> Option<usize> {
+    let mut rest = s.trim_start();
+    for _ in 0..2 {
+        let len = ident_len(rest);
+        if len == 0 {
+            return None;
+        }
+        let word = &rest[..len];
+        rest = rest[len..].trim_start();
+        if word.eq_ignore_ascii_case("incbin") {
+            if !rest.starts_with(is_quote) {
+                return None;
+            }
+            // 閉じていない文字列はパーサーでエラーにする
+            let end = string_end(rest, 0);
+            let closed = end >= 2 && rest[..end].ends_with(|c| rest.starts_with(c));
+            return Some(s.len() - rest.len()).filter(|_| closed);
+        }
+        rest = rest.strip_prefix(':').unwrap_or(rest).trim_start();
+    }
+    None
+}
+
+/// `1`, `1-3`, `1+`, `1-*` 形式の引数の個数
+fn parse_arity(s: &str) -> Option<(usize, Option<usize>, bool)> {
+    let (s, greedy) = match s.strip_suffix('+') {
+        Some(s) => (s, true),
+        None => (s, false),
+    };
+    let mut range = s.splitn(2, '-');
+    let min = range.next()?.parse().ok()?;
+    let max = match range.next() {
+        Some("*") => None,
+        Some(max) => Some(max.parse().ok().filter(|max| *max >= min)?),
+        None => Some(min),
+    };
+    Some((min, max, greedy))
+}
+
+fn ident_len(s: &str) -> usize {
+    if !s.starts_with(is_ident_start) {
+        return 0;
+    }
+    s.find(|c| !is_ident_char(c)).unwrap_or(s.len())
+}
+
+fn is_quote(c: char) -> bool {
+    c == '\'' || c == '"' || c == '`'
+}
+
+/// `s[start..]` から始まる文字列の終わりの位置
+fn string_end(s: &str, start: usize) -> usize {
+    let quote = s[start..].chars().next().unwrap();
+    let mut escaped = false;
+    for (i, c) in s[start + 1..].char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' && quote != '\'' {
+            escaped = true;
+        } else if c == quote {
+            return start + 1 + i + 1;
+        }
+    }
+    s.len()
+}
+
+/// カンマ区切りの引数. `{a, b}` のように括弧で囲めば1つの引数にカンマを含められる.
+/// `limit` 個目の引数は残り全てを受け取る.
+fn split_args(s: &str, limit: Option<usize>) -> Vec<String> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Vec::new();
+    }
+
+    let mut args = Vec::new();
+    let mut start = 0;
+    let mut depth = 0usize;
+    let mut i = 0;
+    while let Some(c) = s[i..].chars().next() {
+        match c {
+            _ if is_quote(c) => {
+                i = string_end(s, i);
+                continue;
+            }
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => depth = depth.saturating_sub(1),
+            ',' if depth == 0 && limit.is_none_or(|n| args.len() + 1 < n) => {
+                args.push(trim_arg(&s[start..i]));
+                start = i + 1;
+            }
+            _ => {}
+        }
+        i += c.len_utf8();
+    }
+    args.push(trim_arg(&s[start..]));
+    args
+}
+
+fn trim_arg(s: &str) -> String {
+    let s = s.trim();
+    match s.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+        Some(inner) => inner.trim().to_string(),
+        None => s.to_string(),
+    }
+}
+
+/// `s[start..]` が `(a, b)` で始まっていれば引数と `)` の次の位置を返す
+fn call_args(s: &str, start: usize) -> Option<(Vec<String>, usize)> {
+    let open = start + s[start..].len() - s[start..].trim_start().len();
+    if !s[open..].starts_with('(') {
+        return None;
+    }
+
+    let mut depth = 0;
+    let mut i = open;
+    while let Some(c) = s[i..].chars().next() {
+        match c {
+            _ if is_quote(c) => {
+                i = string_end(s, i);
+                continue;
+            }
+            '(' => depth += 1,
+            ')' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some((split_args(&s[open + 1..i], None), i + 1));
+                }
+            }
+            _ => {}
+        }
+        i += c.len_utf8();
+    }
+    None
+}
+
+/// 文字列の外にある識別子 `params[i]` を `args[i]` に置き換える
+fn substitute_idents(s: &str, params: &[String], args: &[String]) -> String {
+    let mut out = String::new();
+    let mut i = 0;
+    while let Some(c) = s[i..].chars().next() {
+        if is_quote(c) {
+            let end = string_end(s, i);
+            out.push_str(&s[i..end]);
+            i = end;
+        } else if is_ident_char(c) {
+            let end = i + s[i..].find(|c| !is_ident_char(c)).unwrap_or(s.len() - i);
+            let word = &s[i..end];
+            match params.iter().position(|p| p == word) {
+                Some(n) => out.push_str(&args[n]),
+                None => out.push_str(word),
+            }
+            i = end;
+        } else {
+            out.push(c);
+            i += c.len_utf8();
+        }
+    }
+    out
+}
+
+/// マクロ本体の `%0`, `%1..%N`, `%%label` を置き換える.
+/// `given` は実際に渡された引数の数, `id` は展開ごとに異なる番号.
+fn substitute_params(
+    s: &str,
+    args: &[String],
+    given: usize,
+    id: usize,
+    loc: &Location,
+) -> Result<String> {
+    let mut out = String::new();
+    let mut i = 0;
+    while let Some(c) = s[i..].chars().next() {
+        if is_quote(c) {
+            let end = string_end(s, i);
+            out.push_str(&s[i..end]);
+            i = end;
+            continue;
+        }
+        if c == ';' {
+            out.push_str(&s[i..]);
+            break;
+        }
+        i += c.len_utf8();
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+
+        let rest = &s[i..];
+        if rest.starts_with('%') {
+            // `%%label` -> `..@1.label`
+            out.push_str(&format!("..@{}.", id));
+            i += 1;
+        } else if rest.starts_with(|c: char| c.is_ascii_digit()) {
+            let len = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+            let n = rest[..len].parse::<usize>().unwrap();
+            match n {
+                0 => out.push_str(&given.to_string()),
+                _ => match args.get(n - 1) {
+                    Some(arg) => out.push_str(arg),
+                    None => {
+                        let message = format!("macro parameter %{} is out of range", n);
+                        return Err(error(loc, message));
+                    }
+                },
+            }
+            i += len;
+        } else {
+            out.push('%');
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn preprocess(s: &str) -> Vec<String> {
+        let diagnostics = Diagnostics::new();
+        let lines = Preprocessor::new(Path::new("test.s"), s.as_bytes(), diagnostics.clone())
+            .map(|line| line.text)
+            .collect();
+        assert_eq!(diagnostics.take(), vec![]);
+        lines
+    }
+
+    /// 報告されたエラーのメッセージと行番号
+    fn errors(s: &str) -> Vec<(String, usize)> {
+        let diagnostics = Diagnostics::new();
+        Preprocessor::new(Path::new("test.s"), s.as_bytes(), diagnostics.clone()).for_each(drop);
+        diagnostics
+            .take()
+            .into_iter()
+            .map(|d| (d.message, d.loc.line))
+            .collect()
+    }
+
+    #[test]
+    fn expand_macro() {
+        let src = "\
+%macro write 2-3 1 ; fd
+    mov rdi, %3
+    lea rsi, [rel %1]
+    mov rdx, %2
+    call %%do
+%%do: syscall
+%endmacro
+write msg, len
+start: write {buf + 1}, 4, 2
+";
+        assert_eq!(
+            preprocess(src),
+            vec![
+                "    mov rdi, 1",
+                "    lea rsi, [rel msg]",
+                "    mov rdx, len",
+                "    call ..@1.do",
+                "..@1.do: syscall",
+                "start:",
+                "    mov rdi, 2",
+                "    lea rsi, [rel buf + 1]",
+                "    mov rdx, 4",
+                "    call ..@2.do",
+                "..@2.do: syscall",
+            ]
+        );
+    }
+
+    #[test]
+    fn nested_and_greedy_macro() {
+        let src = "\
+%macro string 1+
+    db %1, 0
+%endmacro
+%macro strings 0-*
+    string %0, ';'
+%endmacro
+strings a, b
+";
+        assert_eq!(preprocess(src), vec!["    db 2, ';', 0"]);
+    }
+
+    #[test]
+    fn param_count_excludes_defaults() {
+        let src = "\
+%macro exit 0-1 0
+%if %0 == 0
+    xor edi, edi
+%else
+    mov rdi, %1
+%endif
+%endmacro
+exit
+exit 3
+";
+        assert_eq!(preprocess(src), vec!["    xor edi, edi", "    mov rdi, 3"]);
+    }
+
+    #[test]
+    fn expand_define() {
+        let src = "\
+%define SYS_EXIT 0x2000001
+%define ADD(a, b) (a + b)
+mov rax, ADD(SYS_EXIT, 0x10) ; SYS_EXIT
+db 'SYS_EXIT'
+%undef SYS_EXIT
+mov rax, SYS_EXIT
+";
+        assert_eq!(
+            preprocess(src),
+            vec![
+                "mov rax, (0x2000001 + 0x10) ; SYS_EXIT",
+                "db 'SYS_EXIT'",
+                "mov rax, SYS_EXIT",
+            ]
+        );
+    }
+
+    #[test]
+    fn conditional() {
+        let src = "\
+%define DEBUG 2
+%if DEBUG >= 2
+  a
+  %ifdef RELEASE
+  b
+  %elif DEBUG == 2
+  c
+  %else
+  d
+  %endif
+%elifndef DEBUG
+  e
+%else
+  f
+%endif
+%if 0
+  %if 1
+  g
+  %else
+  h
+  %endif
+%endif
+%ifndef RELEASE
+  i
+%endif
+";
+        assert_eq!(preprocess(src), vec!["  a", "  c", "  i"]);
+    }
+
+    #[test]
+    fn repeat() {
+        let src = "\
+%macro zeros 1
+%rep %1
+    db 0
+%endrep
+%endmacro
+%rep 2
+    nop
+    %rep 1 + 1
+    zeros 1
+    %endrep
+%endrep
+";
+        assert_eq!(
+            preprocess(src),
+            vec!["    nop", "    db 0", "    db 0", "    nop", "    db 0", "    db 0"]
+        );
+    }
+
+    #[test]
+    fn command_line_define() {
+        let src = "%ifdef DEBUG\nmov rax, LEVEL\n%endif\n";
+        let mut preprocessor =
+            Preprocessor::new(Path::new("test.s"), src.as_bytes(), Diagnostics::new());
+        preprocessor.define("DEBUG", "");
+        preprocessor.define("LEVEL", "3");
+        let lines = preprocessor.map(|line| line.text).collect::<Vec<_>>();
+        assert_eq!(lines, vec!["mov rax, 3"]);
+    }
+
+    #[test]
+    fn unclosed_if() {
+        assert_eq!(
+            errors("%if 1\nnop\n"),
+            vec![("%if is not closed by %endif".to_string(), 1)]
+        );
+    }
+
+    #[test]
+    fn continue_after_error() {
+        let src = "\
+%macro 1
+    nop
+%endmacro
+%if UNDEFINED +
+    a
+%else
+    b
+%endif
+%rep -1
+    c
+%endrep
+%foo
+%endif
+";
+        assert_eq!(
+            errors(src),
+            vec![
+                ("macro name is not specified".to_string(), 1),
+                ("expression is expected but got end of line".to_string(), 4),
+                ("repeat count is negative: -1".to_string(), 9),
+                ("unknown directive %foo".to_string(), 12),
+                ("%endif without %if".to_string(), 13),
+            ]
+        );
+    }
+
+    /// 一時ディレクトリにファイルを作る
+    fn temp_dir(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("atom-asm-{}-{}", name, std::process::id()));
+        for (path, content) in files {
+            let path = dir.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        dir
+    }
+
+    fn open(path: &Path, diagnostics: &Diagnostics) -> Preprocessor<'static> {
+        let read = BufReader::new(File::open(path).unwrap());
+        Preprocessor::new(path, read, diagnostics.clone())
+    }
+
+    #[test]
+    fn include_file() {
+        let dir = temp_dir(
+            "include",
+            &[
+                (
+                    "main.s",
+                    "%include \"sub/a.inc\"\nmov rax, A + B\nblob: incbin \"blob.bin\", 1\n",
+                ),
+                ("sub/a.inc", "%define A 1\n%include 'b.inc'\n"),
+                ("sub/b.inc", "%define B 2\nnop\n"),
+                ("lib/blob.bin", ""),
+            ],
+        );
+        let mut preprocessor = open(&dir.join("main.s"), &Diagnostics::new());
+        preprocessor.add_include_dir(dir.join("lib"));
+        let lines = preprocessor.collect::<Vec<_>>();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let texts = lines.iter().map(|line| line.text.clone()).collect::<Vec<_>>();
+        assert_eq!(
+            texts,
+            vec![
+                "nop".to_string(),
+                "mov rax, 1 + 2".to_string(),
+                format!("blob: incbin `{}`, 1", dir.join("lib/blob.bin").display()),
+            ]
+        );
+
+        // インクルードされた行はそのファイルの位置を持つ
+        let loc = &lines[0].loc;
+        assert_eq!(loc.position(), format!("{}:2", dir.join("sub/b.inc").display()));
+        let include = loc.included_from.as_ref().unwrap();
+        assert_eq!(include.position(), format!("{}:2", dir.join("sub/a.inc").display()));
+        assert_eq!(include.included_from.as_ref().unwrap().line, 1);
+    }
+
+    #[test]
+    fn recursive_include() {
+        let dir = temp_dir(
+            "recursive",
+            &[("a.inc", "%include \"b.inc\"\n"), ("b.inc", "%include \"a.inc\"\n")],
+        );
+        let diagnostics = Diagnostics::new();
+        open(&dir.join("a.inc"), &diagnostics).for_each(drop);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let diagnostics = diagnostics.take();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].message, "a.inc is included recursively");
+        assert_eq!(diagnostics[0].loc.file.as_ref(), dir.join("b.inc"));
+    }
+
+    #[test]
+    fn location_of_expanded_line() {
+        let src = "\
+%macro exit 0
+    syscall
+%endmacro
+exit
+";
+        let lines = Preprocessor::new(Path::new("test.s"), src.as_bytes(), Diagnostics::new())
+            .collect::<Vec<_>>();
+        let loc = &lines[0].loc;
+        assert_eq!(loc.line, 2);
+        assert_eq!(loc.depth(), 1);
+        let expansion = loc.expansion.as_ref().unwrap();
+        assert_eq!((expansion.call.line, expansion.def.line), (4, 1));
+    }
+
+    #[test]
+    fn wrong_number_of_arguments() {
+        assert_eq!(
+            errors("%macro m 2\n%endmacro\nm 1\n"),
+            vec![(
+                "macro m (defined at test.s:1) takes 2 parameters but 1 were given".to_string(),
+                3
+            )]
+        );
+    }
+}
@@ -179,6 +179,18 @@ pub enum SectionType {
     EightByteLiterals = 0x4,
     LiteralPointers = 0x5,
     Coalesced = 0xB,
+    /// section with only 16 byte literals
+    SixteenByteLiterals = 0xE,
+    /// template of initial values for thread local variables
+    ThreadLocalRegular = 0x11,
+    /// template of initial values for thread local variables that are initialized with zero
+    ThreadLocalZerofill = 0x12,
+    /// TLV descriptors of thread local variables
+    ThreadLocalVariables = 0x13,
+    /// pointers to TLV descriptors
+    ThreadLocalVariablePointers = 0x14,
+    /// functions to call to initialize TLV values
+    ThreadLocalInitFunctionPointers = 0x15,
 }
 
 impl SectionType {
@@ -248,6 +260,10 @@ impl SectionAttrs {
         self.attrs.push(attr);
     }
 
+    pub fn contains(&self, attr: SectionAttr) -> bool {
+        self.attrs.contains(&attr)
+    }
+
     pub fn from_u32(flags: u32) -> Self {
         let mut attrs = SectionAttrs::new();
         for i in 8..=31 {
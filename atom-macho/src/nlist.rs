@@ -1,7 +1,10 @@
 use crate::io::{Endian, ReadExt as _, WriteExt as _};
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
-use std::io::{Read, Write};
+use std::{
+    fmt,
+    io::{Read, Write},
+};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct NList64 {
@@ -136,6 +139,95 @@ impl NType {
     }
 }
 
+/// Flags in the n_desc field of a symbol that is not a debugging entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NDescFlag {
+    /// The symbol must be kept in the output even if it is not referenced (N_DESC_DISCARDED
+    /// shares this bit in linked images). Only valid in relocatable .o files.
+    NoDeadStrip = 0x0020,
+    /// The undefined symbol may be missing at runtime and is then set to 0.
+    WeakRef = 0x0040,
+    /// The symbol is a weak definition. If a non-weak definition of the same name exists, the
+    /// static linker uses it and discards this one.
+    WeakDef = 0x0080,
+    /// The symbol is pinned to the previous content and does not start a new atom when the file
+    /// has MH_SUBSECTIONS_VIA_SYMBOLS.
+    AltEntry = 0x0200,
+}
+
+impl NDescFlag {
+    pub fn from_u16(n: u16) -> Self {
+        match n {
+            0x0020 => NDescFlag::NoDeadStrip,
+            0x0040 => NDescFlag::WeakRef,
+            0x0080 => NDescFlag::WeakDef,
+            0x0200 => NDescFlag::AltEntry,
+            _ => panic!("Unsupported n_desc flag 0x{:X}", n),
+        }
+    }
+
+    pub fn to_u16(self) -> u16 {
+        self as u16
+    }
+}
+
+/// Typed view of the n_desc field.
+/// The reference type in the low bits and the library ordinal in the high byte are not modeled.
+#[derive(Clone, PartialEq, Eq)]
+pub struct NDesc {
+    flags: Vec<NDescFlag>,
+}
+
+impl NDesc {
+    pub const FLAGS_MASK: u16 = 0x02e0;
+
+    pub fn new() -> NDesc {
+        NDesc { flags: Vec::new() }
+    }
+
+    pub fn push(&mut self, flag: NDescFlag) {
+        if !self.contains(flag) {
+            self.flags.push(flag);
+        }
+    }
+
+    pub fn contains(&self, flag: NDescFlag) -> bool {
+        self.flags.contains(&flag)
+    }
+
+    /// Bits outside `FLAGS_MASK` are ignored.
+    pub fn from_u16(n_desc: u16) -> Self {
+        let mut desc = NDesc::new();
+        for i in 0..=15 {
+            let flag_n = n_desc & Self::FLAGS_MASK & (1 << i);
+            if flag_n != 0 {
+                desc.push(NDescFlag::from_u16(flag_n));
+            }
+        }
+        desc
+    }
+
+    pub fn to_u16(&self) -> u16 {
+        let mut n = 0;
+        for flag in self.flags.iter() {
+            n |= flag.to_u16();
+        }
+        n
+    }
+}
+
+impl Default for NDesc {
+    fn default() -> Self {
+        NDesc::new()
+    }
+}
+
+impl fmt::Debug for NDesc {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_set().entries(self.flags.iter()).finish()
+    }
+}
+
 /// TODO : implement all
 #[derive(FromPrimitive, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DebugSymbol {
@@ -181,4 +273,19 @@ mod tests {
 
         assert_eq!(read, nlist);
     }
+
+    #[test]
+    fn n_desc_flags() {
+        let mut desc = NDesc::new();
+        desc.push(NDescFlag::WeakDef);
+        desc.push(NDescFlag::NoDeadStrip);
+        desc.push(NDescFlag::WeakDef);
+        assert_eq!(desc.to_u16(), 0x00a0);
+
+        // the library ordinal in the high byte is not a flag
+        let read = NDesc::from_u16(0x0100 | 0x0200 | 0x0040);
+        assert!(read.contains(NDescFlag::AltEntry));
+        assert!(read.contains(NDescFlag::WeakRef));
+        assert_eq!(read.to_u16(), 0x0240);
+    }
 }
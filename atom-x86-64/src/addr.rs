@@ -0,0 +1,293 @@
+use super::reg::Reg64;
+use std::fmt::{Display, Error as FmtError, Formatter};
+
+/// メモリ上のアドレスを指すオペランド
+pub trait Address: Display {
+    /// `qword` などのサイズ指定
+    fn size(&self) -> Option<Size>;
+
+    fn base(&self) -> Option<Base>;
+
+    fn index(&self) -> Option<(Reg64, Scale)>;
+
+    fn disp(&self) -> i32;
+
+    /// RIP相対アドレスの基準となるシンボル
+    fn symbol(&self) -> Option<&str>;
+}
+
+impl<A: Address> Address for &A {
+    fn size(&self) -> Option<Size> {
+        (*self).size()
+    }
+
+    fn base(&self) -> Option<Base> {
+        (*self).base()
+    }
+
+    fn index(&self) -> Option<(Reg64, Scale)> {
+        (*self).index()
+    }
+
+    fn disp(&self) -> i32 {
+        (*self).disp()
+    }
+
+    fn symbol(&self) -> Option<&str> {
+        (*self).symbol()
+    }
+}
+
+/// `size [base + index * scale + disp]` 形式のメモリオペランド
+///
+/// ```
+/// use atom_x86_64::{addr::{Mem, Scale, Size}, reg::Reg64};
+///
+/// let mem = Mem::base(Reg64::RBP).with_disp(-8).with_size(Size::Qword);
+/// assert_eq!(mem.to_string(), "qword [rbp-8]");
+///
+/// let mem = Mem::base(Reg64::RAX).with_index(Reg64::RCX, Scale::S8).with_disp(16);
+/// assert_eq!(mem.to_string(), "[rax+rcx*8+16]");
+///
+/// let mem = Mem::rip("msg").with_disp(4);
+/// assert_eq!(mem.to_string(), "[rel msg+4]");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mem {
+    pub size: Option<Size>,
+    pub base: Option<Base>,
+    pub index: Option<(Reg64, Scale)>,
+    pub disp: i32,
+    /// `Base::Rip` の時のみ指定できる
+    pub symbol: Option<String>,
+}
+
+impl Mem {
+    /// `[base]`
+    pub fn base(base: Reg64) -> Mem {
+        Mem {
+            size: None,
+            base: Some(Base::Reg(base)),
+            index: None,
+            disp: 0,
+            symbol: None,
+        }
+    }
+
+    /// `[rel symbol]`
+    pub fn rip(symbol: &str) -> Mem {
+        Mem {
+            size: None,
+            base: Some(Base::Rip),
+            index: None,
+            disp: 0,
+            symbol: Some(symbol.to_string()),
+        }
+    }
+
+    /// `[disp]`
+    pub fn abs(disp: i32) -> Mem {
+        Mem {
+            size: None,
+            base: None,
+            index: None,
+            disp,
+            symbol: None,
+        }
+    }
+
+    pub fn with_size(mut self, size: Size) -> Mem {
+        self.size = Some(size);
+        self
+    }
+
+    pub fn with_index(mut self, index: Reg64, scale: Scale) -> Mem {
+        self.index = Some((index, scale));
+        self
+    }
+
+    pub fn with_disp(mut self, disp: i32) -> Mem {
+        self.disp = disp;
+        self
+    }
+}
+
+impl Address for Mem {
+    fn size(&self) -> Option<Size> {
+        self.size
+    }
+
+    fn base(&self) -> Option<Base> {
+        self.base
+    }
+
+    fn index(&self) -> Option<(Reg64, Scale)> {
+        self.index
+    }
+
+    fn disp(&self) -> i32 {
+        self.disp
+    }
+
+    fn symbol(&self) -> Option<&str> {
+        self.symbol.as_deref()
+    }
+}
+
+impl Display for Mem {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
+        if let Some(size) = self.size {
+            write!(f, "{} ", size)?;
+        }
+
+        write!(f, "[")?;
+        let mut is_first = true;
+        match self.base {
+            Some(Base::Reg(reg)) => {
+                write!(f, "{}", reg)?;
+                is_first = false;
+            }
+            Some(Base::Rip) => {
+                write!(f, "rel ")?;
+            }
+            None => {}
+        }
+        if let Some(symbol) = self.symbol.as_ref() {
+            if !is_first {
+                write!(f, "+")?;
+            }
+            write!(f, "{}", symbol)?;
+            is_first = false;
+        }
+        if let Some((index, scale)) = self.index {
+            if !is_first {
+                write!(f, "+")?;
+            }
+            write!(f, "{}*{}", index, scale)?;
+            is_first = false;
+        }
+        if is_first {
+            write!(f, "{}", self.disp)?;
+        } else if self.disp != 0 {
+            write!(f, "{:+}", self.disp)?;
+        }
+        write!(f, "]")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Base {
+    Reg(Reg64),
+    /// 次の命令のアドレスを基準にする
+    Rip,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scale {
+    S1 = 1,
+    S2 = 2,
+    S4 = 4,
+    S8 = 8,
+}
+
+impl Scale {
+    pub fn from_u8(n: u8) -> Option<Scale> {
+        match n {
+            1 => Some(Scale::S1),
+            2 => Some(Scale::S2),
+            4 => Some(Scale::S4),
+            8 => Some(Scale::S8),
+            _ => None,
+        }
+    }
+
+    pub fn to_u8(self) -> u8 {
+        self as u8
+    }
+
+    /// SIB の scale フィールドの値
+    pub fn bits(self) -> u8 {
+        match self {
+            Scale::S1 => 0b00,
+            Scale::S2 => 0b01,
+            Scale::S4 => 0b10,
+            Scale::S8 => 0b11,
+        }
+    }
+}
+
+impl Display for Scale {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
+        write!(f, "{}", self.to_u8())
+    }
+}
+
+/// メモリオペランドのサイズ指定
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Size {
+    Byte,
+    Word,
+    Dword,
+    Qword,
+}
+
+impl Size {
+    /// バイト数
+    pub fn bytes(self) -> u8 {
+        match self {
+            Size::Byte => 1,
+            Size::Word => 2,
+            Size::Dword => 4,
+            Size::Qword => 8,
+        }
+    }
+}
+
+impl Display for Size {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
+        match self {
+            Size::Byte => write!(f, "byte"),
+            Size::Word => write!(f, "word"),
+            Size::Dword => write!(f, "dword"),
+            Size::Qword => write!(f, "qword"),
+        }
+    }
+}
+
+/// 分岐命令の飛び先.
+/// 相対アドレスは次の命令の先頭からのオフセット.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rel {
+    Rel8(i8),
+    Rel32(i32),
+    /// アドレスがまだ決まっていないシンボル. `rel32` 形式になる.
+    Symbol(String),
+}
+
+impl Display for Rel {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
+        match self {
+            Rel::Rel8(n) => write!(f, "short {:+}", n),
+            Rel::Rel32(n) => write!(f, "near {:+}", n),
+            Rel::Symbol(symbol) => write!(f, "{}", symbol),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_mem_with_symbol() {
+        let mut mem = Mem::base(Reg64::RBX);
+        mem.symbol = Some("msg".to_string());
+        assert_eq!(mem.to_string(), "[rbx+msg]");
+
+        let mem = mem.with_index(Reg64::RCX, Scale::S8).with_disp(-4);
+        assert_eq!(mem.to_string(), "[rbx+msg+rcx*8-4]");
+
+        let mem = Mem::rip("msg").with_size(Size::Qword);
+        assert_eq!(mem.to_string(), "qword [rel msg]");
+    }
+}
@@ -0,0 +1,332 @@
+use super::{
+    addr::{Address, Base, Rel, Size},
+    reg::Reg64,
+};
+use std::{
+    convert::TryFrom as _,
+    fmt::{self, Display},
+};
+
+/// 機械語に変換できる命令
+pub trait Encode {
+    /// 命令を機械語に変換し、 `code` の末尾に追加する.
+    /// 変換できないオペランドであればエラーを返し、 `code` には何も追加しない.
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError>;
+
+    fn to_code(&self) -> Result<Code, EncodeError> {
+        let mut code = Code::new();
+        self.encode(&mut code)?;
+        Ok(code)
+    }
+}
+
+/// 機械語に変換できないオペランド.
+/// メモリオペランドはその表記を持つ.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EncodeError {
+    /// `rsp` は index にできない
+    InvalidIndex(Reg64),
+    /// シンボルはRIP相対アドレスでしか使えない
+    SymbolWithoutRip(String),
+    /// RIP相対アドレスは index を持てない
+    RipWithIndex(String),
+    /// メモリオペランドのサイズ指定が命令のオペランドサイズと異なる
+    SizeMismatch { expected: Size, operand: String },
+    /// 即値が32bitに収まらない
+    ImmOutOfRange(i64),
+    /// `rel8` 形式を持たない分岐命令に `rel8` を指定した
+    NoShortForm(Rel),
+}
+
+impl Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EncodeError::InvalidIndex(reg) => {
+                write!(f, "{} can not be used as an index register", reg)
+            }
+            EncodeError::SymbolWithoutRip(mem) => {
+                write!(f, "symbol can only be used with RIP relative addressing: {}", mem)
+            }
+            EncodeError::RipWithIndex(mem) => {
+                write!(f, "RIP relative addressing can not have an index: {}", mem)
+            }
+            EncodeError::SizeMismatch { expected, operand } => {
+                write!(f, "operand size mismatch: expected {} but got {}", expected, operand)
+            }
+            EncodeError::ImmOutOfRange(n) => write!(f, "immediate {} does not fit in 32 bits", n),
+            EncodeError::NoShortForm(rel) => write!(f, "rel8 form is not available: {}", rel),
+        }
+    }
+}
+
+impl std::error::Error for EncodeError {}
+
+/// 機械語のバイト列
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Code {
+    pub bytes: Vec<u8>,
+    /// シンボルのアドレスが確定した後に書き換える必要がある箇所
+    pub fixups: Vec<Fixup>,
+}
+
+impl Code {
+    pub fn new() -> Code {
+        Code {
+            bytes: Vec::new(),
+            fixups: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub fn push(&mut self, byte: u8) {
+        self.bytes.push(byte);
+    }
+
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    pub fn push_imm(&mut self, imm: Imm) {
+        match imm {
+            Imm::I8(n) => self.push(n as u8),
+            Imm::I16(n) => self.extend(&n.to_le_bytes()),
+            Imm::I32(n) => self.extend(&n.to_le_bytes()),
+            Imm::I64(n) => self.extend(&n.to_le_bytes()),
+        }
+    }
+}
+
+/// RIP相対アドレスの4byteのdisplacementのうち、
+/// シンボルのアドレスに依存するもの.
+/// displacementにはシンボルからのオフセットが書き込まれている.
+/// 分岐命令の `rel32` もこれで表す.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fixup {
+    /// `Code` の先頭からのオフセット
+    pub offset: usize,
+    pub symbol: String,
+}
+
+/// 即値
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Imm {
+    I8(i8),
+    I16(i16),
+    I32(i32),
+    I64(i64),
+}
+
+impl Imm {
+    /// `imm8` 形式と `imm32` 形式を持つ命令のために、
+    /// 収まる方の即値を返す
+    pub fn i8_or_i32(n: i64) -> Result<Imm, EncodeError> {
+        match i8::try_from(n) {
+            Ok(n) => Ok(Imm::I8(n)),
+            Err(_) => Imm::i32(n).map(Imm::I32),
+        }
+    }
+
+    /// 符号拡張される `imm32` に収まる即値
+    pub fn i32(n: i64) -> Result<i32, EncodeError> {
+        i32::try_from(n).map_err(|_| EncodeError::ImmOutOfRange(n))
+    }
+}
+
+/// ModRM の r/m フィールドで指定するオペランド
+#[derive(Clone, Copy)]
+pub(crate) enum Rm<'a> {
+    /// レジスタ番号
+    Reg(u8),
+    Mem(&'a dyn Address),
+}
+
+/// REX prefix の W bit, REX prefix を強制するかどうか
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Rex {
+    pub w: bool,
+    pub force: bool,
+}
+
+impl Rex {
+    /// オペランドサイズがデフォルト (32bit) の命令
+    pub const NONE: Rex = Rex {
+        w: false,
+        force: false,
+    };
+
+    /// オペランドサイズが64bitの命令
+    pub const W: Rex = Rex {
+        w: true,
+        force: false,
+    };
+}
+
+/// `[REX] opcode ModRM` 形式の命令を書き込む.
+/// `reg` はレジスタ番号もしくは `/digit` で指定されるopcodeの拡張.
+/// 即値は呼び出し側が後ろに追加する.
+pub(crate) fn encode_rm(
+    code: &mut Code,
+    rex: Rex,
+    opcode: &[u8],
+    reg: u8,
+    rm: Rm,
+) -> Result<(), EncodeError> {
+    match rm {
+        Rm::Reg(rm) => {
+            write_rex(code, rex, reg >= 8, false, rm >= 8);
+            code.extend(opcode);
+            code.push(modrm(0b11, reg, rm));
+            Ok(())
+        }
+        Rm::Mem(mem) => encode_mem(code, rex, opcode, reg, mem),
+    }
+}
+
+fn encode_mem(
+    code: &mut Code,
+    rex: Rex,
+    opcode: &[u8],
+    reg: u8,
+    mem: &dyn Address,
+) -> Result<(), EncodeError> {
+    let index = match mem.index() {
+        Some((index, _)) if index.number() == 4 => return Err(EncodeError::InvalidIndex(index)),
+        Some((index, scale)) => Some((index.number(), scale.bits())),
+        None => None,
+    };
+    if mem.symbol().is_some() && mem.base() != Some(Base::Rip) {
+        return Err(EncodeError::SymbolWithoutRip(mem.to_string()));
+    }
+    if mem.base() == Some(Base::Rip) && index.is_some() {
+        return Err(EncodeError::RipWithIndex(mem.to_string()));
+    }
+
+    match mem.base() {
+        // [rip + disp32]
+        Some(Base::Rip) => {
+            write_rex(code, rex, reg >= 8, false, false);
+            code.extend(opcode);
+            code.push(modrm(0b00, reg, 0b101));
+            if let Some(symbol) = mem.symbol() {
+                code.fixups.push(Fixup {
+                    offset: code.len(),
+                    symbol: symbol.to_string(),
+                });
+            }
+            code.push_imm(Imm::I32(mem.disp()));
+        }
+        // [index * scale + disp32]
+        None => {
+            let (index, scale) = index.unwrap_or((0b100, 0));
+            write_rex(code, rex, reg >= 8, index >= 8, false);
+            code.extend(opcode);
+            code.push(modrm(0b00, reg, 0b100));
+            code.push(sib(scale, index, 0b101));
+            code.push_imm(Imm::I32(mem.disp()));
+        }
+        // [base + index * scale + disp]
+        Some(Base::Reg(base)) => {
+            let base = base.number();
+            let disp = match mem.disp() {
+                // rbp, r13 は mod == 00 だとRIP相対/disp32の意味になる
+                0 if base & 0b111 != 0b101 => None,
+                disp => match i8::try_from(disp) {
+                    Ok(disp) => Some(Imm::I8(disp)),
+                    Err(_) => Some(Imm::I32(disp)),
+                },
+            };
+            let md = match disp {
+                None => 0b00,
+                Some(Imm::I8(_)) => 0b01,
+                Some(_) => 0b10,
+            };
+
+            write_rex(code, rex, reg >= 8, index.is_some_and(|i| i.0 >= 8), base >= 8);
+            code.extend(opcode);
+            // rsp, r12 をbaseにする時もSIBが必要
+            match index {
+                Some((index, scale)) => {
+                    code.push(modrm(md, reg, 0b100));
+                    code.push(sib(scale, index, base));
+                }
+                None if base & 0b111 == 0b100 => {
+                    code.push(modrm(md, reg, 0b100));
+                    code.push(sib(0, 0b100, base));
+                }
+                None => code.push(modrm(md, reg, base)),
+            }
+            if let Some(disp) = disp {
+                code.push_imm(disp);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// メモリオペランドのサイズ指定が命令のオペランドサイズと一致しているか確認する
+pub(crate) fn check_size(mem: &dyn Address, size: Size) -> Result<(), EncodeError> {
+    match mem.size() {
+        Some(s) if s != size => Err(EncodeError::SizeMismatch {
+            expected: size,
+            operand: mem.to_string(),
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// `[REX] opcode+rd` 形式の命令を書き込む
+pub(crate) fn encode_plus_r(code: &mut Code, rex: Rex, opcode: u8, reg: u8) {
+    write_rex(code, rex, false, false, reg >= 8);
+    code.push(opcode + (reg & 0b111));
+}
+
+fn write_rex(code: &mut Code, rex: Rex, r: bool, x: bool, b: bool) {
+    if rex.w || rex.force || r || x || b {
+        code.push(0x40 | ((rex.w as u8) << 3) | ((r as u8) << 2) | ((x as u8) << 1) | (b as u8));
+    }
+}
+
+fn modrm(md: u8, reg: u8, rm: u8) -> u8 {
+    (md << 6) | ((reg & 0b111) << 3) | (rm & 0b111)
+}
+
+fn sib(scale: u8, index: u8, base: u8) -> u8 {
+    (scale << 6) | ((index & 0b111) << 3) | (base & 0b111)
+}
+
+/// 分岐命令を書き込む.
+/// `short` は `rel8` 形式のopcode. `rel8` 形式を持たない命令では `None`.
+pub(crate) fn encode_rel(
+    code: &mut Code,
+    short: Option<&[u8]>,
+    near: &[u8],
+    rel: &Rel,
+) -> Result<(), EncodeError> {
+    match rel {
+        Rel::Rel8(n) => {
+            let short = short.ok_or_else(|| EncodeError::NoShortForm(rel.clone()))?;
+            code.extend(short);
+            code.push_imm(Imm::I8(*n));
+        }
+        Rel::Rel32(n) => {
+            code.extend(near);
+            code.push_imm(Imm::I32(*n));
+        }
+        Rel::Symbol(symbol) => {
+            code.extend(near);
+            code.fixups.push(Fixup {
+                offset: code.len(),
+                symbol: symbol.clone(),
+            });
+            code.push_imm(Imm::I32(0));
+        }
+    }
+    Ok(())
+}
@@ -1,8 +1,16 @@
-use super::{addr::Address, reg::*, Asm};
+use super::{
+    addr::{Address, Rel, Size},
+    encode::{
+        check_size, encode_plus_r, encode_rel, encode_rm, Code, Encode, EncodeError, Imm, Rex, Rm,
+    },
+    reg::*,
+    Asm,
+};
+use std::convert::TryFrom as _;
 
 /// Instructionを表す構造体を定義する
 ///
-/// ```
+/// ```ignore
 /// pub struct Mov<T1, T2>(pub T1, pub T2);
 ///
 /// pub fn mov<T1, T2>(T1: T1, T2, T2) -> Mov<T1, T2> {
@@ -61,7 +69,7 @@ macro_rules! instruction {
 
 /// `Asm` trait を実装する
 ///
-/// ```
+/// ```ignore
 /// impl Asm for Mov<Reg64, Reg64> {
 ///     fn write(&self, w: &mut dyn std::io::Write) -> std::io::Result<()> {
 ///         write!(w, "  {} {}, {}\n", Self::opcode(), self.0, self.1)
@@ -96,6 +104,18 @@ macro_rules! impl_asm {
         }
     };
 
+    // 1 引数のinstruction (where句あり)
+    ($ty:tt<$t1:ty> where A: Address) => {
+        impl<A> Asm for $ty<$t1>
+        where
+            A: Address,
+        {
+            fn write(&self, w: &mut dyn std::io::Write) -> std::io::Result<()> {
+                write!(w, "  {} {}\n", Self::opcode(), self.0)
+            }
+        }
+    };
+
     // 2 引数のinstruction (where句あり)
     ($ty:tt<$t1:ty, $t2:ty> where A: Address) => {
         impl<A> Asm for $ty<$t1, $t2>
@@ -120,6 +140,49 @@ instruction! {cmp =>
 }
 impl_asm!(Cmp<Reg64, i64>);
 impl_asm!(Cmp<Reg64, Reg64>);
+impl_asm!(Cmp<A, i64> where A: Address);
+impl_asm!(Cmp<A, Reg64> where A: Address);
+impl_asm!(Cmp<Reg64, A> where A: Address);
+
+impl Encode for Cmp<Reg64, i64> {
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        // REX.W + 83 /7 ib
+        // REX.W + 81 /7 id
+        encode_imm_group1(code, 7, Rm::Reg(self.0.number()), self.1)
+    }
+}
+
+impl Encode for Cmp<Reg64, Reg64> {
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        // REX.W + 39 /r
+        encode_rm(code, Rex::W, &[0x39], self.1.number(), Rm::Reg(self.0.number()))
+    }
+}
+
+impl<A: Address> Encode for Cmp<A, i64> {
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        // REX.W + 83 /7 ib
+        // REX.W + 81 /7 id
+        check_size(&self.0, Size::Qword)?;
+        encode_imm_group1(code, 7, Rm::Mem(&self.0), self.1)
+    }
+}
+
+impl<A: Address> Encode for Cmp<A, Reg64> {
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        // REX.W + 39 /r
+        check_size(&self.0, Size::Qword)?;
+        encode_rm(code, Rex::W, &[0x39], self.1.number(), Rm::Mem(&self.0))
+    }
+}
+
+impl<A: Address> Encode for Cmp<Reg64, A> {
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        // REX.W + 3B /r
+        check_size(&self.1, Size::Qword)?;
+        encode_rm(code, Rex::W, &[0x3B], self.0.number(), Rm::Mem(&self.1))
+    }
+}
 
 // mov
 instruction! {mov =>
@@ -128,8 +191,66 @@ instruction! {mov =>
     pub struct Mov<T1, T2>
 }
 impl_asm!(Mov<Reg64, Reg64>);
+impl_asm!(Mov<Reg64, i64>);
 impl_asm!(Mov<A, Reg64> where A: Address);
 impl_asm!(Mov<Reg64, A> where A: Address);
+impl_asm!(Mov<A, i64> where A: Address);
+
+impl Encode for Mov<Reg64, Reg64> {
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        // REX.W + 89 /r
+        encode_rm(code, Rex::W, &[0x89], self.1.number(), Rm::Reg(self.0.number()))
+    }
+}
+
+impl Encode for Mov<Reg64, i64> {
+    /// 即値の大きさによって最も短い形式を選ぶ
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        let reg = self.0.number();
+        if let Ok(imm) = u32::try_from(self.1) {
+            // B8 +rd id
+            // 32bitレジスタへのmovは上位32bitをゼロクリアする
+            encode_plus_r(code, Rex::NONE, 0xB8, reg);
+            code.extend(&imm.to_le_bytes());
+        } else if let Ok(imm) = i32::try_from(self.1) {
+            // REX.W + C7 /0 id
+            encode_rm(code, Rex::W, &[0xC7], 0, Rm::Reg(reg))?;
+            code.push_imm(Imm::I32(imm));
+        } else {
+            // REX.W + B8 +rd io
+            encode_plus_r(code, Rex::W, 0xB8, reg);
+            code.push_imm(Imm::I64(self.1));
+        }
+        Ok(())
+    }
+}
+
+impl<A: Address> Encode for Mov<A, Reg64> {
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        // REX.W + 89 /r
+        check_size(&self.0, Size::Qword)?;
+        encode_rm(code, Rex::W, &[0x89], self.1.number(), Rm::Mem(&self.0))
+    }
+}
+
+impl<A: Address> Encode for Mov<Reg64, A> {
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        // REX.W + 8B /r
+        check_size(&self.1, Size::Qword)?;
+        encode_rm(code, Rex::W, &[0x8B], self.0.number(), Rm::Mem(&self.1))
+    }
+}
+
+impl<A: Address> Encode for Mov<A, i64> {
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        // REX.W + C7 /0 id
+        check_size(&self.0, Size::Qword)?;
+        let imm = Imm::i32(self.1)?;
+        encode_rm(code, Rex::W, &[0xC7], 0, Rm::Mem(&self.0))?;
+        code.push_imm(Imm::I32(imm));
+        Ok(())
+    }
+}
 
 // movzx
 instruction! {movzx =>
@@ -137,6 +258,36 @@ instruction! {movzx =>
     pub struct Movzx<T1, T2>
 }
 impl_asm!(Movzx<Reg64, Reg8>);
+impl_asm!(Movzx<Reg64, A> where A: Address);
+
+impl Encode for Movzx<Reg64, Reg8> {
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        // REX.W + 0F B6 /r
+        encode_rm(code, Rex::W, &[0x0F, 0xB6], self.0.number(), Rm::Reg(self.1.number()))
+    }
+}
+
+impl<A: Address> Encode for Movzx<Reg64, A> {
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        // REX.W + 0F B6 /r
+        check_size(&self.1, Size::Byte)?;
+        encode_rm(code, Rex::W, &[0x0F, 0xB6], self.0.number(), Rm::Mem(&self.1))
+    }
+}
+
+// lea
+instruction! {lea =>
+    /// `T2` のアドレスを計算し、その値を `T1` にコピーする
+    pub struct Lea<T1, T2>
+}
+impl_asm!(Lea<Reg64, A> where A: Address);
+
+impl<A: Address> Encode for Lea<Reg64, A> {
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        // REX.W + 8D /r
+        encode_rm(code, Rex::W, &[0x8D], self.0.number(), Rm::Mem(&self.1))
+    }
+}
 
 // pop
 instruction! {pop =>
@@ -144,6 +295,23 @@ instruction! {pop =>
     pub struct Pop<T>
 }
 impl_asm!(Pop<Reg64>);
+impl_asm!(Pop<A> where A: Address);
+
+impl Encode for Pop<Reg64> {
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        // 58 +rd
+        encode_plus_r(code, Rex::NONE, 0x58, self.0.number());
+        Ok(())
+    }
+}
+
+impl<A: Address> Encode for Pop<A> {
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        // 8F /0
+        check_size(&self.0, Size::Qword)?;
+        encode_rm(code, Rex::NONE, &[0x8F], 0, Rm::Mem(&self.0))
+    }
+}
 
 // push
 instruction! {push =>
@@ -152,6 +320,37 @@ instruction! {push =>
 }
 impl_asm!(Push<Reg64>);
 impl_asm!(Push<i64>);
+impl_asm!(Push<A> where A: Address);
+
+impl Encode for Push<Reg64> {
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        // 50 +rd
+        encode_plus_r(code, Rex::NONE, 0x50, self.0.number());
+        Ok(())
+    }
+}
+
+impl<A: Address> Encode for Push<A> {
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        // FF /6
+        check_size(&self.0, Size::Qword)?;
+        encode_rm(code, Rex::NONE, &[0xFF], 6, Rm::Mem(&self.0))
+    }
+}
+
+impl Encode for Push<i64> {
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        // 6A ib
+        // 68 id
+        let imm = Imm::i8_or_i32(self.0)?;
+        match imm {
+            Imm::I8(_) => code.push(0x6A),
+            _ => code.push(0x68),
+        }
+        code.push_imm(imm);
+        Ok(())
+    }
+}
 
 // ret
 instruction! {ret =>
@@ -159,12 +358,64 @@ instruction! {ret =>
 }
 impl_asm!(Ret);
 
+impl Encode for Ret {
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        // C3
+        code.push(0xC3);
+        Ok(())
+    }
+}
+
 // add
 instruction! {add =>
     /// `T1` = `T1` + `T2`
     pub struct Add<T1, T2>
 }
 impl_asm!(Add<Reg64, Reg64>);
+impl_asm!(Add<Reg64, i64>);
+impl_asm!(Add<A, i64> where A: Address);
+impl_asm!(Add<A, Reg64> where A: Address);
+impl_asm!(Add<Reg64, A> where A: Address);
+
+impl Encode for Add<Reg64, Reg64> {
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        // REX.W + 01 /r
+        encode_rm(code, Rex::W, &[0x01], self.1.number(), Rm::Reg(self.0.number()))
+    }
+}
+
+impl Encode for Add<Reg64, i64> {
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        // REX.W + 83 /0 ib
+        // REX.W + 81 /0 id
+        encode_imm_group1(code, 0, Rm::Reg(self.0.number()), self.1)
+    }
+}
+
+impl<A: Address> Encode for Add<A, i64> {
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        // REX.W + 83 /0 ib
+        // REX.W + 81 /0 id
+        check_size(&self.0, Size::Qword)?;
+        encode_imm_group1(code, 0, Rm::Mem(&self.0), self.1)
+    }
+}
+
+impl<A: Address> Encode for Add<A, Reg64> {
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        // REX.W + 01 /r
+        check_size(&self.0, Size::Qword)?;
+        encode_rm(code, Rex::W, &[0x01], self.1.number(), Rm::Mem(&self.0))
+    }
+}
+
+impl<A: Address> Encode for Add<Reg64, A> {
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        // REX.W + 03 /r
+        check_size(&self.1, Size::Qword)?;
+        encode_rm(code, Rex::W, &[0x03], self.0.number(), Rm::Mem(&self.1))
+    }
+}
 
 // sub
 instruction! {sub =>
@@ -173,12 +424,71 @@ instruction! {sub =>
 }
 impl_asm!(Sub<Reg64, i64>);
 impl_asm!(Sub<Reg64, Reg64>);
+impl_asm!(Sub<A, i64> where A: Address);
+impl_asm!(Sub<A, Reg64> where A: Address);
+impl_asm!(Sub<Reg64, A> where A: Address);
+
+impl Encode for Sub<Reg64, i64> {
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        // REX.W + 83 /5 ib
+        // REX.W + 81 /5 id
+        encode_imm_group1(code, 5, Rm::Reg(self.0.number()), self.1)
+    }
+}
+
+impl Encode for Sub<Reg64, Reg64> {
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        // REX.W + 29 /r
+        encode_rm(code, Rex::W, &[0x29], self.1.number(), Rm::Reg(self.0.number()))
+    }
+}
+
+impl<A: Address> Encode for Sub<A, i64> {
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        // REX.W + 83 /5 ib
+        // REX.W + 81 /5 id
+        check_size(&self.0, Size::Qword)?;
+        encode_imm_group1(code, 5, Rm::Mem(&self.0), self.1)
+    }
+}
+
+impl<A: Address> Encode for Sub<A, Reg64> {
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        // REX.W + 29 /r
+        check_size(&self.0, Size::Qword)?;
+        encode_rm(code, Rex::W, &[0x29], self.1.number(), Rm::Mem(&self.0))
+    }
+}
+
+impl<A: Address> Encode for Sub<Reg64, A> {
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        // REX.W + 2B /r
+        check_size(&self.1, Size::Qword)?;
+        encode_rm(code, Rex::W, &[0x2B], self.0.number(), Rm::Mem(&self.1))
+    }
+}
 
 // imul
 instruction! {imul =>
     pub struct Imul<T1, T2>
 }
 impl_asm!(Imul<Reg64, Reg64>);
+impl_asm!(Imul<Reg64, A> where A: Address);
+
+impl Encode for Imul<Reg64, Reg64> {
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        // REX.W + 0F AF /r
+        encode_rm(code, Rex::W, &[0x0F, 0xAF], self.0.number(), Rm::Reg(self.1.number()))
+    }
+}
+
+impl<A: Address> Encode for Imul<Reg64, A> {
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        // REX.W + 0F AF /r
+        check_size(&self.1, Size::Qword)?;
+        encode_rm(code, Rex::W, &[0x0F, 0xAF], self.0.number(), Rm::Mem(&self.1))
+    }
+}
 
 // cqo
 instruction! {cqo =>
@@ -186,11 +496,269 @@ instruction! {cqo =>
 }
 impl_asm!(Cqo);
 
+impl Encode for Cqo {
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        // REX.W + 99
+        code.extend(&[0x48, 0x99]);
+        Ok(())
+    }
+}
+
+// syscall
+instruction! {syscall =>
+    /// `rax` で指定されたシステムコールを呼び出す
+    pub struct Syscall
+}
+impl_asm!(Syscall);
+
+impl Encode for Syscall {
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        // 0F 05
+        code.extend(&[0x0F, 0x05]);
+        Ok(())
+    }
+}
+
+// ud2
+instruction! {ud2 =>
+    /// 未定義命令の例外を起こす
+    pub struct Ud2
+}
+impl_asm!(Ud2);
+
+impl Encode for Ud2 {
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        // 0F 0B
+        code.extend(&[0x0F, 0x0B]);
+        Ok(())
+    }
+}
+
+// int3
+instruction! {int3 =>
+    /// ブレークポイントの例外を起こす
+    pub struct Int3
+}
+impl_asm!(Int3);
+
+impl Encode for Int3 {
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        // CC
+        code.push(0xCC);
+        Ok(())
+    }
+}
+
+// nop
+instruction! {nop =>
+    /// 何もしない
+    pub struct Nop
+}
+impl_asm!(Nop);
+
+impl Encode for Nop {
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        // 90
+        code.push(0x90);
+        Ok(())
+    }
+}
+
+/// Intel SDM で推奨されている1〜9バイトのNOP
+const MULTI_BYTE_NOPS: [&[u8]; 9] = [
+    &[0x90],
+    &[0x66, 0x90],
+    &[0x0F, 0x1F, 0x00],
+    &[0x0F, 0x1F, 0x40, 0x00],
+    &[0x0F, 0x1F, 0x44, 0x00, 0x00],
+    &[0x66, 0x0F, 0x1F, 0x44, 0x00, 0x00],
+    &[0x0F, 0x1F, 0x80, 0x00, 0x00, 0x00, 0x00],
+    &[0x0F, 0x1F, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00],
+    &[0x66, 0x0F, 0x1F, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00],
+];
+
+/// `len` バイトの詰め物をNOPで書き込む.
+/// 命令の数が少なくなるように長いNOPから使う.
+pub fn encode_nops(code: &mut Code, mut len: usize) {
+    while len > 0 {
+        let n = len.min(MULTI_BYTE_NOPS.len());
+        code.extend(MULTI_BYTE_NOPS[n - 1]);
+        len -= n;
+    }
+}
+
+// jmp
+instruction! {jmp =>
+    /// 無条件に `T` へ分岐する
+    pub struct Jmp<T>
+}
+impl_asm!(Jmp<Rel>);
+impl_asm!(Jmp<Reg64>);
+impl_asm!(Jmp<A> where A: Address);
+
+impl Encode for Jmp<Rel> {
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        // EB cb / E9 cd
+        encode_rel(code, Some(&[0xEB]), &[0xE9], &self.0)
+    }
+}
+
+impl Encode for Jmp<Reg64> {
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        // FF /4
+        encode_rm(code, Rex::NONE, &[0xFF], 4, Rm::Reg(self.0.number()))
+    }
+}
+
+impl<A: Address> Encode for Jmp<A> {
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        // FF /4
+        check_size(&self.0, Size::Qword)?;
+        encode_rm(code, Rex::NONE, &[0xFF], 4, Rm::Mem(&self.0))
+    }
+}
+
+/// 条件付き命令の条件
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cond {
+    O = 0x0,
+    No = 0x1,
+    B = 0x2,
+    Ae = 0x3,
+    E = 0x4,
+    Ne = 0x5,
+    Be = 0x6,
+    A = 0x7,
+    S = 0x8,
+    Ns = 0x9,
+    P = 0xA,
+    Np = 0xB,
+    L = 0xC,
+    Ge = 0xD,
+    Le = 0xE,
+    G = 0xF,
+}
+
+impl Cond {
+    /// `e`, `nz`, `nae` などの条件名から変換する.
+    /// 別名も受け付ける.
+    pub fn from_name(name: &str) -> Option<Cond> {
+        let cond = match name {
+            "o" => Cond::O,
+            "no" => Cond::No,
+            "b" | "c" | "nae" => Cond::B,
+            "ae" | "nc" | "nb" => Cond::Ae,
+            "e" | "z" => Cond::E,
+            "ne" | "nz" => Cond::Ne,
+            "be" | "na" => Cond::Be,
+            "a" | "nbe" => Cond::A,
+            "s" => Cond::S,
+            "ns" => Cond::Ns,
+            "p" | "pe" => Cond::P,
+            "np" | "po" => Cond::Np,
+            "l" | "nge" => Cond::L,
+            "ge" | "nl" => Cond::Ge,
+            "le" | "ng" => Cond::Le,
+            "g" | "nle" => Cond::G,
+            _ => return None,
+        };
+        Some(cond)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Cond::O => "o",
+            Cond::No => "no",
+            Cond::B => "b",
+            Cond::Ae => "ae",
+            Cond::E => "e",
+            Cond::Ne => "ne",
+            Cond::Be => "be",
+            Cond::A => "a",
+            Cond::S => "s",
+            Cond::Ns => "ns",
+            Cond::P => "p",
+            Cond::Np => "np",
+            Cond::L => "l",
+            Cond::Ge => "ge",
+            Cond::Le => "le",
+            Cond::G => "g",
+        }
+    }
+}
+
+// jcc
+instruction! {jcc =>
+    /// EFLAGSレジスタが条件 `T1` を満たしていれば `T2` へ分岐する
+    pub struct Jcc<T1, T2>
+}
+
+impl Asm for Jcc<Cond, Rel> {
+    fn write(&self, w: &mut dyn std::io::Write) -> std::io::Result<()> {
+        writeln!(w, "  j{} {}", self.0.name(), self.1)
+    }
+}
+
+impl Encode for Jcc<Cond, Rel> {
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        // 70+cc cb / 0F 80+cc cd
+        let cc = self.0 as u8;
+        encode_rel(code, Some(&[0x70 + cc]), &[0x0F, 0x80 + cc], &self.1)
+    }
+}
+
+// call
+instruction! {call =>
+    /// 次の命令のアドレスをスタックに積み、 `T` へ分岐する
+    pub struct Call<T>
+}
+impl_asm!(Call<Rel>);
+impl_asm!(Call<Reg64>);
+impl_asm!(Call<A> where A: Address);
+
+impl Encode for Call<Rel> {
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        // E8 cd
+        encode_rel(code, None, &[0xE8], &self.0)
+    }
+}
+
+impl Encode for Call<Reg64> {
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        // FF /2
+        encode_rm(code, Rex::NONE, &[0xFF], 2, Rm::Reg(self.0.number()))
+    }
+}
+
+impl<A: Address> Encode for Call<A> {
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        // FF /2
+        check_size(&self.0, Size::Qword)?;
+        encode_rm(code, Rex::NONE, &[0xFF], 2, Rm::Mem(&self.0))
+    }
+}
+
 // idiv
 instruction! {idiv =>
     pub struct Idiv<T>
 }
 impl_asm!(Idiv<Reg64>);
+impl_asm!(Idiv<A> where A: Address);
+
+impl Encode for Idiv<Reg64> {
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        // REX.W + F7 /7
+        encode_rm(code, Rex::W, &[0xF7], 7, Rm::Reg(self.0.number()))
+    }
+}
+
+impl<A: Address> Encode for Idiv<A> {
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        // REX.W + F7 /7
+        check_size(&self.0, Size::Qword)?;
+        encode_rm(code, Rex::W, &[0xF7], 7, Rm::Mem(&self.0))
+    }
+}
 
 // sete
 instruction! {sete =>
@@ -200,6 +768,22 @@ instruction! {sete =>
     pub struct Sete<T>
 }
 impl_asm!(Sete<Reg8>);
+impl_asm!(Sete<A> where A: Address);
+
+impl Encode for Sete<Reg8> {
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        // 0F 94 /0
+        encode_setcc(code, 0x94, Rm::Reg(self.0.number()), self.0.requires_rex())
+    }
+}
+
+impl<A: Address> Encode for Sete<A> {
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        // 0F 94 /0
+        check_size(&self.0, Size::Byte)?;
+        encode_setcc(code, 0x94, Rm::Mem(&self.0), false)
+    }
+}
 
 // setne
 instruction! {setne =>
@@ -209,6 +793,22 @@ instruction! {setne =>
     pub struct Setne<T>
 }
 impl_asm!(Setne<Reg8>);
+impl_asm!(Setne<A> where A: Address);
+
+impl Encode for Setne<Reg8> {
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        // 0F 95 /0
+        encode_setcc(code, 0x95, Rm::Reg(self.0.number()), self.0.requires_rex())
+    }
+}
+
+impl<A: Address> Encode for Setne<A> {
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        // 0F 95 /0
+        check_size(&self.0, Size::Byte)?;
+        encode_setcc(code, 0x95, Rm::Mem(&self.0), false)
+    }
+}
 
 // setl
 instruction! {setl =>
@@ -218,6 +818,22 @@ instruction! {setl =>
     pub struct Setl<T>
 }
 impl_asm!(Setl<Reg8>);
+impl_asm!(Setl<A> where A: Address);
+
+impl Encode for Setl<Reg8> {
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        // 0F 9C /0
+        encode_setcc(code, 0x9C, Rm::Reg(self.0.number()), self.0.requires_rex())
+    }
+}
+
+impl<A: Address> Encode for Setl<A> {
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        // 0F 9C /0
+        check_size(&self.0, Size::Byte)?;
+        encode_setcc(code, 0x9C, Rm::Mem(&self.0), false)
+    }
+}
 
 // setle
 instruction! {setle =>
@@ -227,3 +843,249 @@ instruction! {setle =>
     pub struct Setle<T>
 }
 impl_asm!(Setle<Reg8>);
+impl_asm!(Setle<A> where A: Address);
+
+impl Encode for Setle<Reg8> {
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        // 0F 9E /0
+        encode_setcc(code, 0x9E, Rm::Reg(self.0.number()), self.0.requires_rex())
+    }
+}
+
+impl<A: Address> Encode for Setle<A> {
+    fn encode(&self, code: &mut Code) -> Result<(), EncodeError> {
+        // 0F 9E /0
+        check_size(&self.0, Size::Byte)?;
+        encode_setcc(code, 0x9E, Rm::Mem(&self.0), false)
+    }
+}
+
+/// `add`, `sub`, `cmp` などの `r/m64, imm` 形式の命令を書き込む.
+/// `digit` はModRMのregフィールドで指定するopcodeの拡張.
+fn encode_imm_group1(code: &mut Code, digit: u8, rm: Rm, imm: i64) -> Result<(), EncodeError> {
+    let imm = Imm::i8_or_i32(imm)?;
+    let opcode = match imm {
+        Imm::I8(_) => 0x83,
+        _ => 0x81,
+    };
+    encode_rm(code, Rex::W, &[opcode], digit, rm)?;
+    code.push_imm(imm);
+    Ok(())
+}
+
+fn encode_setcc(code: &mut Code, opcode: u8, rm: Rm, force_rex: bool) -> Result<(), EncodeError> {
+    let rex = Rex {
+        w: false,
+        force: force_rex,
+    };
+    encode_rm(code, rex, &[0x0F, opcode], 0, rm)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        addr::{Mem, Scale},
+        encode::Fixup,
+    };
+
+    fn bytes<T: Encode>(inst: T) -> Vec<u8> {
+        inst.to_code().unwrap().bytes
+    }
+
+    #[test]
+    fn encode_reg_reg() {
+        assert_eq!(bytes(mov(Reg64::RAX, Reg64::RBX)), vec![0x48, 0x89, 0xD8]);
+        assert_eq!(bytes(mov(Reg64::R8, Reg64::RAX)), vec![0x49, 0x89, 0xC0]);
+        assert_eq!(bytes(add(Reg64::RAX, Reg64::RDI)), vec![0x48, 0x01, 0xF8]);
+        assert_eq!(bytes(sub(Reg64::RAX, Reg64::R15)), vec![0x4C, 0x29, 0xF8]);
+        assert_eq!(bytes(cmp(Reg64::RDI, Reg64::RSI)), vec![0x48, 0x39, 0xF7]);
+        assert_eq!(bytes(imul(Reg64::RAX, Reg64::RDI)), vec![0x48, 0x0F, 0xAF, 0xC7]);
+        assert_eq!(bytes(movzx(Reg64::RAX, Reg8::SIL)), vec![0x48, 0x0F, 0xB6, 0xC6]);
+    }
+
+    #[test]
+    fn encode_reg_imm() {
+        assert_eq!(bytes(cmp(Reg64::RAX, 1)), vec![0x48, 0x83, 0xF8, 0x01]);
+        assert_eq!(
+            bytes(cmp(Reg64::RDI, 1000)),
+            vec![0x48, 0x81, 0xFF, 0xE8, 0x03, 0x00, 0x00]
+        );
+        assert_eq!(bytes(sub(Reg64::RSP, 16)), vec![0x48, 0x83, 0xEC, 0x10]);
+        assert_eq!(bytes(push(1)), vec![0x6A, 0x01]);
+        assert_eq!(bytes(push(-1000)), vec![0x68, 0x18, 0xFC, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn encode_mov_imm() {
+        assert_eq!(bytes(mov(Reg64::RAX, 0x2000004)), vec![0xB8, 0x04, 0x00, 0x00, 0x02]);
+        assert_eq!(bytes(mov(Reg64::R8, 1)), vec![0x41, 0xB8, 0x01, 0x00, 0x00, 0x00]);
+        assert_eq!(
+            bytes(mov(Reg64::RAX, -1)),
+            vec![0x48, 0xC7, 0xC0, 0xFF, 0xFF, 0xFF, 0xFF]
+        );
+        assert_eq!(
+            bytes(mov(Reg64::RCX, 0x1_0000_0000)),
+            vec![0x48, 0xB9, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn encode_memory_operand() {
+        let rbp = Mem::base(Reg64::RBP);
+        assert_eq!(
+            bytes(mov(rbp.clone().with_disp(-8), Reg64::RAX)),
+            vec![0x48, 0x89, 0x45, 0xF8]
+        );
+        assert_eq!(bytes(mov(Reg64::RAX, rbp.clone())), vec![0x48, 0x8B, 0x45, 0x00]);
+        assert_eq!(
+            bytes(mov(Reg64::RAX, Mem::base(Reg64::RSP))),
+            vec![0x48, 0x8B, 0x04, 0x24]
+        );
+        assert_eq!(
+            bytes(mov(Reg64::R9, Mem::base(Reg64::R12).with_disp(0x100))),
+            vec![0x4D, 0x8B, 0x8C, 0x24, 0x00, 0x01, 0x00, 0x00]
+        );
+        assert_eq!(
+            bytes(lea(
+                Reg64::RAX,
+                Mem::base(Reg64::RBX).with_index(Reg64::R10, Scale::S8).with_disp(16)
+            )),
+            vec![0x4A, 0x8D, 0x44, 0xD3, 0x10]
+        );
+        assert_eq!(
+            bytes(mov(Reg64::RAX, Mem::abs(0).with_index(Reg64::RCX, Scale::S4))),
+            vec![0x48, 0x8B, 0x04, 0x8D, 0x00, 0x00, 0x00, 0x00]
+        );
+        assert_eq!(
+            bytes(cmp(rbp.clone().with_disp(-16).with_size(Size::Qword), 1)),
+            vec![0x48, 0x83, 0x7D, 0xF0, 0x01]
+        );
+        assert_eq!(bytes(push(rbp.clone())), vec![0xFF, 0x75, 0x00]);
+        assert_eq!(
+            bytes(sete(Mem::base(Reg64::RAX).with_size(Size::Byte))),
+            vec![0x0F, 0x94, 0x00]
+        );
+    }
+
+    #[test]
+    fn encode_rip_relative() {
+        let code = lea(Reg64::RSI, Mem::rip("msg")).to_code().unwrap();
+        assert_eq!(code.bytes, vec![0x48, 0x8D, 0x35, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(
+            code.fixups,
+            vec![Fixup {
+                offset: 3,
+                symbol: "msg".to_string()
+            }]
+        );
+
+        let code = mov(Mem::rip("var").with_disp(8), 42).to_code().unwrap();
+        assert_eq!(
+            code.bytes,
+            vec![0x48, 0xC7, 0x05, 0x08, 0x00, 0x00, 0x00, 0x2A, 0x00, 0x00, 0x00]
+        );
+        assert_eq!(code.fixups[0].offset, 3);
+    }
+
+    #[test]
+    fn rsp_can_not_be_index() {
+        assert_eq!(
+            lea(Reg64::RAX, Mem::base(Reg64::RAX).with_index(Reg64::RSP, Scale::S1)).to_code(),
+            Err(EncodeError::InvalidIndex(Reg64::RSP))
+        );
+    }
+
+    #[test]
+    fn memory_operand_size_mismatch() {
+        let err = mov(Reg64::RAX, Mem::base(Reg64::RAX).with_size(Size::Dword))
+            .to_code()
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "operand size mismatch: expected qword but got dword [rax]"
+        );
+    }
+
+    #[test]
+    fn invalid_operands_are_errors() {
+        let mut code = Code::new();
+        assert_eq!(
+            add(Reg64::RAX, 0x1_0000_0000).encode(&mut code),
+            Err(EncodeError::ImmOutOfRange(0x1_0000_0000))
+        );
+        assert_eq!(
+            mov(Mem::base(Reg64::RBX), 0x1_0000_0000).encode(&mut code),
+            Err(EncodeError::ImmOutOfRange(0x1_0000_0000))
+        );
+        assert_eq!(
+            push(-0x8000_0001).encode(&mut code),
+            Err(EncodeError::ImmOutOfRange(-0x8000_0001))
+        );
+        // エラーになった命令は何も書き込まない
+        assert!(code.is_empty());
+
+        let mut mem = Mem::base(Reg64::RBX);
+        mem.symbol = Some("msg".to_string());
+        assert!(matches!(
+            mov(Reg64::RAX, mem).to_code(),
+            Err(EncodeError::SymbolWithoutRip(_))
+        ));
+        assert_eq!(
+            mov(Reg64::RAX, Mem::rip("msg").with_index(Reg64::RCX, Scale::S8)).to_code(),
+            Err(EncodeError::RipWithIndex("[rel msg+rcx*8]".to_string()))
+        );
+    }
+
+    #[test]
+    fn encode_single_operand() {
+        assert_eq!(bytes(push(Reg64::RBP)), vec![0x55]);
+        assert_eq!(bytes(push(Reg64::R12)), vec![0x41, 0x54]);
+        assert_eq!(bytes(pop(Reg64::RBP)), vec![0x5D]);
+        assert_eq!(bytes(idiv(Reg64::RDI)), vec![0x48, 0xF7, 0xFF]);
+        assert_eq!(bytes(sete(Reg8::AL)), vec![0x0F, 0x94, 0xC0]);
+        assert_eq!(bytes(setne(Reg8::SIL)), vec![0x40, 0x0F, 0x95, 0xC6]);
+        assert_eq!(bytes(setl(Reg8::R8B)), vec![0x41, 0x0F, 0x9C, 0xC0]);
+        assert_eq!(bytes(ret()), vec![0xC3]);
+        assert_eq!(bytes(cqo()), vec![0x48, 0x99]);
+        assert_eq!(bytes(ud2()), vec![0x0F, 0x0B]);
+        assert_eq!(bytes(int3()), vec![0xCC]);
+    }
+
+    #[test]
+    fn encode_nop_padding() {
+        assert_eq!(bytes(nop()), vec![0x90]);
+
+        let mut code = Code::new();
+        encode_nops(&mut code, 11);
+        assert_eq!(
+            code.bytes,
+            vec![0x66, 0x0F, 0x1F, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00, 0x66, 0x90]
+        );
+    }
+
+    #[test]
+    fn encode_branch() {
+        assert_eq!(bytes(jmp(Rel::Rel8(-2))), vec![0xEB, 0xFE]);
+        assert_eq!(bytes(jmp(Rel::Rel32(0x100))), vec![0xE9, 0x00, 0x01, 0x00, 0x00]);
+        assert_eq!(bytes(jcc(Cond::Ne, Rel::Rel8(4))), vec![0x75, 0x04]);
+        assert_eq!(
+            bytes(jcc(Cond::Le, Rel::Rel32(-6))),
+            vec![0x0F, 0x8E, 0xFA, 0xFF, 0xFF, 0xFF]
+        );
+        assert_eq!(bytes(call(Reg64::RAX)), vec![0xFF, 0xD0]);
+        assert_eq!(bytes(jmp(Mem::base(Reg64::R11))), vec![0x41, 0xFF, 0x23]);
+
+        let code = call(Rel::Symbol("_printf".to_string())).to_code().unwrap();
+        assert_eq!(code.bytes, vec![0xE8, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(code.fixups[0].offset, 1);
+    }
+
+    #[test]
+    fn call_has_no_rel8_form() {
+        assert_eq!(
+            call(Rel::Rel8(0)).to_code(),
+            Err(EncodeError::NoShortForm(Rel::Rel8(0)))
+        );
+    }
+}
@@ -1,3 +1,15 @@
+pub mod addr;
+pub mod encode;
+pub mod instructions;
+pub mod reg;
+
+pub use self::encode::{Code, Encode};
+
+/// アセンブリのテキストとして書き出せる命令
+pub trait Asm {
+    fn write(&self, w: &mut dyn std::io::Write) -> std::io::Result<()>;
+}
+
 #[cfg(test)]
 mod tests {
     #[test]
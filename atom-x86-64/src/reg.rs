@@ -1,4 +1,7 @@
-use std::fmt::{Display, Error as FmtError, Formatter};
+use std::{
+    fmt::{Display, Error as FmtError, Formatter},
+    str::FromStr,
+};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Reg {
@@ -19,6 +22,28 @@ impl Display for Reg {
     }
 }
 
+impl FromStr for Reg {
+    type Err = ParseRegError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse()
+            .map(Reg::Reg64)
+            .or_else(|_| s.parse().map(Reg::Reg32))
+            .or_else(|_| s.parse().map(Reg::Reg16))
+            .or_else(|_| s.parse().map(Reg::Reg8))
+    }
+}
+
+/// レジスタ名として解釈できない文字列
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseRegError(pub String);
+
+impl Display for ParseRegError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
+        write!(f, "{} is not a register", self.0)
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Reg64 {
     /// Accumulator Register
@@ -55,6 +80,30 @@ pub enum Reg64 {
     R15,
 }
 
+impl Reg64 {
+    /// ModRM や REX prefix で使うレジスタ番号 (0 ~ 15)
+    pub fn number(self) -> u8 {
+        match self {
+            Reg64::RAX => 0,
+            Reg64::RCX => 1,
+            Reg64::RDX => 2,
+            Reg64::RBX => 3,
+            Reg64::RSP => 4,
+            Reg64::RBP => 5,
+            Reg64::RSI => 6,
+            Reg64::RDI => 7,
+            Reg64::R8 => 8,
+            Reg64::R9 => 9,
+            Reg64::R10 => 10,
+            Reg64::R11 => 11,
+            Reg64::R12 => 12,
+            Reg64::R13 => 13,
+            Reg64::R14 => 14,
+            Reg64::R15 => 15,
+        }
+    }
+}
+
 impl Display for Reg64 {
     fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
         match self {
@@ -78,6 +127,33 @@ impl Display for Reg64 {
     }
 }
 
+impl FromStr for Reg64 {
+    type Err = ParseRegError;
+
+    /// 大文字・小文字は区別しない
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "rax" => Ok(Reg64::RAX),
+            "rdi" => Ok(Reg64::RDI),
+            "rsi" => Ok(Reg64::RSI),
+            "rdx" => Ok(Reg64::RDX),
+            "rcx" => Ok(Reg64::RCX),
+            "rbp" => Ok(Reg64::RBP),
+            "rsp" => Ok(Reg64::RSP),
+            "rbx" => Ok(Reg64::RBX),
+            "r8" => Ok(Reg64::R8),
+            "r9" => Ok(Reg64::R9),
+            "r10" => Ok(Reg64::R10),
+            "r11" => Ok(Reg64::R11),
+            "r12" => Ok(Reg64::R12),
+            "r13" => Ok(Reg64::R13),
+            "r14" => Ok(Reg64::R14),
+            "r15" => Ok(Reg64::R15),
+            _ => Err(ParseRegError(s.to_string())),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Reg32 {
     /// Lower 32-bits of RAX Register
@@ -114,6 +190,30 @@ pub enum Reg32 {
     R15D,
 }
 
+impl Reg32 {
+    /// ModRM や REX prefix で使うレジスタ番号 (0 ~ 15)
+    pub fn number(self) -> u8 {
+        match self {
+            Reg32::EAX => 0,
+            Reg32::ECX => 1,
+            Reg32::EDX => 2,
+            Reg32::EBX => 3,
+            Reg32::ESP => 4,
+            Reg32::EBP => 5,
+            Reg32::ESI => 6,
+            Reg32::EDI => 7,
+            Reg32::R8D => 8,
+            Reg32::R9D => 9,
+            Reg32::R10D => 10,
+            Reg32::R11D => 11,
+            Reg32::R12D => 12,
+            Reg32::R13D => 13,
+            Reg32::R14D => 14,
+            Reg32::R15D => 15,
+        }
+    }
+}
+
 impl Display for Reg32 {
     fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
         match self {
@@ -137,6 +237,33 @@ impl Display for Reg32 {
     }
 }
 
+impl FromStr for Reg32 {
+    type Err = ParseRegError;
+
+    /// 大文字・小文字は区別しない
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "eax" => Ok(Reg32::EAX),
+            "edi" => Ok(Reg32::EDI),
+            "esi" => Ok(Reg32::ESI),
+            "edx" => Ok(Reg32::EDX),
+            "ecx" => Ok(Reg32::ECX),
+            "ebp" => Ok(Reg32::EBP),
+            "esp" => Ok(Reg32::ESP),
+            "ebx" => Ok(Reg32::EBX),
+            "r8d" => Ok(Reg32::R8D),
+            "r9d" => Ok(Reg32::R9D),
+            "r10d" => Ok(Reg32::R10D),
+            "r11d" => Ok(Reg32::R11D),
+            "r12d" => Ok(Reg32::R12D),
+            "r13d" => Ok(Reg32::R13D),
+            "r14d" => Ok(Reg32::R14D),
+            "r15d" => Ok(Reg32::R15D),
+            _ => Err(ParseRegError(s.to_string())),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Reg16 {
     /// Lower 16-bits of RAX Register
@@ -173,6 +300,30 @@ pub enum Reg16 {
     R15W,
 }
 
+impl Reg16 {
+    /// ModRM や REX prefix で使うレジスタ番号 (0 ~ 15)
+    pub fn number(self) -> u8 {
+        match self {
+            Reg16::AX => 0,
+            Reg16::CX => 1,
+            Reg16::DX => 2,
+            Reg16::BX => 3,
+            Reg16::SP => 4,
+            Reg16::BP => 5,
+            Reg16::SI => 6,
+            Reg16::DI => 7,
+            Reg16::R8W => 8,
+            Reg16::R9W => 9,
+            Reg16::R10W => 10,
+            Reg16::R11W => 11,
+            Reg16::R12W => 12,
+            Reg16::R13W => 13,
+            Reg16::R14W => 14,
+            Reg16::R15W => 15,
+        }
+    }
+}
+
 impl Display for Reg16 {
     fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
         match self {
@@ -196,6 +347,33 @@ impl Display for Reg16 {
     }
 }
 
+impl FromStr for Reg16 {
+    type Err = ParseRegError;
+
+    /// 大文字・小文字は区別しない
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "ax" => Ok(Reg16::AX),
+            "di" => Ok(Reg16::DI),
+            "si" => Ok(Reg16::SI),
+            "dx" => Ok(Reg16::DX),
+            "cx" => Ok(Reg16::CX),
+            "bp" => Ok(Reg16::BP),
+            "sp" => Ok(Reg16::SP),
+            "bx" => Ok(Reg16::BX),
+            "r8w" => Ok(Reg16::R8W),
+            "r9w" => Ok(Reg16::R9W),
+            "r10w" => Ok(Reg16::R10W),
+            "r11w" => Ok(Reg16::R11W),
+            "r12w" => Ok(Reg16::R12W),
+            "r13w" => Ok(Reg16::R13W),
+            "r14w" => Ok(Reg16::R14W),
+            "r15w" => Ok(Reg16::R15W),
+            _ => Err(ParseRegError(s.to_string())),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Reg8 {
     /// Lower 8-bits of RAX Register
@@ -232,6 +410,35 @@ pub enum Reg8 {
     R15B,
 }
 
+impl Reg8 {
+    /// ModRM や REX prefix で使うレジスタ番号 (0 ~ 15)
+    pub fn number(self) -> u8 {
+        match self {
+            Reg8::AL => 0,
+            Reg8::CL => 1,
+            Reg8::DL => 2,
+            Reg8::BL => 3,
+            Reg8::SPL => 4,
+            Reg8::BPL => 5,
+            Reg8::SIL => 6,
+            Reg8::DIL => 7,
+            Reg8::R8B => 8,
+            Reg8::R9B => 9,
+            Reg8::R10B => 10,
+            Reg8::R11B => 11,
+            Reg8::R12B => 12,
+            Reg8::R13B => 13,
+            Reg8::R14B => 14,
+            Reg8::R15B => 15,
+        }
+    }
+
+    /// REX prefix がないと AH, CH, DH, BH と解釈されてしまうレジスタかどうか
+    pub fn requires_rex(self) -> bool {
+        matches!(self, Reg8::SPL | Reg8::BPL | Reg8::SIL | Reg8::DIL)
+    }
+}
+
 impl Display for Reg8 {
     fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
         match self {
@@ -254,3 +461,30 @@ impl Display for Reg8 {
         }
     }
 }
+
+impl FromStr for Reg8 {
+    type Err = ParseRegError;
+
+    /// 大文字・小文字は区別しない
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "al" => Ok(Reg8::AL),
+            "dil" => Ok(Reg8::DIL),
+            "sil" => Ok(Reg8::SIL),
+            "dl" => Ok(Reg8::DL),
+            "cl" => Ok(Reg8::CL),
+            "bpl" => Ok(Reg8::BPL),
+            "spl" => Ok(Reg8::SPL),
+            "bl" => Ok(Reg8::BL),
+            "r8b" => Ok(Reg8::R8B),
+            "r9b" => Ok(Reg8::R9B),
+            "r10b" => Ok(Reg8::R10B),
+            "r11b" => Ok(Reg8::R11B),
+            "r12b" => Ok(Reg8::R12B),
+            "r13b" => Ok(Reg8::R13B),
+            "r14b" => Ok(Reg8::R14B),
+            "r15b" => Ok(Reg8::R15B),
+            _ => Err(ParseRegError(s.to_string())),
+        }
+    }
+}